@@ -1,6 +1,6 @@
-use crate::proto;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
+use tsdb2::proto;
 
 #[derive(Debug, Default)]
 pub struct ConfigServiceImpl {
@@ -1,18 +1,15 @@
-use crate::proto::tsdb2::{
-    config_service_server::ConfigServiceServer, tsz_collection_server::TszCollectionServer,
-};
 use anyhow::Result;
 use clap::Parser;
 use std::sync::Arc;
 use tonic::transport::Server;
+use tsdb2::proto::tsdb2::{
+    config_service_server::ConfigServiceServer, tsz_collection_server::TszCollectionServer,
+};
+use tsdb2::tsz;
 
 mod config;
 mod server;
 
-pub mod proto;
-pub mod tsz;
-pub mod utils;
-
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -36,7 +33,14 @@ async fn main() -> Result<()> {
         .add_service(TszCollectionServer::new(time_series_service));
 
     println!("listening on {}", args.local_address);
-    builder.serve(args.local_address.parse()?).await?;
+    let result = builder
+        .serve_with_shutdown(args.local_address.parse()?, async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await;
+
+    tsz::shutdown().await;
 
+    result?;
     Ok(())
 }
@@ -1,7 +1,7 @@
 use crate::config::ConfigServiceImpl;
-use crate::proto;
 use std::sync::Arc;
 use tonic::{Request, Response, Status};
+use tsdb2::proto;
 
 #[derive(Debug)]
 pub struct TimeSeriesService {
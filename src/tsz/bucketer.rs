@@ -5,7 +5,7 @@ use std::borrow::Borrow;
 use std::collections::BTreeSet;
 use std::ops::Deref;
 use std::pin::Pin;
-use std::sync::{LazyLock, Mutex};
+use std::sync::{LazyLock, Mutex, atomic::AtomicUsize, atomic::Ordering};
 
 /// Determines the number and boundaries of the buckets of a `Distribution`.
 ///
@@ -19,50 +19,141 @@ use std::sync::{LazyLock, Mutex};
 ///
 /// for any `growth_factor` != 0. If `growth_factor` is zero the upper bound is just
 /// `width * (i + 1)`.
+///
+/// Bucketers are canonical: all constructors return a `&'static Bucketer` from a global cache, so
+/// two bucketers with the same parameters are always the same object. `==` compares parameters,
+/// while `same_as` (and `BucketerRef`'s `==`) compares identity and is cheaper. The two are
+/// equivalent for bucketers obtained from the constructors, but only identity comparison is
+/// meaningful for copies that don't come from the cache.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Bucketer {
     params: (F64, F64, F64, usize),
 }
 
+/// The parameters of a `Bucketer` as a named struct, see `Bucketer::params`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BucketerParams {
+    pub width: f64,
+    pub growth_factor: f64,
+    pub scale_factor: f64,
+    pub num_finite_buckets: usize,
+}
+
 impl Bucketer {
     pub const MAX_NUM_FINITE_BUCKETS: usize = 5000;
 
+    /// See `set_cache_warning_threshold`.
+    pub const DEFAULT_CACHE_WARNING_THRESHOLD: usize = 10_000;
+
+    /// Number of significant digits the floating point parameters are rounded to before looking up
+    /// the cache, see `canonicalize`. 0 disables rounding.
+    pub const PARAM_SIGNIFICANT_DIGITS: i32 = 12;
+
+    /// Rounds a parameter to `PARAM_SIGNIFICANT_DIGITS` significant digits, so that parameters
+    /// that differ only by floating point noise (e.g. `0.1 + 0.2` and `0.3`) map to the same
+    /// bucketer rather than fragmenting the cache. Twelve digits are well beyond the precision
+    /// anyone configures a bucketer with, and well above the noise of a few arithmetic operations.
+    fn canonicalize(value: f64) -> f64 {
+        if Self::PARAM_SIGNIFICANT_DIGITS <= 0 || value == 0.0 || !value.is_finite() {
+            return value;
+        }
+        let magnitude = value.abs().log10().floor() as i32;
+        let scale = 10f64.powi(Self::PARAM_SIGNIFICANT_DIGITS - 1 - magnitude);
+        let result = (value * scale).round() / scale;
+        if result.is_finite() { result } else { value }
+    }
+
+    /// Validates bucketer parameters.
+    ///
+    /// Besides limiting the number of buckets, this rejects a zero `width` combined with a zero
+    /// `growth_factor` when there are finite buckets: all boundaries would be 0, so the buckets
+    /// would be empty and `get_bucket_for` would misroute samples. The `none` bucketer is fine
+    /// because it has no finite buckets.
+    fn check_params(width: f64, growth_factor: f64, num_finite_buckets: usize) -> Result<()> {
+        if num_finite_buckets > Self::MAX_NUM_FINITE_BUCKETS {
+            return Err(anyhow!(
+                "too many finite buckets: {} (the maximum is {})",
+                num_finite_buckets,
+                Self::MAX_NUM_FINITE_BUCKETS
+            ));
+        }
+        if width == 0.0 && growth_factor == 0.0 && num_finite_buckets > 0 {
+            return Err(anyhow!(
+                "a bucketer with finite buckets can't have both zero width and zero growth factor"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Panics if the parameters are invalid, see `check_params`.
     fn get(
         width: f64,
         growth_factor: f64,
         scale_factor: f64,
         num_finite_buckets: usize,
     ) -> &'static Self {
-        assert!(num_finite_buckets <= Self::MAX_NUM_FINITE_BUCKETS);
-        static BUCKETERS: LazyLock<Mutex<BTreeSet<Pin<Box<Bucketer>>>>> =
-            LazyLock::new(|| Mutex::default());
-        let params = (
+        let width = Self::canonicalize(width);
+        let growth_factor = Self::canonicalize(growth_factor);
+        let scale_factor = Self::canonicalize(scale_factor);
+        if let Err(error) = Self::check_params(width, growth_factor, num_finite_buckets) {
+            panic!("invalid bucketer: {}", error);
+        }
+        BUCKETER_CACHE.get((
             width.into(),
             growth_factor.into(),
             scale_factor.into(),
             num_finite_buckets,
-        );
-        let mut bucketers = BUCKETERS.lock().unwrap();
-        if !bucketers.contains(&params) {
-            bucketers.insert(Box::pin(Self { params }));
-        }
-        let bucketer = bucketers.get(&params).unwrap();
-        let bucketer: &Self = bucketer.as_ref().get_ref();
-        unsafe {
-            // Transmuting extends the lifetime of the `bucketer` reference to `'static`. This is
-            // safe here because bucketers are pinned and never removed from the bucketer set, and
-            // the bucketer set is never dropped.
-            std::mem::transmute(bucketer)
-        }
+        ))
+    }
+
+    /// Returns the number of distinct bucketers created so far.
+    ///
+    /// Bucketers are handed out as `&'static` references so they're never freed. A count that keeps
+    /// growing indicates code that creates bucketers with ever-changing parameters (e.g. derived from
+    /// data), which leaks memory.
+    pub fn cached_count() -> usize {
+        BUCKETER_CACHE.len()
+    }
+
+    /// Returns how many times the number of cached bucketers has reached a multiple of the warning
+    /// threshold (see `set_cache_warning_threshold`). Meant to be exported or polled for alerting:
+    /// any nonzero value is suspicious.
+    pub fn cache_warnings() -> usize {
+        BUCKETER_CACHE.warnings.load(Ordering::Relaxed)
+    }
+
+    /// Sets the number of cached bucketers past which a warning is counted in `cache_warnings`, and
+    /// counted again every time the count grows by that much. 0 disables the warning. The default
+    /// is `DEFAULT_CACHE_WARNING_THRESHOLD`.
+    pub fn set_cache_warning_threshold(threshold: usize) {
+        BUCKETER_CACHE
+            .warning_threshold
+            .store(threshold, Ordering::Relaxed);
     }
 
     pub fn fixed_width(width: f64, num_finite_buckets: usize) -> &'static Self {
         Self::get(width, 0.0, 1.0, num_finite_buckets)
     }
 
+    /// Tolerance used when rounding logarithms in `scaled_powers_of`.
+    const LOG_EPSILON: f64 = 1e-9;
+
+    /// Returns a bucketer whose bucket boundaries are `scale_factor * pow(base, i)`, with enough
+    /// buckets to cover `max`.
+    ///
+    /// The number of buckets is derived from `log(max / scale_factor, base)`. If that logarithm is
+    /// within `LOG_EPSILON` of an integer it's snapped to that integer before rounding up, so that
+    /// floating point error (e.g. `log(125, 5) == 3.0000000000000004`) doesn't add a spurious
+    /// bucket when `max` is an exact power of `base`.
     pub fn scaled_powers_of(base: f64, scale_factor: f64, max: f64) -> &'static Self {
-        let num_finite_buckets =
-            std::cmp::max(1, 1 + (max / scale_factor).log(base).ceil() as usize);
+        let exponent = (max / scale_factor).log(base);
+        let nearest = exponent.round();
+        let exponent = if (exponent - nearest).abs() < Self::LOG_EPSILON {
+            nearest
+        } else {
+            exponent.ceil()
+        };
+        let num_finite_buckets = std::cmp::max(1, 1 + exponent as usize);
         Self::get(0.0, base, scale_factor, num_finite_buckets)
     }
 
@@ -74,6 +165,22 @@ impl Bucketer {
         Self::powers_of(4.0)
     }
 
+    /// Returns the canonical bucketer for latencies measured in milliseconds.
+    ///
+    /// Bucket boundaries are `0.01 * pow(1.3, i)`, i.e. each bucket is 30% wider than the previous
+    /// one, from 10 microseconds up to about 68 seconds (61 finite buckets). That keeps the
+    /// relative error of percentiles below 15% across the whole range, which is adequate for RPC
+    /// latencies. Faster samples land in the underflow bucket, slower ones in the overflow bucket.
+    pub fn latency_ms() -> &'static Self {
+        Self::scaled_powers_of(1.3, 0.01, 60_000.0)
+    }
+
+    /// Like `latency_ms`, but for latencies measured in microseconds: the boundaries are the same
+    /// durations, i.e. `10 * pow(1.3, i)` microseconds.
+    pub fn latency_us() -> &'static Self {
+        Self::scaled_powers_of(1.3, 10.0, 60_000_000.0)
+    }
+
     pub fn custom(
         width: f64,
         growth_factor: f64,
@@ -83,6 +190,23 @@ impl Bucketer {
         Self::get(width, growth_factor, scale_factor, num_finite_buckets)
     }
 
+    /// Returns a bucketer with `num_finite_buckets` buckets of the same `width` covering
+    /// `[-range, range)` with `range = width * num_finite_buckets / 2`, for signed metrics (e.g.
+    /// temperature deltas) whose negative samples would otherwise all land in the underflow bucket.
+    ///
+    /// This is just a growth factor of 1 and a scale factor of `-range`, so that the boundary
+    /// formula becomes `width * (i + 1) - range`. With an odd number of buckets the middle one is
+    /// centered on 0, so a sample and its opposite land in mirrored buckets (except on the
+    /// boundaries, which belong to the bucket above). With an even number of buckets 0 is the lower
+    /// bound of the bucket `num_finite_buckets / 2`.
+    pub fn symmetric(width: f64, num_finite_buckets: usize) -> &'static Self {
+        let range = width * (num_finite_buckets as f64) / 2.0;
+        Self::get(width, 1.0, -range, num_finite_buckets)
+    }
+
+    /// Returns a bucketer with no finite buckets. Distributions using it only keep count, sum, mean,
+    /// and sum of squared deviations: negative samples land in the underflow bucket and all others
+    /// (including 0) in the overflow bucket.
     pub fn none() -> &'static Self {
         Self::get(0.0, 0.0, 0.0, 0)
     }
@@ -107,11 +231,25 @@ impl Bucketer {
         num_finite_buckets
     }
 
-    /// Returns the (inclusive) lower bound of the i-th bucket.
-    ///
-    /// NOTE: this function doesn't check that `i` is in the range `[0, num_finite_buckets)`, the
-    /// caller has to do that.
-    pub fn lower_bound(&self, i: isize) -> f64 {
+    /// Returns all four parameters at once, e.g. for serialization by external tools.
+    pub fn params(&self) -> BucketerParams {
+        BucketerParams {
+            width: self.width(),
+            growth_factor: self.growth_factor(),
+            scale_factor: self.scale_factor(),
+            num_finite_buckets: self.num_finite_buckets(),
+        }
+    }
+
+    /// Checks whether `self` and `other` are the same object, as opposed to `==` which compares
+    /// parameters.
+    pub fn same_as(&self, other: &Bucketer) -> bool {
+        std::ptr::eq(self, other)
+    }
+
+    /// Evaluates the boundary formula described in the struct comment, i.e. returns the exclusive
+    /// upper bound of the i-th bucket.
+    fn boundary(&self, i: isize) -> f64 {
         let i = i as f64;
         let mut result = self.width() * (i + 1.0);
         let growth_factor = self.growth_factor();
@@ -121,12 +259,40 @@ impl Bucketer {
         result
     }
 
+    /// Returns the (inclusive) lower bound of the i-th bucket.
+    ///
+    /// NOTE: this function doesn't check that `i` is in the range `[0, num_finite_buckets)`, the
+    /// caller has to do that.
+    pub fn lower_bound(&self, i: isize) -> f64 {
+        self.boundary(i - 1)
+    }
+
     /// Returns the (exclusive) upper bound of the i-th bucket.
     ///
     /// NOTE: this function doesn't check that `i` is in the range `[0, num_finite_buckets)`, the
     /// caller has to do that.
     pub fn upper_bound(&self, i: isize) -> f64 {
-        self.lower_bound(i + 1)
+        self.boundary(i)
+    }
+
+    /// Returns the point at fraction `frac` of the i-th bucket, i.e.
+    /// `lower_bound(i) + frac * (upper_bound(i) - lower_bound(i))`. Recording it is guaranteed to
+    /// land in bucket `i`, which is handy to generate synthetic data.
+    ///
+    /// Panics if `i` is not in the range `[0, num_finite_buckets)` or `frac` is not in `[0, 1)`.
+    pub fn value_at_fraction(&self, i: usize, frac: f64) -> f64 {
+        assert!(i < self.num_finite_buckets());
+        assert!((0.0..1.0).contains(&frac));
+        let lower = self.lower_bound(i as isize);
+        let upper = self.upper_bound(i as isize);
+        lower + frac * (upper - lower)
+    }
+
+    /// Returns the midpoint of the i-th finite bucket, which is guaranteed to land in it. Meant for
+    /// tests that need samples in known buckets. Panics if `i` is out of range.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn sample_in_bucket(&self, i: usize) -> f64 {
+        self.value_at_fraction(i, 0.5)
     }
 
     /// Performs a binary search over the buckets and retrieves the one where `sample` falls. If the
@@ -137,7 +303,7 @@ impl Bucketer {
         let mut j = self.num_finite_buckets() as isize + 1;
         while j > i {
             let k = i + ((j - i) >> 1);
-            let l = self.lower_bound(k - 1);
+            let l = self.lower_bound(k);
             if sample < l {
                 j = k;
             } else if sample > l {
@@ -177,6 +343,7 @@ impl Bucketer {
             Some(num_finite_buckets) => Ok(num_finite_buckets as usize),
             _ => Err(anyhow!("missing num_finite_buckets field from bucketer")),
         }?;
+        Self::check_params(width, growth_factor, num_finite_buckets)?;
         Ok(Self::get(
             width,
             growth_factor,
@@ -186,6 +353,51 @@ impl Bucketer {
     }
 }
 
+/// The global set of canonical bucketers, see `Bucketer::get`.
+#[derive(Debug)]
+struct BucketerCache {
+    bucketers: Mutex<BTreeSet<Pin<Box<Bucketer>>>>,
+    warning_threshold: AtomicUsize,
+    warnings: AtomicUsize,
+}
+
+impl Default for BucketerCache {
+    fn default() -> Self {
+        Self {
+            bucketers: Mutex::default(),
+            warning_threshold: AtomicUsize::new(Bucketer::DEFAULT_CACHE_WARNING_THRESHOLD),
+            warnings: AtomicUsize::default(),
+        }
+    }
+}
+
+impl BucketerCache {
+    fn get(&'static self, params: (F64, F64, F64, usize)) -> &'static Bucketer {
+        let mut bucketers = self.bucketers.lock().unwrap();
+        if !bucketers.contains(&params) {
+            bucketers.insert(Box::pin(Bucketer { params }));
+            let threshold = self.warning_threshold.load(Ordering::Relaxed);
+            if threshold > 0 && bucketers.len().is_multiple_of(threshold) {
+                self.warnings.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let bucketer = bucketers.get(&params).unwrap();
+        let bucketer: &Bucketer = bucketer.as_ref().get_ref();
+        unsafe {
+            // Transmuting extends the lifetime of the `bucketer` reference to `'static`. This is
+            // safe here because bucketers are pinned and never removed from the bucketer set, and
+            // the bucketer set is never dropped.
+            std::mem::transmute(bucketer)
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bucketers.lock().unwrap().len()
+    }
+}
+
+static BUCKETER_CACHE: LazyLock<BucketerCache> = LazyLock::new(BucketerCache::default);
+
 impl Borrow<(F64, F64, F64, usize)> for Pin<Box<Bucketer>> {
     fn borrow(&self) -> &(F64, F64, F64, usize) {
         &self.params
@@ -261,6 +473,12 @@ mod tests {
         assert_eq!(bucketer.num_finite_buckets(), 10);
     }
 
+    #[test]
+    #[should_panic(expected = "zero width and zero growth factor")]
+    fn test_zero_fixed_width() {
+        Bucketer::fixed_width(0.0, 5);
+    }
+
     #[test]
     fn test_scaled_powers_of() {
         let bucketer = Bucketer::scaled_powers_of(2.0, 3.0, 100.0);
@@ -270,6 +488,42 @@ mod tests {
         assert_eq!(bucketer.num_finite_buckets(), 7);
     }
 
+    #[test]
+    fn test_scaled_powers_of_exact_power() {
+        assert_eq!(
+            Bucketer::scaled_powers_of(10.0, 1.0, 1000.0).num_finite_buckets(),
+            4
+        );
+        assert_eq!(
+            Bucketer::scaled_powers_of(5.0, 1.0, 125.0).num_finite_buckets(),
+            4
+        );
+        assert_eq!(
+            Bucketer::scaled_powers_of(7.0, 1.0, 16807.0).num_finite_buckets(),
+            6
+        );
+        assert_eq!(
+            Bucketer::scaled_powers_of(2.0, 1.0, 536870912.0).num_finite_buckets(),
+            30
+        );
+        assert_eq!(
+            Bucketer::scaled_powers_of(5.0, 2.0, 250.0).num_finite_buckets(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_scaled_powers_of_near_power() {
+        assert_eq!(
+            Bucketer::scaled_powers_of(5.0, 1.0, 124.0).num_finite_buckets(),
+            4
+        );
+        assert_eq!(
+            Bucketer::scaled_powers_of(5.0, 1.0, 126.0).num_finite_buckets(),
+            5
+        );
+    }
+
     #[test]
     fn test_powers_of() {
         let bucketer = Bucketer::powers_of(2.0);
@@ -293,6 +547,119 @@ mod tests {
         assert_eq!(Bucketer::default(), Bucketer::powers_of(4.0));
     }
 
+    #[test]
+    fn test_symmetric() {
+        let bucketer = Bucketer::symmetric(1.0, 21);
+        assert_eq!(bucketer.num_finite_buckets(), 21);
+        assert_eq!(bucketer.lower_bound(0), -10.5);
+        assert_eq!(bucketer.upper_bound(20), 10.5);
+        assert_eq!(bucketer.get_bucket_for(0.0), 10);
+        assert_eq!(bucketer.lower_bound(10), -0.5);
+        assert_eq!(bucketer.upper_bound(10), 0.5);
+        let negative = bucketer.get_bucket_for(-5.0);
+        let positive = bucketer.get_bucket_for(5.0);
+        assert_eq!(negative, 5);
+        assert_eq!(positive, 15);
+        assert_eq!(negative + positive, 20);
+        assert_eq!(bucketer.get_bucket_for(-11.0), -1);
+        assert_eq!(bucketer.get_bucket_for(11.0), 21);
+    }
+
+    #[test]
+    fn test_symmetric_even() {
+        let bucketer = Bucketer::symmetric(2.0, 10);
+        assert_eq!(bucketer.lower_bound(0), -10.0);
+        assert_eq!(bucketer.upper_bound(9), 10.0);
+        assert_eq!(bucketer.get_bucket_for(0.0), 5);
+        assert_eq!(bucketer.get_bucket_for(-0.1), 4);
+        assert!(bucketer.same_as(Bucketer::symmetric(2.0, 10)));
+    }
+
+    #[test]
+    fn test_params() {
+        assert_eq!(
+            Bucketer::default().params(),
+            BucketerParams {
+                width: 0.0,
+                growth_factor: 4.0,
+                scale_factor: 1.0,
+                num_finite_buckets: 17,
+            }
+        );
+    }
+
+    #[test]
+    fn test_canonical_params() {
+        let b1 = Bucketer::custom(0.1 + 0.2, 1.1 * 1.1, 3.0 / 7.0 * 7.0, 10);
+        let b2 = Bucketer::custom(0.3, 1.21, 3.0, 10);
+        assert!(b1.same_as(b2));
+        assert_eq!(b1.width(), 0.3);
+        assert_eq!(b1.growth_factor(), 1.21);
+        assert!(!b1.same_as(Bucketer::custom(0.3000001, 1.21, 3.0, 10)));
+    }
+
+    #[test]
+    fn test_cache_count() {
+        let cache: &'static BucketerCache = Box::leak(Box::default());
+        let params1 = (1.0.into(), 0.0.into(), 1.0.into(), 10);
+        let params2 = (2.0.into(), 0.0.into(), 1.0.into(), 10);
+        assert_eq!(cache.len(), 0);
+        let b1 = cache.get(params1);
+        assert_eq!(cache.len(), 1);
+        cache.get(params2);
+        assert_eq!(cache.len(), 2);
+        assert!(b1.same_as(cache.get(params1)));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_cache_warnings() {
+        let cache: &'static BucketerCache = Box::leak(Box::default());
+        cache.warning_threshold.store(2, Ordering::Relaxed);
+        for width in 1..=5 {
+            cache.get(((width as f64).into(), 0.0.into(), 1.0.into(), 10));
+        }
+        cache.get((1.0.into(), 0.0.into(), 1.0.into(), 10));
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.warnings.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_cached_count() {
+        Bucketer::custom(1.0, 2.0, 3.0, 1234);
+        let count = Bucketer::cached_count();
+        assert!(count > 0);
+        Bucketer::custom(1.0, 2.0, 3.0, 1235);
+        assert!(Bucketer::cached_count() > count);
+    }
+
+    #[test]
+    fn test_latency_ms() {
+        let bucketer = Bucketer::latency_ms();
+        assert!(bucketer.same_as(Bucketer::latency_ms()));
+        assert_eq!(bucketer.growth_factor(), 1.3);
+        assert_eq!(bucketer.num_finite_buckets(), 61);
+        let bucket1 = bucketer.get_bucket_for(100.0);
+        let bucket2 = bucketer.get_bucket_for(1000.0);
+        assert!(0 <= bucket1 && bucket1 < bucket2 && bucket2 < 61);
+        assert_eq!(bucketer.get_bucket_for(0.001), -1);
+        assert_eq!(bucketer.get_bucket_for(100_000.0), 61);
+    }
+
+    #[test]
+    fn test_latency_us() {
+        let bucketer = Bucketer::latency_us();
+        assert_eq!(bucketer.num_finite_buckets(), 61);
+        assert_eq!(
+            bucketer.get_bucket_for(100_000.0),
+            Bucketer::latency_ms().get_bucket_for(100.0)
+        );
+        assert_eq!(
+            bucketer.get_bucket_for(1_000_000.0),
+            Bucketer::latency_ms().get_bucket_for(1000.0)
+        );
+    }
+
     #[test]
     fn test_none() {
         let bucketer = Bucketer::none();
@@ -311,6 +678,24 @@ mod tests {
         assert_eq!(bucketer.get_bucket_for(2.0), 0);
     }
 
+    #[test]
+    fn test_same_as() {
+        let b1 = Bucketer::custom(1.0, 2.0, 0.5, 20);
+        let b2 = Bucketer::custom(1.0, 2.0, 0.5, 20);
+        let b3 = Bucketer::custom(1.0, 2.0, 0.5, 21);
+        assert!(b1.same_as(b2));
+        assert!(!b1.same_as(b3));
+    }
+
+    #[test]
+    fn test_equality_by_value() {
+        let b1 = Bucketer::custom(1.0, 2.0, 0.5, 20);
+        let b2 = *b1;
+        assert_eq!(*b1, b2);
+        assert!(!b1.same_as(&b2));
+        assert_ne!(*b1, *Bucketer::custom(1.0, 2.0, 0.5, 21));
+    }
+
     #[test]
     fn test_underflow() {
         let bucketer = Bucketer::custom(1.0, 0.0, 1.0, 5);
@@ -349,6 +734,97 @@ mod tests {
         assert_eq!(bucketer.get_bucket_for(7.0), 5);
     }
 
+    #[test]
+    fn test_bounds() {
+        let bucketer = Bucketer::custom(1.0, 0.0, 1.0, 5);
+        assert_eq!(bucketer.lower_bound(0), 0.0);
+        assert_eq!(bucketer.upper_bound(0), 1.0);
+        assert_eq!(bucketer.lower_bound(4), 4.0);
+        assert_eq!(bucketer.upper_bound(4), 5.0);
+        let bucketer = Bucketer::default();
+        assert_eq!(bucketer.lower_bound(1), 1.0);
+        assert_eq!(bucketer.upper_bound(1), 4.0);
+        assert_eq!(bucketer.lower_bound(2), 4.0);
+        assert_eq!(bucketer.upper_bound(2), 16.0);
+    }
+
+    #[test]
+    fn test_bounds_agree_with_get_bucket_for() {
+        let bucketers = [
+            Bucketer::default(),
+            Bucketer::fixed_width(1.0, 10),
+            Bucketer::powers_of(2.0),
+            Bucketer::scaled_powers_of(2.0, 3.0, 100.0),
+            Bucketer::custom(1.0, 2.0, 0.5, 20),
+        ];
+        let samples = [
+            -1.0, 0.0, 0.1, 0.3, 0.5, 1.0, 1.5, 2.0, 3.0, 4.0, 7.5, 12.0, 42.0, 100.0, 1234.5,
+            1e6, 1e9,
+        ];
+        for bucketer in bucketers {
+            let num_finite_buckets = bucketer.num_finite_buckets() as isize;
+            for sample in samples {
+                let i = bucketer.get_bucket_for(sample);
+                if i < 0 {
+                    assert!(sample < bucketer.lower_bound(0));
+                } else if i >= num_finite_buckets {
+                    assert!(sample >= bucketer.upper_bound(num_finite_buckets - 1));
+                } else {
+                    assert!(bucketer.lower_bound(i) <= sample);
+                    assert!(sample < bucketer.upper_bound(i));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_at_fraction() {
+        let bucketer = Bucketer::default();
+        assert_eq!(bucketer.value_at_fraction(2, 0.0), 4.0);
+        assert_eq!(bucketer.value_at_fraction(2, 0.5), 10.0);
+        assert_eq!(
+            bucketer.get_bucket_for(bucketer.value_at_fraction(3, 0.5)),
+            3
+        );
+        let bucketer = Bucketer::custom(1.0, 2.0, 0.5, 20);
+        for i in 0..20 {
+            for frac in [0.0, 0.25, 0.5, 0.99] {
+                assert_eq!(
+                    bucketer.get_bucket_for(bucketer.value_at_fraction(i, frac)),
+                    i as isize
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_in_bucket() {
+        for bucketer in [
+            Bucketer::default(),
+            Bucketer::fixed_width(1.0, 5),
+            Bucketer::symmetric(2.0, 6),
+        ] {
+            for i in 0..bucketer.num_finite_buckets() {
+                assert_eq!(
+                    bucketer.get_bucket_for(bucketer.sample_in_bucket(i)),
+                    i as isize
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_value_at_fraction_out_of_range() {
+        Bucketer::fixed_width(1.0, 5).value_at_fraction(5, 0.5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_value_at_fraction_invalid_fraction() {
+        Bucketer::fixed_width(1.0, 5).value_at_fraction(2, 1.0);
+    }
+
     #[test]
     fn test_encode1() {
         let proto = Bucketer::default().encode();
@@ -382,4 +858,15 @@ mod tests {
         let b2 = Bucketer::decode(&proto).unwrap();
         assert!(std::ptr::eq(b1, b2));
     }
+
+    #[test]
+    fn test_decode_zero_width() {
+        let proto = proto::tsz::Bucketer {
+            width: Some(0.0),
+            growth_factor: Some(0.0),
+            scale_factor: Some(1.0),
+            num_finite_buckets: Some(5),
+        };
+        assert!(Bucketer::decode(&proto).is_err());
+    }
 }
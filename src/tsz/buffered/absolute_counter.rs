@@ -0,0 +1,278 @@
+use crate::tsz::{
+    FieldMap, buffered::manager::METRIC_MANAGER, buffered::manager::Metric,
+    buffered::manager::Registration, buffered::manager::flush_int_deltas,
+    buffered::manager::group_by_entity, config::MetricConfig, exporter::EXPORTER,
+};
+use crate::utils::lazy::Lazy;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct AbsoluteValues {
+    /// The latest absolute value set for each key since the last flush.
+    latest: BTreeMap<(FieldMap, FieldMap), i64>,
+    /// The last absolute value flushed for each key, which the next delta is computed from.
+    /// Entries are removed when the corresponding cells are deleted.
+    flushed: BTreeMap<(FieldMap, FieldMap), i64>,
+}
+
+#[derive(Debug)]
+struct AbsoluteCounterImpl {
+    name: &'static str,
+    config: MetricConfig,
+    registration: Registration,
+    data: Mutex<AbsoluteValues>,
+}
+
+impl AbsoluteCounterImpl {
+    fn new(name: &'static str, config: MetricConfig) -> Arc<Self> {
+        let metric = Arc::new(Self {
+            name,
+            config,
+            registration: Registration::new(),
+            data: Mutex::default(),
+        });
+        metric.registration.register(metric.clone());
+        metric
+    }
+
+    async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
+        self.registration.wait().await;
+        METRIC_MANAGER
+            .get_int(entity_labels, self.name, metric_fields)
+            .await
+    }
+
+    fn set(&self, value: i64, entity_labels: FieldMap, metric_fields: FieldMap) {
+        let mut data = self.data.lock().unwrap();
+        data.latest.insert((entity_labels, metric_fields), value);
+    }
+
+    async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        {
+            let key = (entity_labels.clone(), metric_fields.clone());
+            let mut data = self.data.lock().unwrap();
+            data.latest.remove(&key);
+            data.flushed.remove(&key);
+        }
+        EXPORTER
+            .delete_value(entity_labels, self.name, metric_fields)
+            .await
+            .is_some()
+    }
+
+    async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
+        {
+            let mut data = self.data.lock().unwrap();
+            data.latest.retain(|(labels, _), _| labels != entity_labels);
+            data.flushed
+                .retain(|(labels, _), _| labels != entity_labels);
+        }
+        EXPORTER
+            .delete_metric_from_entity(entity_labels, self.name)
+            .await
+    }
+
+    /// Takes the latest absolute values and turns them into deltas from the previously flushed
+    /// ones. A value lower than the previous one means that the upstream counter was reset, in
+    /// which case the whole value is the delta accumulated since the reset.
+    fn fetch_deltas(&self) -> BTreeMap<(FieldMap, FieldMap), i64> {
+        let mut data = self.data.lock().unwrap();
+        let latest = std::mem::take(&mut data.latest);
+        let mut deltas = BTreeMap::default();
+        for (key, value) in latest {
+            let delta = match data.flushed.get(&key) {
+                Some(previous) if value >= *previous => value - previous,
+                _ => value,
+            };
+            data.flushed.insert(key.clone(), value);
+            deltas.insert(key, delta);
+        }
+        deltas
+    }
+
+    async fn flush_impl(&self) {
+        flush_int_deltas(self.name, group_by_entity(self.fetch_deltas())).await;
+    }
+}
+
+impl Metric for AbsoluteCounterImpl {
+    fn id(&self) -> u64 {
+        self.registration.id()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn config(&self) -> &MetricConfig {
+        &self.config
+    }
+
+    fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.flush_impl())
+    }
+
+    /// Same as `Counter`: the deltas are lost for good if not flushed.
+    fn flush_priority(&self) -> u8 {
+        1
+    }
+}
+
+/// A buffered counter fed with absolute cumulative values rather than deltas, for upstreams that
+/// report running totals. Only the latest value set for a cell within a flush period matters: on
+/// flush the counter is incremented by the difference from the previously flushed value. If the
+/// value decreases the upstream is assumed to have been reset, and the new value is added in full.
+///
+/// Buffering the absolute values in a regular `Counter` would add them up and double-count.
+#[derive(Debug)]
+pub struct AbsoluteCounter {
+    name: &'static str,
+    config: MetricConfig,
+    inner: Lazy<Arc<AbsoluteCounterImpl>>,
+}
+
+impl AbsoluteCounter {
+    pub fn new(name: &'static str, mut config: MetricConfig) -> Self {
+        config.cumulative = true;
+        config.user_timestamps = true;
+        config.bucketer = None;
+        Self {
+            name,
+            config,
+            inner: Lazy::new(move || AbsoluteCounterImpl::new(name, config)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn config(&self) -> &MetricConfig {
+        &self.config
+    }
+
+    /// Returns the exported value, i.e. the sum of the flushed deltas, after flushing all buffers.
+    pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
+        self.inner.get(entity_labels, metric_fields).await
+    }
+
+    /// Records the current absolute value reported by the upstream.
+    pub fn set(&self, value: i64, entity_labels: FieldMap, metric_fields: FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner.set(value, entity_labels, metric_fields);
+    }
+
+    /// Deletes a cell, discarding the absolute value last flushed for it: the next value set
+    /// for the cell is added in full.
+    pub async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.inner.delete(entity_labels, metric_fields).await
+    }
+
+    /// Deletes all cells of an entity, see `delete`.
+    pub async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
+        self.inner.delete_entity(entity_labels).await
+    }
+}
+
+impl Drop for AbsoluteCounter {
+    fn drop(&mut self) {
+        Registration::unregister(self.inner.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsz::{
+        buffered::manager::FlushedDeltas, testing::test_entity_labels, testing::test_metric_fields,
+    };
+
+    #[tokio::test]
+    async fn test_new() {
+        let counter = AbsoluteCounter::new("/foo/bar/absolute_counter", MetricConfig::default());
+        assert_eq!(counter.name(), "/foo/bar/absolute_counter");
+        assert_eq!(
+            *counter.config(),
+            MetricConfig::counter().set_user_timestamps(true)
+        );
+        assert_eq!(
+            counter
+                .get(&test_entity_labels(), &test_metric_fields())
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_value_wins() {
+        let counter = AbsoluteCounter::new("/foo/bar/absolute_counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.set(3, entity_labels.clone(), metric_fields.clone());
+        counter.set(7, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_deltas_and_reset() {
+        let counter =
+            AbsoluteCounter::new("/foo/bar/absolute_counter/deltas", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let mut receiver = METRIC_MANAGER.subscribe();
+        let mut deltas = vec![];
+        for (value, expected) in [(10, 10), (25, 25), (5, 30)] {
+            counter.set(value, entity_labels.clone(), metric_fields.clone());
+            assert_eq!(
+                counter.get(&entity_labels, &metric_fields).await,
+                Some(expected)
+            );
+            loop {
+                let batch = receiver.recv().await.unwrap();
+                if batch.metric_name == counter.name() && batch.entity_labels == entity_labels {
+                    match batch.deltas {
+                        FlushedDeltas::Int(values) => deltas.push(values[&metric_fields]),
+                        _ => panic!(),
+                    }
+                    break;
+                }
+            }
+        }
+        assert_eq!(deltas, vec![10, 15, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let counter =
+            AbsoluteCounter::new("/foo/bar/absolute_counter/delete", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.set(10, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(10));
+        assert!(counter.delete(&entity_labels, &metric_fields).await);
+        assert!(counter.inner.data.lock().unwrap().flushed.is_empty());
+        assert!(!counter.delete(&entity_labels, &metric_fields).await);
+        counter.set(25, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(25));
+    }
+
+    #[tokio::test]
+    async fn test_delete_entity() {
+        let counter = AbsoluteCounter::new(
+            "/foo/bar/absolute_counter/delete_entity",
+            MetricConfig::default(),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.set(10, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(10));
+        assert!(counter.delete_entity(&entity_labels).await);
+        assert!(counter.inner.data.lock().unwrap().flushed.is_empty());
+        counter.set(25, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(25));
+    }
+}
@@ -1,54 +1,35 @@
 use crate::tsz::{
-    FieldMap, buffered::manager::METRIC_MANAGER, buffered::manager::Metric, config::MetricConfig,
-    exporter::EXPORTER,
+    FieldMap, buffered::manager::METRIC_MANAGER, buffered::manager::Metric,
+    buffered::manager::Registration, buffered::manager::flush_int_deltas,
+    buffered::manager::group_by_entity, config::MetricConfig, exporter::EXPORTER,
 };
 use crate::utils::lazy::Lazy;
 use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex, atomic::AtomicU64, atomic::Ordering};
-use tokio::task::JoinHandle;
 
 #[derive(Debug)]
 struct CounterImpl {
-    id: u64,
     name: &'static str,
     config: MetricConfig,
-    register_task_handle: Mutex<Option<JoinHandle<()>>>,
+    registration: Registration,
     data: Mutex<BTreeMap<(FieldMap, FieldMap), i64>>,
 }
 
 impl CounterImpl {
     fn new(name: &'static str, config: MetricConfig) -> Arc<Self> {
-        static IOTA: AtomicU64 = AtomicU64::new(0);
         let metric = Arc::new(Self {
-            id: IOTA.fetch_add(1, Ordering::Relaxed),
             name,
             config,
-            register_task_handle: Mutex::new(None),
+            registration: Registration::new(),
             data: Mutex::default(),
         });
-        metric.register();
+        metric.registration.register(metric.clone());
         metric
     }
 
-    fn register(self: &Arc<Self>) {
-        let metric = self.clone();
-        let mut register_task_handle = self.register_task_handle.lock().unwrap();
-        *register_task_handle = Some(tokio::spawn(async move {
-            METRIC_MANAGER.register_metric(metric).await;
-        }));
-    }
-
-    async fn await_registration(&self) {
-        let mut register_task_handle = self.register_task_handle.lock().unwrap();
-        if let Some(handle) = &mut *register_task_handle {
-            handle.await.unwrap();
-            *register_task_handle = None;
-        }
-    }
-
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
-        self.await_registration().await;
+        self.registration.wait().await;
         METRIC_MANAGER
             .get_int(entity_labels, self.name, metric_fields)
             .await
@@ -71,30 +52,13 @@ impl CounterImpl {
     }
 
     async fn flush_impl(&self) {
-        let data = self.fetch();
-        let mut data_by_entity = BTreeMap::<FieldMap, BTreeMap<FieldMap, i64>>::default();
-        for ((entity_labels, metric_fields), delta) in data {
-            if let Some(entity_data) = data_by_entity.get_mut(&entity_labels) {
-                if let Some(value) = entity_data.get_mut(&metric_fields) {
-                    *value += delta;
-                } else {
-                    entity_data.insert(metric_fields, delta);
-                }
-            } else {
-                data_by_entity.insert(entity_labels, BTreeMap::from([(metric_fields, delta)]));
-            }
-        }
-        for (entity_labels, deltas) in data_by_entity {
-            EXPORTER
-                .add_int_deltas(&entity_labels, self.name, deltas)
-                .await;
-        }
+        flush_int_deltas(self.name, group_by_entity(self.fetch())).await;
     }
 }
 
 impl Metric for CounterImpl {
     fn id(&self) -> u64 {
-        self.id
+        self.registration.id()
     }
 
     fn name(&self) -> &'static str {
@@ -108,12 +72,18 @@ impl Metric for CounterImpl {
     fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
         Box::pin(self.flush_impl())
     }
+
+    /// Counter deltas are lost for good if not flushed, so they drain before other metrics.
+    fn flush_priority(&self) -> u8 {
+        1
+    }
 }
 
 #[derive(Debug)]
 pub struct Counter {
     name: &'static str,
     config: MetricConfig,
+    rejected_deltas: AtomicU64,
     inner: Lazy<Arc<CounterImpl>>,
 }
 
@@ -125,6 +95,7 @@ impl Counter {
         Self {
             name,
             config,
+            rejected_deltas: AtomicU64::default(),
             inner: Lazy::new(move || CounterImpl::new(name, config)),
         }
     }
@@ -137,6 +108,12 @@ impl Counter {
         &self.config
     }
 
+    /// Returns the number of negative deltas dropped because of the `reject_negative_deltas` config
+    /// option.
+    pub fn rejected_deltas(&self) -> u64 {
+        self.rejected_deltas.load(Ordering::Relaxed)
+    }
+
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
         self.inner.get(entity_labels, metric_fields).await
     }
@@ -149,11 +126,25 @@ impl Counter {
             .unwrap()
     }
 
+    /// Adds `delta` to the counter. Negative deltas are accepted unless the metric is configured
+    /// with `reject_negative_deltas`, in which case they're dropped and counted in
+    /// `rejected_deltas`.
     pub fn increment_by(&self, delta: i64, entity_labels: FieldMap, metric_fields: FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
+        if delta < 0 && self.config.reject_negative_deltas {
+            self.rejected_deltas.fetch_add(1, Ordering::Relaxed);
+            EXPORTER.record_dropped_samples(self.name, 1);
+            return;
+        }
         self.inner.increment_by(delta, entity_labels, metric_fields);
     }
 
     pub fn increment(&self, entity_labels: FieldMap, metric_fields: FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.increment_by(1, entity_labels, metric_fields);
     }
 
@@ -162,10 +153,7 @@ impl Counter {
 
 impl Drop for Counter {
     fn drop(&mut self) {
-        let inner = self.inner.clone();
-        tokio::spawn(async move {
-            METRIC_MANAGER.unregister_metric(inner).await;
-        });
+        Registration::unregister(self.inner.clone());
     }
 }
 
@@ -173,8 +161,10 @@ impl Drop for Counter {
 mod tests {
     use super::*;
     use crate::tsz::{
-        bucketer::Bucketer, testing::test_entity_labels, testing::test_metric_fields,
+        bucketer::Bucketer, buffered::manager::FlushedDeltas, buffered::manager::MetricManager,
+        testing::test_entity_labels, testing::test_metric_fields,
     };
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_new() {
@@ -322,4 +312,134 @@ mod tests {
             Some(2)
         );
     }
+
+    #[tokio::test]
+    async fn test_reject_negative_delta() {
+        let counter = Counter::new(
+            "/foo/bar/counter",
+            MetricConfig::default().set_reject_negative_deltas(true),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment_by(3, entity_labels.clone(), metric_fields.clone());
+        counter.increment_by(-2, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(3));
+        assert_eq!(counter.rejected_deltas(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_instance_count() {
+        let counter1 = Counter::new("/foo/bar/counter/instances", MetricConfig::default());
+        let counter2 = Counter::new("/foo/bar/counter/instances", MetricConfig::default());
+        counter1.inner.registration.wait().await;
+        counter2.inner.registration.wait().await;
+        assert_eq!(
+            METRIC_MANAGER
+                .instance_count("/foo/bar/counter/instances")
+                .await,
+            2
+        );
+        assert!(METRIC_MANAGER.total_instances().await >= 2);
+        drop(counter1);
+        for _ in 0..100 {
+            if METRIC_MANAGER
+                .instance_count("/foo/bar/counter/instances")
+                .await
+                < 2
+            {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            METRIC_MANAGER
+                .instance_count("/foo/bar/counter/instances")
+                .await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush_priority() {
+        let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
+        assert!(counter.inner.flush_priority() > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_get() {
+        let counter = Arc::new(Counter::new(
+            "/foo/bar/counter/concurrent",
+            MetricConfig::default(),
+        ));
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment(entity_labels.clone(), metric_fields.clone());
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let counter = counter.clone();
+                let entity_labels = entity_labels.clone();
+                let metric_fields = metric_fields.clone();
+                tokio::spawn(async move { counter.get(&entity_labels, &metric_fields).await })
+            })
+            .collect();
+        for task in tasks {
+            let result = tokio::time::timeout(std::time::Duration::from_secs(10), task)
+                .await
+                .expect("concurrent get deadlocked")
+                .unwrap();
+            assert_eq!(result, Some(1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_start_and_stop() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let counter = Counter::new("/foo/bar/counter/start", MetricConfig::default());
+        manager.register_metric((*counter.inner).clone()).await;
+        manager.start_with_period(Duration::from_millis(10)).await;
+        manager.start_with_period(Duration::from_millis(10)).await;
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment(entity_labels.clone(), metric_fields.clone());
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        assert_eq!(
+            EXPORTER
+                .get_int(&entity_labels, "/foo/bar/counter/start", &metric_fields)
+                .await,
+            Some(1)
+        );
+        manager.stop().await;
+        manager.stop().await;
+        counter.increment(entity_labels.clone(), metric_fields.clone());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            EXPORTER
+                .get_int(&entity_labels, "/foo/bar/counter/start", &metric_fields)
+                .await,
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let counter = Counter::new("/foo/bar/counter/subscribed", MetricConfig::default());
+        manager.register_metric((*counter.inner).clone()).await;
+        let mut receiver = manager.subscribe();
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment_by(42, entity_labels.clone(), metric_fields.clone());
+        manager.flush_all().await;
+        let batch = loop {
+            let batch = receiver.try_recv().unwrap();
+            if batch.metric_name == "/foo/bar/counter/subscribed" {
+                break batch;
+            }
+        };
+        assert_eq!(batch.entity_labels, entity_labels);
+        assert_eq!(
+            batch.deltas,
+            FlushedDeltas::Int(BTreeMap::from([(metric_fields, 42)]))
+        );
+    }
 }
@@ -1,49 +1,43 @@
 use crate::tsz::{
-    FieldMap, bucketer::BucketerRef, buffered::manager::METRIC_MANAGER, buffered::manager::Metric,
-    config::MetricConfig, distribution::Distribution, exporter::EXPORTER,
+    FieldMap, bucketer::BucketerRef, buffered::manager::FlushedBatch,
+    buffered::manager::FlushedDeltas, buffered::manager::METRIC_MANAGER, buffered::manager::Metric,
+    buffered::manager::Registration, buffered::manager::group_by_entity,
+    buffered::manager::publish, config::MetricConfig, distribution::Distribution,
+    event_metric::TimeUnit, exporter::EXPORTER, exporter::TimestampedDistribution,
 };
 use crate::utils::lazy::Lazy;
+use anyhow::{Result, anyhow};
 use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex, atomic::AtomicU64, atomic::Ordering};
-use tokio::task::JoinHandle;
+use std::sync::{Arc, Mutex, atomic::AtomicUsize, atomic::Ordering};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug)]
 pub struct EventMetricImpl {
-    id: u64,
     name: &'static str,
     config: MetricConfig,
-    register_task_handle: Mutex<Option<JoinHandle<()>>>,
-    data: Mutex<BTreeMap<(FieldMap, FieldMap), Distribution>>,
+    registration: Registration,
+    observed: AtomicUsize,
+    /// The bucketer of the buffered samples. Only ever changed with `data` locked.
+    bucketer: Mutex<BucketerRef>,
+    data: Mutex<BTreeMap<(FieldMap, FieldMap), TimestampedDistribution>>,
 }
 
 impl EventMetricImpl {
     fn new(name: &'static str, config: MetricConfig) -> Arc<Self> {
-        static IOTA: AtomicU64 = AtomicU64::new(0);
         let metric = Arc::new(Self {
-            id: IOTA.fetch_add(1, Ordering::Relaxed),
             name,
             config,
-            register_task_handle: Mutex::new(None),
+            registration: Registration::new(),
+            observed: AtomicUsize::new(0),
+            bucketer: Mutex::new(config.bucketer.unwrap()),
             data: Mutex::default(),
         });
-        metric.register();
+        metric.registration.register(metric.clone());
         metric
     }
 
-    fn register(self: &Arc<Self>) {
-        let metric = self.clone();
-        let mut register_task_handle = self.register_task_handle.lock().unwrap();
-        *register_task_handle = Some(tokio::spawn(async move {
-            METRIC_MANAGER.register_metric(metric).await;
-        }));
-    }
-
-    async fn await_registration(&self) {
-        let mut register_task_handle = self.register_task_handle.lock().unwrap();
-        if let Some(handle) = &mut *register_task_handle {
-            handle.await.unwrap();
-            *register_task_handle = None;
-        }
+    fn bucketer(&self) -> BucketerRef {
+        *self.bucketer.lock().unwrap()
     }
 
     async fn get(
@@ -51,57 +45,189 @@ impl EventMetricImpl {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) -> Option<Distribution> {
-        self.await_registration().await;
+        self.registration.wait().await;
         METRIC_MANAGER
             .get_distribution(entity_labels, self.name, metric_fields)
             .await
     }
 
-    fn record(&self, sample: f64, times: usize, entity_labels: FieldMap, metric_fields: FieldMap) {
-        let bucketer = self.config.bucketer.unwrap();
-        let bucket = bucketer.get_bucket_for(sample);
+    fn record(
+        &self,
+        sample: f64,
+        times: usize,
+        timestamp: SystemTime,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        self.record_impl(sample, None, times, timestamp, entity_labels, metric_fields);
+    }
+
+    fn record_to_bucket(
+        &self,
+        sample: f64,
+        bucket: isize,
+        times: usize,
+        timestamp: SystemTime,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        self.record_impl(
+            sample,
+            Some(bucket),
+            times,
+            timestamp,
+            entity_labels,
+            metric_fields,
+        );
+    }
+
+    /// Looks up the bucket of `sample` unless the caller already did. The lookup happens with the
+    /// buffer locked so that it always uses the bucketer of the buffered samples.
+    fn record_impl(
+        &self,
+        sample: f64,
+        bucket: Option<isize>,
+        times: usize,
+        timestamp: SystemTime,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        self.observed.fetch_add(times, Ordering::Relaxed);
+        let times = times.saturating_mul(self.config.sampling_weight());
         let key = (entity_labels, metric_fields);
         let mut data = self.data.lock().unwrap();
-        if let Some(distribution) = data.get_mut(&key) {
+        let bucketer = self.bucketer();
+        let bucket = bucket.unwrap_or_else(|| bucketer.get_bucket_for(sample));
+        if let Some((distribution, first, last)) = data.get_mut(&key) {
             distribution.record_to_bucket(sample, bucket, times);
+            *first = std::cmp::min(*first, timestamp);
+            *last = std::cmp::max(*last, timestamp);
         } else {
             let mut distribution = Distribution::new(bucketer);
             distribution.record_to_bucket(sample, bucket, times);
-            data.insert(key, distribution);
+            data.insert(key, (distribution, timestamp, timestamp));
         }
     }
 
-    fn fetch(&self) -> BTreeMap<(FieldMap, FieldMap), Distribution> {
+    fn record_batch(
+        &self,
+        samples: &[(f64, usize)],
+        timestamp: SystemTime,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        if samples.is_empty() {
+            return;
+        }
+        let observed: usize = samples.iter().map(|(_, times)| times).sum();
+        self.observed.fetch_add(observed, Ordering::Relaxed);
+        let weight = self.config.sampling_weight();
+        let key = (entity_labels, metric_fields);
+        let mut data = self.data.lock().unwrap();
+        let bucketer = self.bucketer();
+        let (distribution, first, last) = data
+            .entry(key)
+            .or_insert_with(|| (Distribution::new(bucketer), timestamp, timestamp));
+        for (sample, times) in samples {
+            let bucket = bucketer.get_bucket_for(*sample);
+            distribution.record_to_bucket(*sample, bucket, times.saturating_mul(weight));
+        }
+        *first = std::cmp::min(*first, timestamp);
+        *last = std::cmp::max(*last, timestamp);
+    }
+
+    async fn record_and_get(
+        &self,
+        sample: f64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> Distribution {
+        self.registration.wait().await;
+        self.observed.fetch_add(1, Ordering::Relaxed);
+        let times = self.config.sampling_weight();
+        METRIC_MANAGER
+            .add_to_distribution_and_get(entity_labels, self.name, sample, times, metric_fields)
+            .await
+            .unwrap_or_else(|| Distribution::new(self.bucketer()))
+    }
+
+    fn record_distribution(
+        &self,
+        delta: &Distribution,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) -> Result<()> {
+        let now = EXPORTER.clock().now();
+        let key = (entity_labels, metric_fields);
+        let mut data = self.data.lock().unwrap();
+        if !delta.has_bucketer(self.bucketer()) {
+            return Err(anyhow!("incompatible bucketers"));
+        }
+        if let Some((distribution, _, last)) = data.get_mut(&key) {
+            *last = std::cmp::max(*last, now);
+            distribution.add(delta)
+        } else {
+            data.insert(key, (delta.clone(), now, now));
+            Ok(())
+        }
+    }
+
+    /// Takes the buffered deltas out, switching to `bucketer` (if any) for the samples recorded
+    /// from then on.
+    fn fetch(
+        &self,
+        bucketer: Option<BucketerRef>,
+    ) -> BTreeMap<(FieldMap, FieldMap), TimestampedDistribution> {
         let new_data = BTreeMap::default();
         let mut data = self.data.lock().unwrap();
+        if let Some(bucketer) = bucketer {
+            *self.bucketer.lock().unwrap() = bucketer;
+        }
         std::mem::replace(&mut *data, new_data)
     }
 
+    /// Flushes the buffered deltas to the exporter. If the metric was redefined in the exporter
+    /// with a different bucketer since the samples were buffered, the deltas are rebucketed into
+    /// the new bucketer (see `Distribution::add_rebucketed`) so that they don't get merged into
+    /// cells with mismatching buckets. The metric then buffers further samples with the new
+    /// bucketer, so only the samples buffered before the flush lose precision.
     async fn flush_impl(&self) {
-        let data = self.fetch();
-        let mut data_by_entity = BTreeMap::<FieldMap, BTreeMap<FieldMap, Distribution>>::default();
-        for ((entity_labels, metric_fields), delta) in data {
-            if let Some(entity_data) = data_by_entity.get_mut(&entity_labels) {
-                if let Some(distribution) = entity_data.get_mut(&metric_fields) {
-                    distribution.add(&delta).unwrap();
-                } else {
-                    entity_data.insert(metric_fields, delta);
+        let bucketer = EXPORTER
+            .get_metric_config(self.name)
+            .and_then(|config| config.bucketer);
+        for (entity_labels, mut deltas) in group_by_entity(self.fetch(bucketer)) {
+            if let Some(bucketer) = bucketer {
+                for (delta, _, _) in deltas.values_mut() {
+                    if !delta.has_bucketer(bucketer) {
+                        let mut rebucketed = Distribution::new(bucketer);
+                        rebucketed.add_rebucketed(delta);
+                        *delta = rebucketed;
+                    }
                 }
-            } else {
-                data_by_entity.insert(entity_labels, BTreeMap::from([(metric_fields, delta)]));
             }
-        }
-        for (entity_labels, deltas) in data_by_entity {
+            publish(|| FlushedBatch {
+                metric_name: self.name,
+                entity_labels: entity_labels.clone(),
+                deltas: FlushedDeltas::Distribution(
+                    deltas
+                        .iter()
+                        .map(|(metric_fields, (delta, _, _))| {
+                            (metric_fields.clone(), delta.clone())
+                        })
+                        .collect(),
+                ),
+            });
             EXPORTER
-                .add_distribution_deltas(&entity_labels, self.name, deltas)
-                .await;
+                .add_timestamped_distribution_deltas(&entity_labels, self.name, deltas)
+                .await
+                .ok();
         }
     }
 }
 
 impl Metric for EventMetricImpl {
     fn id(&self) -> u64 {
-        self.id
+        self.registration.id()
     }
 
     fn name(&self) -> &'static str {
@@ -146,8 +272,11 @@ impl EventMetric {
         &self.config
     }
 
+    /// Returns the bucketer of the samples recorded from now on. That's the one of `config` unless
+    /// the metric was redefined in the exporter with a different bucketer, in which case the metric
+    /// switches to the new one on its next flush.
     pub fn bucketer(&self) -> BucketerRef {
-        self.config.bucketer.unwrap()
+        self.inner.bucketer()
     }
 
     pub async fn get(
@@ -170,6 +299,13 @@ impl EventMetric {
             .unwrap()
     }
 
+    /// Returns the number of samples actually passed to the record functions of this metric. See
+    /// `tsz::event_metric::EventMetric::observed_samples` for how this relates to the recorded
+    /// counts when the config has a sampling rate.
+    pub fn observed_samples(&self) -> usize {
+        self.inner.observed.load(Ordering::Relaxed)
+    }
+
     pub fn record_many(
         &self,
         sample: f64,
@@ -177,17 +313,134 @@ impl EventMetric {
         entity_labels: FieldMap,
         metric_fields: FieldMap,
     ) {
+        if !self.config.enabled() {
+            return;
+        }
+        let now = EXPORTER.clock().now();
+        self.inner
+            .record(sample, times, now, entity_labels, metric_fields);
+    }
+
+    /// Records each `(sample, times)` pair of `samples` as `record_many` would, but locking the
+    /// buffer only once for the whole batch, which is cheaper for bulk ingestion.
+    pub fn record_batch(
+        &self,
+        samples: &[(f64, usize)],
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        let now = EXPORTER.clock().now();
         self.inner
-            .record(sample, times, entity_labels, metric_fields);
+            .record_batch(samples, now, entity_labels, metric_fields);
     }
 
     pub fn record(&self, sample: f64, entity_labels: FieldMap, metric_fields: FieldMap) {
-        self.inner.record(sample, 1, entity_labels, metric_fields);
+        self.record_at(sample, EXPORTER.clock().now(), entity_labels, metric_fields);
+    }
+
+    /// Records a sample that occurred at `timestamp` rather than now. Since event metrics have
+    /// `user_timestamps`, the exported cell starts at the time of the first event and is updated
+    /// at the time of the latest one.
+    pub fn record_at(
+        &self,
+        sample: f64,
+        timestamp: SystemTime,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner
+            .record(sample, 1, timestamp, entity_labels, metric_fields);
+    }
+
+    /// Like `record`, but with the bucket of the sample already computed by the caller (e.g. once
+    /// for many identical samples), which skips the bucket lookup. `bucket` must be
+    /// `bucketer().get_bucket_for(sample)`, which is only checked in debug builds.
+    pub fn record_to_bucket(
+        &self,
+        sample: f64,
+        bucket: isize,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        debug_assert_eq!(bucket, self.bucketer().get_bucket_for(sample));
+        let now = EXPORTER.clock().now();
+        self.inner
+            .record_to_bucket(sample, bucket, 1, now, entity_labels, metric_fields);
+    }
+
+    /// Records `sample` and returns the resulting distribution of the cell, including the samples
+    /// still buffered. Unlike `record` this writes to the exporter right away, flushing the buffers
+    /// of the metric first, so it's much more expensive and meant for tests and feedback loops
+    /// rather than hot paths. If the metric is disabled nothing is recorded and an empty
+    /// distribution is returned.
+    pub async fn record_and_get(
+        &self,
+        sample: f64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> Distribution {
+        if !self.config.enabled() {
+            return Distribution::new(self.bucketer());
+        }
+        self.inner
+            .record_and_get(sample, entity_labels, metric_fields)
+            .await
+    }
+
+    /// Merges all samples of `distribution` at once, e.g. to aggregate histograms computed
+    /// elsewhere. Fails if `distribution` doesn't use the bucketer of this metric.
+    pub fn record_distribution(
+        &self,
+        distribution: &Distribution,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) -> Result<()> {
+        if !self.config.enabled() {
+            return Ok(());
+        }
+        self.inner
+            .record_distribution(distribution, entity_labels, metric_fields)
+    }
+
+    /// Records a duration in milliseconds, which is the conventional unit of latency metrics.
+    pub fn record_duration(
+        &self,
+        duration: Duration,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        self.record_duration_as(duration, TimeUnit::Millis, entity_labels, metric_fields);
+    }
+
+    /// Records a duration in the specified unit.
+    pub fn record_duration_as(
+        &self,
+        duration: Duration,
+        unit: TimeUnit,
+        entity_labels: FieldMap,
+        metric_fields: FieldMap,
+    ) {
+        self.record(unit.to_sample(duration), entity_labels, metric_fields);
     }
 
     // TODO
 }
 
+impl Drop for EventMetric {
+    fn drop(&mut self) {
+        Registration::unregister(self.inner.clone());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -275,6 +528,56 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_record_to_bucket() {
+        let config = MetricConfig::default().set_bucketer(Bucketer::fixed_width(1.0, 10));
+        let metric1 = EventMetric::new("/foo/bar/distribution/by_bucket1", config);
+        let metric2 = EventMetric::new("/foo/bar/distribution/by_bucket2", config);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for sample in [-1.0, 2.5, 2.5, 7.0, 42.0] {
+            metric1.record(sample, entity_labels.clone(), metric_fields.clone());
+            let bucket = metric2.bucketer().get_bucket_for(sample);
+            metric2.record_to_bucket(sample, bucket, entity_labels.clone(), metric_fields.clone());
+        }
+        let d1 = metric1.get(&entity_labels, &metric_fields).await.unwrap();
+        let d2 = metric2.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(d1, d2);
+        assert_eq!(d1.count(), d2.count());
+        assert_eq!(d1.sum(), d2.sum());
+        assert_eq!(d2.bucket(2), 2);
+    }
+
+    #[tokio::test]
+    async fn test_record_and_get() {
+        let metric = EventMetric::new(
+            "/foo/bar/distribution/record_and_get",
+            MetricConfig::default(),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        let d = metric
+            .record_and_get(34.0, &entity_labels, &metric_fields)
+            .await;
+        let mut expected = Distribution::default();
+        expected.record(12.0);
+        expected.record(34.0);
+        assert_eq!(d, expected);
+        assert_eq!(d.count(), 2);
+        assert_eq!(d.sum(), 46.0);
+        assert_eq!(metric.observed_samples(), 2);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic]
+    async fn test_record_to_wrong_bucket() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        metric.record_to_bucket(42.0, 0, test_entity_labels(), test_metric_fields());
+    }
+
     #[tokio::test]
     async fn test_record_sample_twice() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -354,5 +657,274 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_record_distribution() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let mut child1 = Distribution::default();
+        child1.record(12.0);
+        child1.record(34.0);
+        let mut child2 = Distribution::default();
+        child2.record(56.0);
+        metric.record(78.0, entity_labels.clone(), metric_fields.clone());
+        metric
+            .record_distribution(&child1, entity_labels.clone(), metric_fields.clone())
+            .unwrap();
+        metric
+            .record_distribution(&child2, entity_labels.clone(), metric_fields.clone())
+            .unwrap();
+        let mut d = Distribution::default();
+        d.record(78.0);
+        d.add(&child1).unwrap();
+        d.add(&child2).unwrap();
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual, d);
+        assert_eq!(actual.count(), 4);
+        assert_eq!(actual.sum(), 180.0);
+    }
+
+    #[tokio::test]
+    async fn test_record_distribution_equivalent_bucketer() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let copy: &'static Bucketer = Box::leak(Box::new(*Bucketer::default()));
+        let mut child = Distribution::new(copy.into());
+        child.record(12.0);
+        metric
+            .record_distribution(&child, entity_labels.clone(), metric_fields.clone())
+            .unwrap();
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual, child);
+    }
+
+    #[tokio::test]
+    async fn test_record_incompatible_distribution() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let mut child = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        child.record(5.0);
+        assert!(
+            metric
+                .record_distribution(&child, entity_labels.clone(), metric_fields.clone())
+                .is_err()
+        );
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_record_duration() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record_duration(
+            Duration::from_millis(42),
+            entity_labels.clone(),
+            metric_fields.clone(),
+        );
+        let mut d = Distribution::default();
+        d.record(42.0);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[tokio::test]
+    async fn test_record_duration_as() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record_duration_as(
+            Duration::from_secs(2),
+            TimeUnit::Seconds,
+            entity_labels.clone(),
+            metric_fields.clone(),
+        );
+        let mut d = Distribution::default();
+        d.record(2.0);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_rate() {
+        let metric = EventMetric::new(
+            "/foo/bar/buffered/sampled",
+            MetricConfig::default().set_sampling_rate(10),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(42.0, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(metric.observed_samples(), 1);
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual.count(), 10);
+        assert_eq!(actual.mean(), 42.0);
+    }
+
+    #[tokio::test]
+    async fn test_sampling_rate_saturates() {
+        let metric = EventMetric::new(
+            "/foo/bar/buffered/saturated",
+            MetricConfig::default().set_sampling_rate(10),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record_many(
+            42.0,
+            usize::MAX / 2,
+            entity_labels.clone(),
+            metric_fields.clone(),
+        );
+        assert_eq!(metric.observed_samples(), usize::MAX / 2);
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual.count(), usize::MAX);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_get() {
+        let metric = Arc::new(EventMetric::new(
+            "/foo/bar/buffered/concurrent",
+            MetricConfig::default(),
+        ));
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(42.0, entity_labels.clone(), metric_fields.clone());
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let metric = metric.clone();
+                let entity_labels = entity_labels.clone();
+                let metric_fields = metric_fields.clone();
+                tokio::spawn(async move { metric.get(&entity_labels, &metric_fields).await })
+            })
+            .collect();
+        for task in tasks {
+            let result = tokio::time::timeout(Duration::from_secs(10), task)
+                .await
+                .expect("concurrent get deadlocked")
+                .unwrap();
+            assert_eq!(result.map(|d| d.count()), Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_at() {
+        let metric = EventMetric::new("/foo/bar/buffered/timestamped", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let t1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let t2 = SystemTime::UNIX_EPOCH + Duration::from_secs(2000);
+        metric.record_at(12.0, t2, entity_labels.clone(), metric_fields.clone());
+        metric.record_at(34.0, t1, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(
+            metric
+                .get(&entity_labels, &metric_fields)
+                .await
+                .map(|d| d.count()),
+            Some(2)
+        );
+        let snapshot = EXPORTER.snapshot().await;
+        let cell = snapshot
+            .get_cell(
+                &entity_labels,
+                "/foo/bar/buffered/timestamped",
+                &metric_fields,
+            )
+            .unwrap();
+        assert_eq!(cell.start_timestamp(), t1);
+        assert_eq!(cell.update_timestamp(), t2);
+    }
+
+    #[tokio::test]
+    async fn test_flush_after_redefinition() {
+        let config = MetricConfig::default().set_bucketer(Bucketer::fixed_width(1.0, 10));
+        let metric = EventMetric::new("/foo/bar/distribution/redefined", config);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(2.5, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(
+            metric
+                .get(&entity_labels, &metric_fields)
+                .await
+                .unwrap()
+                .count(),
+            1
+        );
+        let new_bucketer = Bucketer::fixed_width(5.0, 2);
+        EXPORTER
+            .redefine_metric(
+                "/foo/bar/distribution/redefined",
+                metric.config().set_bucketer(new_bucketer),
+            )
+            .await
+            .unwrap();
+        metric.record(2.5, entity_labels.clone(), metric_fields.clone());
+        metric.record(7.5, entity_labels.clone(), metric_fields.clone());
+        let mut expected = Distribution::new(new_bucketer.into());
+        expected.record(2.5);
+        expected.record(7.5);
+        assert_eq!(
+            metric.get(&entity_labels, &metric_fields).await,
+            Some(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_after_redefinition() {
+        let old_bucketer = Bucketer::fixed_width(1.0, 10);
+        let new_bucketer = Bucketer::fixed_width(5.0, 4);
+        let metric = EventMetric::new(
+            "/foo/bar/distribution/rebucketed",
+            MetricConfig::default().set_bucketer(old_bucketer),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        let d = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert!(d.bucketer().same_as(old_bucketer));
+        assert_eq!(d.overflow(), 1);
+        EXPORTER
+            .redefine_metric(
+                "/foo/bar/distribution/rebucketed",
+                metric.config().set_bucketer(new_bucketer),
+            )
+            .await
+            .unwrap();
+        // Buffered with the old bucketer, so it's rebucketed as an overflow.
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        let d = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert!(d.bucketer().same_as(new_bucketer));
+        assert_eq!(d.count(), 1);
+        assert_eq!(d.overflow(), 1);
+        assert!(metric.bucketer().same_as(new_bucketer));
+        // Buffered with the new bucketer.
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        let d = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(d.count(), 2);
+        assert_eq!(d.overflow(), 1);
+        assert_eq!(d.bucket(2), 1);
+    }
+
+    #[tokio::test]
+    async fn test_record_batch() {
+        let config = MetricConfig::default().set_bucketer(Bucketer::fixed_width(1.0, 10));
+        let batched = EventMetric::new("/foo/bar/distribution/batched", config);
+        let individual = EventMetric::new("/foo/bar/distribution/individual", config);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let samples = [(2.5, 3), (7.5, 1), (-1.0, 2), (2.5, 1)];
+        batched.record_batch(&samples, entity_labels.clone(), metric_fields.clone());
+        for (sample, times) in samples {
+            individual.record_many(sample, times, entity_labels.clone(), metric_fields.clone());
+        }
+        assert_eq!(batched.observed_samples(), 7);
+        let expected = individual
+            .get(&entity_labels, &metric_fields)
+            .await
+            .unwrap();
+        let actual = batched.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(actual.count(), 7);
+        assert_eq!(actual.sum(), expected.sum());
+    }
+
     // TODO
 }
@@ -1,9 +1,13 @@
-use crate::tsz::{FieldMap, config::MetricConfig, distribution::Distribution, exporter::EXPORTER};
+use crate::tsz::{
+    FieldMap, bucketer::Bucketer, config::MetricConfig, distribution::Distribution,
+    exporter::EXPORTER,
+};
 use std::collections::BTreeMap;
 use std::pin::Pin;
-use std::sync::{Arc, LazyLock};
+use std::sync::{Arc, LazyLock, Mutex as SyncMutex, atomic::AtomicU64, atomic::Ordering};
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell, broadcast, oneshot};
+use tokio::task::JoinHandle;
 
 /// Implemented by all buffered metrics.
 ///
@@ -14,32 +18,332 @@ pub trait Metric: std::fmt::Debug + Send + Sync {
     fn name(&self) -> &'static str;
     fn config(&self) -> &MetricConfig;
     fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+
+    /// Metrics with a higher priority are flushed first by `MetricManager::flush_all`, so that
+    /// the most critical data drains first when flushing is cut short. Metrics with the same
+    /// priority are flushed in name order.
+    fn flush_priority(&self) -> u8 {
+        0
+    }
 }
 
-// Manages the buffered metrics.
+/// A callback invoked after every flush cycle, see `MetricManager::on_flush`.
+pub type FlushCallback = Box<dyn Fn() + Send + Sync>;
+
+/// The deltas of a `FlushedBatch`, keyed by metric fields.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlushedDeltas {
+    Int(BTreeMap<FieldMap, i64>),
+    Distribution(BTreeMap<FieldMap, Distribution>),
+}
+
+/// The deltas pushed by a buffered metric for one entity during a flush, see
+/// `MetricManager::subscribe`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlushedBatch {
+    pub metric_name: &'static str,
+    pub entity_labels: FieldMap,
+    pub deltas: FlushedDeltas,
+}
+
+/// Registration state of a buffered metric instance: its unique ID and the task that registers it
+/// with `METRIC_MANAGER`.
 #[derive(Debug)]
+pub(super) struct Registration {
+    id: u64,
+    task_handle: SyncMutex<Option<JoinHandle<()>>>,
+    registered: OnceCell<()>,
+}
+
+impl Registration {
+    pub(super) fn new() -> Self {
+        static IOTA: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: IOTA.fetch_add(1, Ordering::Relaxed),
+            task_handle: SyncMutex::new(None),
+            registered: OnceCell::new(),
+        }
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Spawns the task registering `metric`. Invoked once by the constructor of the metric.
+    pub(super) fn register(&self, metric: Arc<dyn Metric>) {
+        let mut task_handle = self.task_handle.lock().unwrap();
+        *task_handle = Some(tokio::spawn(async move {
+            METRIC_MANAGER.register_metric(metric).await;
+        }));
+    }
+
+    /// Waits for the registration task spawned by `register` to complete. The handle is taken out
+    /// of its mutex before being awaited, and concurrent callers wait on `registered` for the first
+    /// one to finish.
+    pub(super) async fn wait(&self) {
+        self.registered
+            .get_or_init(|| async {
+                let handle = self.task_handle.lock().unwrap().take();
+                if let Some(handle) = handle {
+                    handle.await.unwrap();
+                }
+            })
+            .await;
+    }
+
+    /// Spawns a task unregistering `metric`. Invoked when the metric is dropped.
+    pub(super) fn unregister(metric: Arc<dyn Metric>) {
+        tokio::spawn(async move {
+            METRIC_MANAGER.unregister_metric(metric).await;
+        });
+    }
+}
+
+tokio::task_local! {
+    /// The `flushed_batches` sender of the manager flushing the current task, see `publish`.
+    static FLUSHED_BATCHES: broadcast::Sender<FlushedBatch>;
+}
+
+/// Sends a batch to the subscribers of the manager flushing the calling metric, or to those of
+/// `METRIC_MANAGER` if the metric is flushed by other means. Meant to be called by the `flush`
+/// implementations.
+pub(super) fn publish(make_batch: impl FnOnce() -> FlushedBatch) {
+    let sender = FLUSHED_BATCHES
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| METRIC_MANAGER.flushed_batches.clone());
+    send_batch(&sender, make_batch);
+}
+
+/// Sends a batch to the subscribers of `sender`. `make_batch` is only invoked if there's at least
+/// one, so that metrics don't copy their deltas for nothing.
+fn send_batch(sender: &broadcast::Sender<FlushedBatch>, make_batch: impl FnOnce() -> FlushedBatch) {
+    if sender.receiver_count() > 0 {
+        sender.send(make_batch()).ok();
+    }
+}
+
+/// Groups the buffered values of a metric by entity labels.
+pub(super) fn group_by_entity<V>(
+    data: BTreeMap<(FieldMap, FieldMap), V>,
+) -> BTreeMap<FieldMap, BTreeMap<FieldMap, V>> {
+    let mut data_by_entity = BTreeMap::<FieldMap, BTreeMap<FieldMap, V>>::default();
+    for ((entity_labels, metric_fields), value) in data {
+        data_by_entity
+            .entry(entity_labels)
+            .or_default()
+            .insert(metric_fields, value);
+    }
+    data_by_entity
+}
+
+/// Publishes the integer deltas of a metric to the subscribers and adds them to the exporter, one
+/// batch per entity.
+pub(super) async fn flush_int_deltas(
+    metric_name: &'static str,
+    data_by_entity: BTreeMap<FieldMap, BTreeMap<FieldMap, i64>>,
+) {
+    for (entity_labels, deltas) in data_by_entity {
+        publish(|| FlushedBatch {
+            metric_name,
+            entity_labels: entity_labels.clone(),
+            deltas: FlushedDeltas::Int(deltas.clone()),
+        });
+        EXPORTER
+            .add_int_deltas(&entity_labels, metric_name, deltas)
+            .await
+            .ok();
+    }
+}
+
+// Manages the buffered metrics.
 pub struct MetricManager {
     metrics: Mutex<BTreeMap<String, BTreeMap<u64, Arc<dyn Metric>>>>,
+    flush_callbacks: SyncMutex<Vec<Arc<FlushCallback>>>,
+    flush_errors: AtomicU64,
+    flushed_batches: broadcast::Sender<FlushedBatch>,
+    flush_task: SyncMutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+    last_flush_duration: SyncMutex<Duration>,
+    /// Durations of all flush cycles so far, in milliseconds.
+    flush_durations: SyncMutex<Distribution>,
+}
+
+impl Default for MetricManager {
+    fn default() -> Self {
+        Self {
+            metrics: Mutex::default(),
+            flush_callbacks: SyncMutex::default(),
+            flush_errors: AtomicU64::default(),
+            flushed_batches: broadcast::Sender::new(Self::FLUSHED_BATCH_CAPACITY),
+            flush_task: SyncMutex::default(),
+            last_flush_duration: SyncMutex::default(),
+            flush_durations: SyncMutex::new(Distribution::new(Bucketer::latency_ms().into())),
+        }
+    }
+}
+
+impl std::fmt::Debug for MetricManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricManager")
+            .field("metrics", &self.metrics)
+            .field(
+                "flush_callbacks",
+                &self.flush_callbacks.lock().unwrap().len(),
+            )
+            .field("flush_errors", &self.flush_errors)
+            .field("subscribers", &self.flushed_batches.receiver_count())
+            .field("running", &self.is_running())
+            .finish()
+    }
 }
 
 impl MetricManager {
     pub const FLUSH_PERIOD: Duration = Duration::from_secs(60);
 
-    /// Starts the background task that periodically flushes the buffered metrics.
+    /// Number of batches a subscriber can fall behind before it starts missing them, see
+    /// `subscribe`.
+    pub const FLUSHED_BATCH_CAPACITY: usize = 1024;
+
+    /// Starts the background task that periodically flushes the buffered metrics. The snapshot of
+    /// the exporter is refreshed after every flush cycle (see `Exporter::snapshot_arc`).
+    ///
+    /// No-op if the task is already running, so there's never more than one flush loop.
     pub async fn start(&'static self) {
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Self::FLUSH_PERIOD);
+        self.start_with_period(Self::FLUSH_PERIOD).await;
+    }
+
+    /// Like `start` but flushes every `period` rather than every `FLUSH_PERIOD`.
+    pub async fn start_with_period(&'static self, period: Duration) {
+        let mut flush_task = self.flush_task.lock().unwrap();
+        if flush_task
+            .as_ref()
+            .is_some_and(|(_, handle)| !handle.is_finished())
+        {
+            return;
+        }
+        let (stop_sender, mut stop_receiver) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
             interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
             loop {
-                interval.tick().await;
-                let metrics = self.metrics.lock().await;
-                for (_, metrics) in &*metrics {
-                    for (_, metric) in metrics {
-                        metric.flush().await;
-                    }
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = &mut stop_receiver => break,
                 }
+                self.flush_cycle().await;
             }
         });
+        *flush_task = Some((stop_sender, handle));
+    }
+
+    /// Stops the background flush task started by `start` and then flushes all buffers one last
+    /// time, so that no buffered data is lost. A flush cycle that's already in progress is allowed
+    /// to complete first.
+    ///
+    /// Idempotent: if the task isn't running this only flushes.
+    pub async fn stop(&self) {
+        let flush_task = self.flush_task.lock().unwrap().take();
+        if let Some((stop_sender, handle)) = flush_task {
+            stop_sender.send(()).ok();
+            handle.await.ok();
+        }
+        self.flush_cycle().await;
+    }
+
+    /// Indicates whether the background flush task is running.
+    pub fn is_running(&self) -> bool {
+        self.flush_task
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, handle)| !handle.is_finished())
+    }
+
+    /// Flushes all registered metrics in decreasing `flush_priority` order.
+    ///
+    /// Each flush runs in its own task so that a metric whose flush panics doesn't prevent the
+    /// others from flushing. Such failures are counted in `flush_errors`.
+    pub async fn flush_all(&self) {
+        let metrics = self.metrics.lock().await;
+        let mut metrics: Vec<&Arc<dyn Metric>> = metrics
+            .values()
+            .flat_map(|metrics| metrics.values())
+            .collect();
+        metrics.sort_by_key(|metric| std::cmp::Reverse(metric.flush_priority()));
+        for metric in metrics {
+            let metric = metric.clone();
+            let flushed_batches = self.flushed_batches.clone();
+            let flush = async move { metric.flush().await };
+            if tokio::spawn(FLUSHED_BATCHES.scope(flushed_batches, flush))
+                .await
+                .is_err()
+            {
+                self.flush_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the number of metric flushes that failed (i.e. panicked) so far.
+    pub fn flush_errors(&self) -> u64 {
+        self.flush_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long the last flush cycle took to flush all buffered metrics, or zero if there
+    /// wasn't any cycle yet.
+    pub fn last_flush_duration(&self) -> Duration {
+        *self.last_flush_duration.lock().unwrap()
+    }
+
+    /// Returns the distribution of the durations of all flush cycles so far, in milliseconds, e.g.
+    /// to detect slow flushes. Durations are measured with the monotonic clock of the exporter.
+    pub fn flush_durations(&self) -> Distribution {
+        self.flush_durations.lock().unwrap().clone()
+    }
+
+    async fn flush_cycle(&self) {
+        let clock = EXPORTER.clock();
+        let start = clock.monotonic_now();
+        self.flush_all().await;
+        let duration = clock.monotonic_now().saturating_duration_since(start);
+        *self.last_flush_duration.lock().unwrap() = duration;
+        self.flush_durations
+            .lock()
+            .unwrap()
+            .record(duration.as_secs_f64() * 1000.0);
+        EXPORTER.refresh_snapshot().await;
+        // Invoke the callbacks without holding the lock, so that they may register further ones.
+        let callbacks = self.flush_callbacks.lock().unwrap().clone();
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// Registers a callback to run after every flush cycle, once all buffered metrics have been
+    /// flushed and the exporter snapshot has been refreshed. Callbacks run in registration order on
+    /// the flushing task, so they should be quick.
+    pub fn on_flush(&self, callback: FlushCallback) {
+        self.flush_callbacks
+            .lock()
+            .unwrap()
+            .push(Arc::new(callback));
+    }
+
+    /// Returns a stream of the deltas flushed by the buffered metrics of this manager, e.g. to
+    /// forward them to a custom sink.
+    ///
+    /// Every flush sends one batch per metric and entity *before* applying its deltas to the
+    /// exporter, so a subscriber may observe a batch shortly before the exporter reflects it. A
+    /// subscriber that falls more than `FLUSHED_BATCH_CAPACITY` batches behind misses the oldest
+    /// ones and gets `RecvError::Lagged`.
+    pub fn subscribe(&self) -> broadcast::Receiver<FlushedBatch> {
+        self.flushed_batches.subscribe()
+    }
+
+    /// Flushes `metric` outside of a flush cycle, publishing its deltas to the subscribers of this
+    /// manager.
+    async fn flush_metric(&self, metric: &Arc<dyn Metric>) {
+        FLUSHED_BATCHES
+            .scope(self.flushed_batches.clone(), metric.flush())
+            .await;
     }
 
     /// Registers a buffered metric instance. Invoked automatically by `Metric` implementations when
@@ -75,6 +379,24 @@ impl MetricManager {
         metrics.remove(&metric_id);
     }
 
+    /// Returns the number of live instances registered under `metric_name`.
+    ///
+    /// Buffered metrics may be instantiated multiple times (e.g. thread-local), so a number growing
+    /// without bounds usually indicates leaked instances.
+    pub async fn instance_count(&self, metric_name: &str) -> usize {
+        let metrics = self.metrics.lock().await;
+        match metrics.get(metric_name) {
+            Some(metrics) => metrics.len(),
+            None => 0,
+        }
+    }
+
+    /// Returns the total number of live instances registered across all metric names.
+    pub async fn total_instances(&self) -> usize {
+        let metrics = self.metrics.lock().await;
+        metrics.values().map(|metrics| metrics.len()).sum()
+    }
+
     /// Retrieves an integer value in a buffered metric, atomically flushing all buffers beforehand.
     /// The returned value will be accurate even if it was updated by other threads.
     pub async fn get_int(
@@ -86,7 +408,7 @@ impl MetricManager {
         let metrics = self.metrics.lock().await;
         if let Some(metrics) = metrics.get(metric_name) {
             for (_, metric) in metrics {
-                metric.flush().await;
+                self.flush_metric(metric).await;
             }
             EXPORTER
                 .get_int(entity_labels, metric_name, metric_fields)
@@ -107,7 +429,7 @@ impl MetricManager {
         let metrics = self.metrics.lock().await;
         if let Some(metrics) = metrics.get(metric_name) {
             for (_, metric) in metrics {
-                metric.flush().await;
+                self.flush_metric(metric).await;
             }
             EXPORTER
                 .get_distribution(entity_labels, metric_name, metric_fields)
@@ -116,13 +438,259 @@ impl MetricManager {
             None
         }
     }
+
+    /// Records `sample` `times` times in a buffered distribution and returns the resulting value.
+    /// The buffers of the metric are flushed and the sample written straight to the exporter
+    /// without releasing the metrics lock, so no flush can interleave; samples recorded
+    /// concurrently stay in the buffers until the next flush. The sample is published to the
+    /// subscribers like a flushed delta.
+    pub async fn add_to_distribution_and_get(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &'static str,
+        sample: f64,
+        times: usize,
+        metric_fields: &FieldMap,
+    ) -> Option<Distribution> {
+        let metrics = self.metrics.lock().await;
+        if let Some(metrics) = metrics.get(metric_name) {
+            for metric in metrics.values() {
+                self.flush_metric(metric).await;
+            }
+        }
+        let result = EXPORTER
+            .add_many_to_distribution_and_get(
+                entity_labels,
+                metric_name,
+                sample,
+                times,
+                metric_fields,
+            )
+            .await
+            .ok()?;
+        send_batch(&self.flushed_batches, || {
+            let mut delta = Distribution::new(result.bucketer());
+            delta.record_many(sample, times);
+            FlushedBatch {
+                metric_name,
+                entity_labels: entity_labels.clone(),
+                deltas: FlushedDeltas::Distribution(BTreeMap::from([(
+                    metric_fields.clone(),
+                    delta,
+                )])),
+            }
+        });
+        Some(result)
+    }
 }
 
-static METRIC_MANAGER_INSTANCE: LazyLock<Pin<Box<MetricManager>>> = LazyLock::new(|| {
-    Box::pin(MetricManager {
-        metrics: Mutex::default(),
-    })
-});
+static METRIC_MANAGER_INSTANCE: LazyLock<Pin<Box<MetricManager>>> =
+    LazyLock::new(|| Box::pin(MetricManager::default()));
 
 pub static METRIC_MANAGER: LazyLock<Pin<&MetricManager>> =
     LazyLock::new(|| METRIC_MANAGER_INSTANCE.as_ref());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_flush() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let count1 = Arc::new(AtomicUsize::default());
+        let count2 = Arc::new(AtomicUsize::default());
+        {
+            let count1 = count1.clone();
+            manager.on_flush(Box::new(move || {
+                count1.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        {
+            let count2 = count2.clone();
+            manager.on_flush(Box::new(move || {
+                count2.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        manager.start_with_period(Duration::from_millis(10)).await;
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(count1.load(Ordering::Relaxed), 3);
+        assert_eq!(count2.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_flush_registers_callback() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let count = Arc::new(AtomicUsize::default());
+        {
+            let count = count.clone();
+            manager.on_flush(Box::new(move || {
+                let count = count.clone();
+                manager.on_flush(Box::new(move || {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }));
+            }));
+        }
+        manager.start_with_period(Duration::from_millis(10)).await;
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_durations() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        assert!(manager.flush_durations().is_empty());
+        manager.start_with_period(Duration::from_millis(10)).await;
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(manager.flush_durations().count(), 3);
+        manager.stop().await;
+        let durations = manager.flush_durations();
+        assert_eq!(durations.count(), 4);
+        assert_eq!(durations.bucketer(), Bucketer::latency_ms().into());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_start_and_stop() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let count = Arc::new(AtomicUsize::default());
+        {
+            let count = count.clone();
+            manager.on_flush(Box::new(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+            }));
+        }
+        assert!(!manager.is_running());
+        manager.start_with_period(Duration::from_millis(10)).await;
+        manager.start_with_period(Duration::from_millis(10)).await;
+        assert!(manager.is_running());
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        manager.stop().await;
+        assert!(!manager.is_running());
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+        manager.stop().await;
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+    }
+
+    #[derive(Debug)]
+    struct TestMetric {
+        id: u64,
+        name: &'static str,
+        priority: u8,
+        config: MetricConfig,
+        log: Arc<SyncMutex<Vec<(&'static str, tokio::time::Instant)>>>,
+    }
+
+    impl Metric for TestMetric {
+        fn id(&self) -> u64 {
+            self.id
+        }
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn config(&self) -> &MetricConfig {
+            &self.config
+        }
+
+        fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            Box::pin(async move {
+                tokio::time::advance(Duration::from_millis(1)).await;
+                let now = tokio::time::Instant::now();
+                self.log.lock().unwrap().push((self.name, now));
+            })
+        }
+
+        fn flush_priority(&self) -> u8 {
+            self.priority
+        }
+    }
+
+    #[derive(Debug)]
+    struct PanickingMetric {
+        config: MetricConfig,
+    }
+
+    impl Metric for PanickingMetric {
+        fn id(&self) -> u64 {
+            100
+        }
+
+        fn name(&self) -> &'static str {
+            "/foo/bar/panicking"
+        }
+
+        fn config(&self) -> &MetricConfig {
+            &self.config
+        }
+
+        fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            Box::pin(async move { panic!("flush failed") })
+        }
+
+        fn flush_priority(&self) -> u8 {
+            2
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panicking_flush() {
+        let manager: &'static MetricManager = Box::leak(Box::default());
+        let log = Arc::new(SyncMutex::new(vec![]));
+        manager
+            .register_metric(Arc::new(PanickingMetric {
+                config: MetricConfig::default(),
+            }))
+            .await;
+        manager
+            .register_metric(Arc::new(TestMetric {
+                id: 101,
+                name: "/foo/bar/healthy",
+                priority: 0,
+                config: MetricConfig::default(),
+                log: log.clone(),
+            }))
+            .await;
+        manager.start_with_period(Duration::from_millis(10)).await;
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        assert_eq!(log.lock().unwrap().len(), 2);
+        assert_eq!(manager.flush_errors(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_priority() {
+        let manager = MetricManager::default();
+        let log = Arc::new(SyncMutex::new(vec![]));
+        for (id, name, priority) in [
+            (0, "/foo/bar/a_events", 0),
+            (1, "/foo/bar/b_counter", 1),
+            (2, "/foo/bar/c_events", 0),
+        ] {
+            manager
+                .register_metric(Arc::new(TestMetric {
+                    id,
+                    name,
+                    priority,
+                    config: MetricConfig::default(),
+                    log: log.clone(),
+                }))
+                .await;
+        }
+        manager.flush_all().await;
+        let log = log.lock().unwrap();
+        let names: Vec<&str> = log.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "/foo/bar/b_counter",
+                "/foo/bar/a_events",
+                "/foo/bar/c_events"
+            ]
+        );
+        assert!(log[0].1 < log[1].1);
+        assert!(log[1].1 < log[2].1);
+    }
+}
@@ -1,8 +1,22 @@
 mod manager;
+mod self_metrics;
 
+pub mod absolute_counter;
 pub mod counter;
 pub mod event_metric;
+pub mod reservoir;
 
+/// Starts flushing the buffered metrics periodically. Idempotent.
 pub async fn init() {
     manager::METRIC_MANAGER.start().await;
 }
+
+/// Starts exporting metrics about tsz itself, see `tsz::install_self_metrics`. Idempotent.
+pub async fn install_self_metrics() {
+    self_metrics::install().await;
+}
+
+/// Stops the periodic flushing and flushes all buffers one last time. Idempotent.
+pub async fn shutdown() {
+    manager::METRIC_MANAGER.stop().await;
+}
@@ -0,0 +1,272 @@
+use crate::tsz::{
+    FieldMap, buffered::manager::Metric, buffered::manager::Registration, config::MetricConfig,
+};
+use crate::utils::lazy::Lazy;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Minimal splitmix64 generator, good enough to pick reservoir slots.
+#[derive(Debug)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// A fixed-size uniform sample of a stream of values, maintained with Algorithm R.
+#[derive(Debug, Default, Clone)]
+struct Reservoir {
+    samples: Vec<f64>,
+    seen: u64,
+}
+
+impl Reservoir {
+    fn record(&mut self, sample: f64, capacity: usize, rng: &mut Rng) {
+        self.seen += 1;
+        if self.samples.len() < capacity {
+            self.samples.push(sample);
+        } else {
+            let j = rng.next() % self.seen;
+            if j < capacity as u64 {
+                self.samples[j as usize] = sample;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ReservoirMetricImpl {
+    name: &'static str,
+    config: MetricConfig,
+    capacity: usize,
+    registration: Registration,
+    rng: Mutex<Rng>,
+    data: Mutex<BTreeMap<(FieldMap, FieldMap), Reservoir>>,
+    snapshot: Mutex<BTreeMap<(FieldMap, FieldMap), Vec<f64>>>,
+}
+
+impl ReservoirMetricImpl {
+    fn new(name: &'static str, capacity: usize, config: MetricConfig) -> Arc<Self> {
+        let registration = Registration::new();
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        let rng = Mutex::new(Rng::new(seed ^ registration.id()));
+        let metric = Arc::new(Self {
+            name,
+            config,
+            capacity,
+            registration,
+            rng,
+            data: Mutex::default(),
+            snapshot: Mutex::default(),
+        });
+        metric.registration.register(metric.clone());
+        metric
+    }
+
+    fn record(&self, sample: f64, entity_labels: FieldMap, metric_fields: FieldMap) {
+        let key = (entity_labels, metric_fields);
+        let mut rng = self.rng.lock().unwrap();
+        let mut data = self.data.lock().unwrap();
+        if let Some(reservoir) = data.get_mut(&key) {
+            reservoir.record(sample, self.capacity, &mut rng);
+        } else {
+            let mut reservoir = Reservoir::default();
+            reservoir.record(sample, self.capacity, &mut rng);
+            data.insert(key, reservoir);
+        }
+    }
+
+    fn samples(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Vec<f64> {
+        let key = (entity_labels.clone(), metric_fields.clone());
+        let data = self.data.lock().unwrap();
+        match data.get(&key) {
+            Some(reservoir) => reservoir.samples.clone(),
+            None => vec![],
+        }
+    }
+
+    fn snapshot(&self) -> BTreeMap<(FieldMap, FieldMap), Vec<f64>> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    async fn flush_impl(&self) {
+        let snapshot = {
+            let data = self.data.lock().unwrap();
+            data.iter()
+                .map(|(key, reservoir)| (key.clone(), reservoir.samples.clone()))
+                .collect()
+        };
+        *self.snapshot.lock().unwrap() = snapshot;
+    }
+}
+
+impl Metric for ReservoirMetricImpl {
+    fn id(&self) -> u64 {
+        self.registration.id()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn config(&self) -> &MetricConfig {
+        &self.config
+    }
+
+    fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.flush_impl())
+    }
+}
+
+/// Keeps a bounded, uniformly random sample of the raw values recorded for each (entity, metric
+/// fields) pair, e.g. to recompute percentiles with a different bucketer later.
+///
+/// Each reservoir retains at most `capacity` samples regardless of how many are recorded. The
+/// reservoirs are not written to the exporter; instead every flush of the `MetricManager` publishes
+/// a copy of them that can be retrieved with `snapshot`.
+#[derive(Debug)]
+pub struct ReservoirMetric {
+    name: &'static str,
+    capacity: usize,
+    config: MetricConfig,
+    inner: Lazy<Arc<ReservoirMetricImpl>>,
+}
+
+impl ReservoirMetric {
+    pub fn new(name: &'static str, capacity: usize, config: MetricConfig) -> Self {
+        Self {
+            name,
+            capacity,
+            config,
+            inner: Lazy::new(move || ReservoirMetricImpl::new(name, capacity, config)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn config(&self) -> &MetricConfig {
+        &self.config
+    }
+
+    pub fn record(&self, sample: f64, entity_labels: FieldMap, metric_fields: FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner.record(sample, entity_labels, metric_fields);
+    }
+
+    /// Returns the samples currently retained for the specified entity and metric fields.
+    pub fn samples(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Vec<f64> {
+        self.inner.samples(entity_labels, metric_fields)
+    }
+
+    /// Returns the reservoirs as of the last flush, keyed by entity labels and metric fields.
+    pub fn snapshot(&self) -> BTreeMap<(FieldMap, FieldMap), Vec<f64>> {
+        self.inner.snapshot()
+    }
+}
+
+impl Drop for ReservoirMetric {
+    fn drop(&mut self) {
+        Registration::unregister(self.inner.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsz::{testing::test_entity_labels, testing::test_metric_fields};
+
+    #[tokio::test]
+    async fn test_new() {
+        let metric = ReservoirMetric::new("/foo/bar/reservoir", 100, MetricConfig::default());
+        assert_eq!(metric.name(), "/foo/bar/reservoir");
+        assert_eq!(metric.capacity(), 100);
+        assert_eq!(*metric.config(), MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(metric.samples(&entity_labels, &metric_fields).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_below_capacity() {
+        let metric = ReservoirMetric::new("/foo/bar/reservoir", 100, MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        metric.record(34.0, entity_labels.clone(), metric_fields.clone());
+        assert_eq!(
+            metric.samples(&entity_labels, &metric_fields),
+            vec![12.0, 34.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let metric = ReservoirMetric::new(
+            "/foo/bar/reservoir",
+            100,
+            MetricConfig::default().set_enabled(false),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(12.0, entity_labels.clone(), metric_fields.clone());
+        assert!(metric.samples(&entity_labels, &metric_fields).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bounded() {
+        let metric = ReservoirMetric::new("/foo/bar/reservoir", 100, MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for i in 0..10000 {
+            metric.record(i as f64, entity_labels.clone(), metric_fields.clone());
+        }
+        let samples = metric.samples(&entity_labels, &metric_fields);
+        assert_eq!(samples.len(), 100);
+        for sample in samples {
+            assert!((0.0..10000.0).contains(&sample));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let metric = ReservoirMetric::new("/foo/bar/reservoir", 100, MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for i in 0..1000 {
+            metric.record(i as f64, entity_labels.clone(), metric_fields.clone());
+        }
+        assert!(metric.snapshot().is_empty());
+        metric.inner.flush().await;
+        let snapshot = metric.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot[&(entity_labels.clone(), metric_fields.clone())],
+            metric.samples(&entity_labels, &metric_fields)
+        );
+    }
+}
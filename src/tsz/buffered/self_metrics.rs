@@ -0,0 +1,119 @@
+use crate::tsz::buffered::manager::METRIC_MANAGER;
+use crate::tsz::{FieldMap, bucketer::Bucketer, config::MetricConfig, exporter::EXPORTER};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const ENTITY_COUNT: &str = "/tsz/entity_count";
+const CELL_COUNT: &str = "/tsz/cell_count";
+const DROPPED_SAMPLES: &str = "/tsz/dropped_samples";
+const FLUSH_LATENCY_MS: &str = "/tsz/flush_latency_ms";
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Defines the self-metrics and refreshes them after every flush cycle. Idempotent.
+pub async fn install() {
+    if INSTALLED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    EXPORTER.define_metric_redundant(ENTITY_COUNT, MetricConfig::gauge());
+    EXPORTER.define_metric_redundant(CELL_COUNT, MetricConfig::gauge());
+    EXPORTER.define_metric_redundant(DROPPED_SAMPLES, MetricConfig::counter());
+    EXPORTER.define_metric_redundant(
+        FLUSH_LATENCY_MS,
+        MetricConfig::distribution(Bucketer::latency_ms()),
+    );
+    // Flush callbacks are synchronous, while updating the exporter isn't.
+    METRIC_MANAGER.on_flush(Box::new(|| {
+        let flush_duration = METRIC_MANAGER.last_flush_duration();
+        tokio::spawn(update(Some(flush_duration)));
+    }));
+    update(None).await;
+}
+
+async fn update(flush_duration: Option<Duration>) {
+    let entity_labels = FieldMap::from([]);
+    let metric_fields = FieldMap::from([]);
+    let entity_count = EXPORTER.entity_count().await;
+    EXPORTER
+        .set_int(
+            &entity_labels,
+            ENTITY_COUNT,
+            entity_count as i64,
+            &metric_fields,
+        )
+        .await
+        .ok();
+    let cell_count = EXPORTER.cell_count().await;
+    EXPORTER
+        .set_int(
+            &entity_labels,
+            CELL_COUNT,
+            cell_count as i64,
+            &metric_fields,
+        )
+        .await
+        .ok();
+    EXPORTER
+        .set_int(
+            &entity_labels,
+            DROPPED_SAMPLES,
+            EXPORTER.total_dropped_samples() as i64,
+            &metric_fields,
+        )
+        .await
+        .ok();
+    if let Some(flush_duration) = flush_duration {
+        EXPORTER
+            .add_to_distribution(
+                &entity_labels,
+                FLUSH_LATENCY_MS,
+                flush_duration.as_secs_f64() * 1000.0,
+                &metric_fields,
+            )
+            .await
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsz::testing::{test_entity_labels, test_metric_fields};
+
+    #[tokio::test]
+    async fn test_self_metrics() {
+        install().await;
+        install().await;
+        let entity_labels = test_entity_labels();
+        EXPORTER
+            .set_int(&entity_labels, "/foo/bar/self", 1, &test_metric_fields())
+            .await
+            .unwrap();
+        EXPORTER
+            .set_int(&entity_labels, "/foo/bar/self", 2, &test_metric_fields())
+            .await
+            .unwrap();
+        update(Some(Duration::from_millis(5))).await;
+        let root = FieldMap::from([]);
+        let cell_count = EXPORTER.get_int(&root, CELL_COUNT, &root).await.unwrap();
+        // Other tests write to the global exporter concurrently, so the count can only be bounded
+        // from below: our two cells plus the self-metrics already written.
+        assert!(cell_count >= 3);
+        assert!(EXPORTER.get_int(&root, ENTITY_COUNT, &root).await.unwrap() >= 2);
+        assert!(
+            EXPORTER
+                .get_int(&root, DROPPED_SAMPLES, &root)
+                .await
+                .is_some()
+        );
+        let latency = EXPORTER
+            .get_distribution(&root, FLUSH_LATENCY_MS, &root)
+            .await
+            .unwrap();
+        assert!(latency.count() >= 1);
+        assert_eq!(
+            *EXPORTER.get_metric_config(CELL_COUNT).unwrap(),
+            MetricConfig::gauge()
+        );
+    }
+}
@@ -1,15 +1,53 @@
-use crate::tsz::{bucketer::Bucketer, bucketer::BucketerRef};
+use crate::tsz::{FieldType, bucketer::Bucketer, bucketer::BucketerRef};
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct MetricConfig {
     pub cumulative: bool,
     pub skip_stable_cells: bool,
+    /// Makes `Exporter::export_snapshot` report the int and float cells of the metric as deltas from
+    /// the previous export rather than as absolute values.
     pub delta_mode: bool,
     pub user_timestamps: bool,
+    /// Makes every write to a cell of a non-cumulative metric move its start timestamp to the time
+    /// of the write, for backends that expect the interval of a gauge point to start at its last
+    /// update. Off by default, meaning cells keep the start timestamp of their first write.
+    /// Cumulative metrics always keep it, since it marks the start of the accumulation.
+    pub reset_start_on_update: bool,
     pub bucketer: Option<BucketerRef>,
+    /// When set, counters drop negative deltas instead of applying them. Off by default for
+    /// compatibility, meaning counters accept negative deltas and may decrease.
+    pub reject_negative_deltas: bool,
+    /// Optional schema of the metric fields. When set, the exporter rejects writes whose metric
+    /// fields don't have exactly the declared names and types.
+    pub fields: Option<&'static [(&'static str, FieldType)]>,
+    /// For event metrics, the N of an application that only records 1 in N events. Each recorded
+    /// sample is weighted N times so that distributions estimate the whole population. 0 and 1 both
+    /// mean that every event is recorded.
+    pub sampling_rate: u32,
+    /// Turns the metric into a no-op: writes return before taking any lock and the metric is never
+    /// defined in the exporter. Stored negated so that metrics are enabled by default.
+    pub disabled: bool,
 }
 
 impl MetricConfig {
+    /// Returns the config of a counter, i.e. a cumulative metric without bucketer. This is what
+    /// `Counter` uses when given a default config.
+    pub fn counter() -> Self {
+        Self::default().set_cumulative(true)
+    }
+
+    /// Returns the config of a gauge, i.e. a non-cumulative metric without bucketer. Same as
+    /// `default()`, provided for symmetry.
+    pub fn gauge() -> Self {
+        Self::default()
+    }
+
+    /// Returns the config of a distribution, i.e. a cumulative metric with the specified bucketer.
+    /// This is what `EventMetric` uses when given a default config and the default bucketer.
+    pub fn distribution(bucketer: &'static Bucketer) -> Self {
+        Self::default().set_cumulative(true).set_bucketer(bucketer)
+    }
+
     pub fn set_cumulative(mut self, value: bool) -> Self {
         self.cumulative = value;
         self
@@ -30,6 +68,11 @@ impl MetricConfig {
         self
     }
 
+    pub fn set_reset_start_on_update(mut self, value: bool) -> Self {
+        self.reset_start_on_update = value;
+        self
+    }
+
     pub fn set_bucketer(mut self, bucketer: &'static Bucketer) -> Self {
         self.bucketer = Some(bucketer.into());
         self
@@ -39,6 +82,41 @@ impl MetricConfig {
         self.bucketer = None;
         self
     }
+
+    pub fn set_reject_negative_deltas(mut self, value: bool) -> Self {
+        self.reject_negative_deltas = value;
+        self
+    }
+
+    pub fn set_fields(mut self, fields: &'static [(&'static str, FieldType)]) -> Self {
+        self.fields = Some(fields);
+        self
+    }
+
+    pub fn clear_fields(mut self) -> Self {
+        self.fields = None;
+        self
+    }
+
+    pub fn set_sampling_rate(mut self, n: u32) -> Self {
+        self.sampling_rate = n;
+        self
+    }
+
+    pub fn set_enabled(mut self, value: bool) -> Self {
+        self.disabled = !value;
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    /// Returns the number of times each sampled event is recorded, i.e. `sampling_rate` but at
+    /// least 1.
+    pub fn sampling_weight(&self) -> usize {
+        std::cmp::max(self.sampling_rate, 1) as usize
+    }
 }
 
 #[cfg(test)]
@@ -52,7 +130,14 @@ mod tests {
         assert_eq!(config.skip_stable_cells, false);
         assert_eq!(config.delta_mode, false);
         assert_eq!(config.user_timestamps, false);
+        assert_eq!(config.reset_start_on_update, false);
         assert!(config.bucketer.is_none());
+        assert_eq!(config.reject_negative_deltas, false);
+        assert!(config.fields.is_none());
+        assert_eq!(config.sampling_rate, 0);
+        assert_eq!(config.sampling_weight(), 1);
+        assert_eq!(config.disabled, false);
+        assert!(config.enabled());
     }
 
     #[test]
@@ -95,6 +180,17 @@ mod tests {
         assert!(config.bucketer.is_none());
     }
 
+    #[test]
+    fn test_reset_start_on_update_field() {
+        let config = MetricConfig::default().set_reset_start_on_update(true);
+        assert_eq!(config.cumulative, false);
+        assert_eq!(config.skip_stable_cells, false);
+        assert_eq!(config.delta_mode, false);
+        assert_eq!(config.user_timestamps, false);
+        assert_eq!(config.reset_start_on_update, true);
+        assert!(config.bucketer.is_none());
+    }
+
     #[test]
     fn test_set_bucketer() {
         let config = MetricConfig::default().set_bucketer(Bucketer::default());
@@ -116,4 +212,75 @@ mod tests {
         assert_eq!(config.user_timestamps, false);
         assert!(config.bucketer.is_none());
     }
+
+    #[test]
+    fn test_reject_negative_deltas_field() {
+        let config = MetricConfig::default().set_reject_negative_deltas(true);
+        assert_eq!(config.cumulative, false);
+        assert_eq!(config.skip_stable_cells, false);
+        assert_eq!(config.delta_mode, false);
+        assert_eq!(config.user_timestamps, false);
+        assert!(config.bucketer.is_none());
+        assert_eq!(config.reject_negative_deltas, true);
+    }
+
+    #[test]
+    fn test_counter_config() {
+        let config = MetricConfig::counter();
+        assert_eq!(config.cumulative, true);
+        assert_eq!(config.skip_stable_cells, false);
+        assert_eq!(config.delta_mode, false);
+        assert_eq!(config.user_timestamps, false);
+        assert!(config.bucketer.is_none());
+    }
+
+    #[test]
+    fn test_gauge_config() {
+        let config = MetricConfig::gauge();
+        assert_eq!(config.cumulative, false);
+        assert_eq!(config.skip_stable_cells, false);
+        assert_eq!(config.delta_mode, false);
+        assert_eq!(config.user_timestamps, false);
+        assert!(config.bucketer.is_none());
+    }
+
+    #[test]
+    fn test_distribution_config() {
+        let bucketer = Bucketer::fixed_width(1.0, 10);
+        let config = MetricConfig::distribution(bucketer);
+        assert_eq!(config.cumulative, true);
+        assert_eq!(config.skip_stable_cells, false);
+        assert_eq!(config.delta_mode, false);
+        assert_eq!(config.user_timestamps, false);
+        assert_eq!(config.bucketer, Some(bucketer.into()));
+    }
+
+    #[test]
+    fn test_set_fields() {
+        let config = MetricConfig::default()
+            .set_fields(&[("code", FieldType::Int), ("ok", FieldType::Bool)]);
+        assert_eq!(
+            config.fields,
+            Some(&[("code", FieldType::Int), ("ok", FieldType::Bool)][..])
+        );
+        assert!(config.clear_fields().fields.is_none());
+    }
+
+    #[test]
+    fn test_sampling_rate_field() {
+        let config = MetricConfig::default().set_sampling_rate(10);
+        assert_eq!(config.cumulative, false);
+        assert!(config.bucketer.is_none());
+        assert_eq!(config.sampling_rate, 10);
+        assert_eq!(config.sampling_weight(), 10);
+        assert_eq!(config.set_sampling_rate(1).sampling_weight(), 1);
+    }
+
+    #[test]
+    fn test_enabled_field() {
+        let config = MetricConfig::default().set_enabled(false);
+        assert!(!config.enabled());
+        assert!(config.disabled);
+        assert!(config.set_enabled(true).enabled());
+    }
 }
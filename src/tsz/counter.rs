@@ -1,58 +1,167 @@
-use crate::tsz::{FieldMap, config::MetricConfig, exporter::EXPORTER};
+#[cfg(not(feature = "no-global-exporter"))]
+use crate::tsz::exporter::EXPORTER;
+use crate::tsz::{
+    FieldMap,
+    config::MetricConfig,
+    exporter::{Aggregation, Exporter, Value},
+};
 use crate::utils::lazy::Lazy;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
 
 #[derive(Debug)]
 struct CounterImpl {
+    exporter: Pin<&'static Exporter<'static>>,
     name: &'static str,
 }
 
 impl CounterImpl {
-    fn new(name: &'static str, config: MetricConfig) -> Self {
-        EXPORTER.define_metric_redundant(name, config);
-        Self { name }
+    fn new(
+        exporter: Pin<&'static Exporter<'static>>,
+        name: &'static str,
+        config: MetricConfig,
+    ) -> Self {
+        exporter.define_metric_redundant(name, config);
+        Self { exporter, name }
     }
 
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
-        EXPORTER
+        self.exporter
             .get_int(entity_labels, self.name, metric_fields)
             .await
     }
 
+    async fn exists(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.exporter
+            .contains_cell(entity_labels, self.name, metric_fields)
+            .await
+    }
+
     async fn increment_by(&self, entity_labels: &FieldMap, delta: i64, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .add_to_int(entity_labels, self.name, delta, metric_fields)
-            .await;
+            .await
+            .ok();
     }
 
     async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_value(entity_labels, self.name, metric_fields)
             .await
             .is_some()
     }
 
     async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_metric_from_entity(entity_labels, self.name)
             .await
     }
+
+    async fn delete_all(&self) -> usize {
+        self.exporter.delete_metric(self.name).await
+    }
+
+    async fn sum_across_entities(&self, group_by: &[&str]) -> BTreeMap<FieldMap, i64> {
+        let snapshot = self
+            .exporter
+            .snapshot_filtered(|metric_name| metric_name == self.name)
+            .await;
+        // Counter cells are all ints, so summing them can't fail.
+        snapshot
+            .aggregate(self.name, group_by, Aggregation::Sum)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Value::Int(sum) => Some((key, sum)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// A reading of a counter cell, see `Counter::snapshot`. Callers hold on to snapshots between reads
+/// in order to compute rates with `Counter::rate`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CounterSnapshot {
+    value: i64,
+    timestamp: SystemTime,
+    instant: Option<Instant>,
+}
+
+impl CounterSnapshot {
+    pub fn new(value: i64, timestamp: SystemTime) -> Self {
+        Self {
+            value,
+            timestamp,
+            instant: None,
+        }
+    }
+
+    /// Attaches a monotonic time point to the snapshot. When both snapshots passed to
+    /// `Counter::rate` have one, the elapsed time is measured with them rather than with the
+    /// timestamps, which can jump backward.
+    pub fn with_instant(mut self, instant: Instant) -> Self {
+        self.instant = Some(instant);
+        self
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.instant
+    }
+}
+
+/// The rate of change of a counter between two snapshots, see `Counter::rate`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CounterRate {
+    /// Increase per second.
+    pub per_second: f64,
+    /// True iff the counter value decreased between the two snapshots, in which case the counter is
+    /// assumed to have been reset and `per_second` is computed from zero.
+    pub reset_detected: bool,
 }
 
 #[derive(Debug)]
 pub struct Counter {
     name: &'static str,
     config: MetricConfig,
+    rejected_deltas: AtomicU64,
+    /// Last snapshot and smoothed rate of the cells read with `smoothed_rate`.
+    smoothed_rates: Mutex<BTreeMap<(FieldMap, FieldMap), (CounterSnapshot, f64)>>,
     inner: Lazy<CounterImpl>,
 }
 
 impl Counter {
-    pub fn new(name: &'static str, mut config: MetricConfig) -> Self {
+    #[cfg(not(feature = "no-global-exporter"))]
+    pub fn new(name: &'static str, config: MetricConfig) -> Self {
+        Self::new_with(EXPORTER.get_ref(), name, config)
+    }
+
+    /// Creates a counter exported by `exporter` rather than the global exporter.
+    pub fn new_with(
+        exporter: &'static Exporter<'static>,
+        name: &'static str,
+        mut config: MetricConfig,
+    ) -> Self {
+        let exporter = Pin::new(exporter);
         config.cumulative = true;
         config.bucketer = None;
         Self {
             name,
             config,
-            inner: Lazy::new(move || CounterImpl::new(name, config)),
+            rejected_deltas: AtomicU64::default(),
+            smoothed_rates: Mutex::default(),
+            inner: Lazy::new(move || CounterImpl::new(exporter, name, config)),
         }
     }
 
@@ -64,10 +173,21 @@ impl Counter {
         &self.config
     }
 
+    /// Returns the number of negative deltas dropped because of the `reject_negative_deltas` config
+    /// option.
+    pub fn rejected_deltas(&self) -> u64 {
+        self.rejected_deltas.load(Ordering::Relaxed)
+    }
+
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
         self.inner.get(entity_labels, metric_fields).await
     }
 
+    /// Checks whether the cell exists, i.e. whether it was ever incremented (and not deleted since).
+    pub async fn exists(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.inner.exists(entity_labels, metric_fields).await
+    }
+
     pub async fn get_or_zero(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> i64 {
         self.inner
             .get(entity_labels, metric_fields)
@@ -76,39 +196,154 @@ impl Counter {
             .unwrap()
     }
 
+    /// Reads the current value of the specified cell (0 if it doesn't exist) along with the current
+    /// wall-clock and monotonic time of the exporter's clock.
+    pub async fn snapshot(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> CounterSnapshot {
+        let value = self.get_or_zero(entity_labels, metric_fields).await;
+        let clock = self.inner.exporter.clock();
+        CounterSnapshot::new(value, clock.now()).with_instant(clock.monotonic_now())
+    }
+
+    /// Computes the rate of increase between two snapshots of the same cell.
+    ///
+    /// If the value decreased (e.g. because of a process restart or an explicit reset) the counter
+    /// is assumed to have restarted from zero, so the rate is computed from `current.value()` alone
+    /// and `reset_detected` is set. The elapsed time is measured with the monotonic instants if both
+    /// snapshots have one, and with their timestamps otherwise. The rate is 0 if `current` is not
+    /// later than `previous`.
+    pub fn rate(previous: &CounterSnapshot, current: &CounterSnapshot) -> CounterRate {
+        let reset_detected = current.value < previous.value;
+        let delta = if reset_detected {
+            current.value
+        } else {
+            current.value - previous.value
+        };
+        let elapsed = match (previous.instant, current.instant) {
+            (Some(previous), Some(current)) => current.saturating_duration_since(previous),
+            _ => current
+                .timestamp
+                .duration_since(previous.timestamp)
+                .unwrap_or_default(),
+        };
+        let per_second = if elapsed.is_zero() {
+            0.0
+        } else {
+            delta as f64 / elapsed.as_secs_f64()
+        };
+        CounterRate {
+            per_second,
+            reset_detected,
+        }
+    }
+
+    /// Returns an exponentially-weighted moving average of the per-second rate of the specified
+    /// cell, for display purposes.
+    ///
+    /// Every call takes a snapshot of the cell, computes the rate since the previous call (see
+    /// `rate`), and folds it into the average as `alpha * rate + (1 - alpha) * average`. `alpha` is
+    /// clamped to `[0, 1]`: higher values track recent changes more closely, lower values smooth
+    /// more. The first call for a cell only records a snapshot and returns 0. The state is kept in
+    /// a side table of this `Counter` and doesn't affect the exported value. Deleting the cell
+    /// (including via `delete_entity` and `reset_all`) clears its state.
+    pub async fn smoothed_rate(
+        &self,
+        alpha: f64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> f64 {
+        let alpha = alpha.clamp(0.0, 1.0);
+        let current = self.snapshot(entity_labels, metric_fields).await;
+        let key = (entity_labels.clone(), metric_fields.clone());
+        let mut smoothed_rates = self.smoothed_rates.lock().unwrap();
+        let average = match smoothed_rates.get(&key) {
+            Some((previous, average)) => {
+                let rate = Self::rate(previous, &current).per_second;
+                alpha * rate + (1.0 - alpha) * average
+            }
+            None => 0.0,
+        };
+        smoothed_rates.insert(key, (current, average));
+        average
+    }
+
+    /// Adds `delta` to the counter. Negative deltas are accepted unless the metric is configured
+    /// with `reject_negative_deltas`, in which case they're dropped and counted in
+    /// `rejected_deltas`.
     pub async fn increment_by(
         &self,
         delta: i64,
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) {
+        if !self.config.enabled() {
+            return;
+        }
+        if delta < 0 && self.config.reject_negative_deltas {
+            self.rejected_deltas.fetch_add(1, Ordering::Relaxed);
+            self.inner.exporter.record_dropped_samples(self.name, 1);
+            return;
+        }
         self.inner
             .increment_by(entity_labels, delta, metric_fields)
             .await;
     }
 
     pub async fn increment(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner
             .increment_by(entity_labels, 1, metric_fields)
             .await;
     }
 
     pub async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.smoothed_rates
+            .lock()
+            .unwrap()
+            .remove(&(entity_labels.clone(), metric_fields.clone()));
         self.inner.delete(entity_labels, metric_fields).await
     }
 
     pub async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
+        self.smoothed_rates
+            .lock()
+            .unwrap()
+            .retain(|(labels, _), _| labels != entity_labels);
         self.inner.delete_entity(entity_labels).await
     }
+
+    /// Deletes every cell of the counter across all entities. Returns the number of cells removed.
+    pub async fn reset_all(&self) -> usize {
+        self.smoothed_rates.lock().unwrap().clear();
+        self.inner.delete_all().await
+    }
+
+    /// Sums the counter across all entities, grouping the cells by the values of the `group_by`
+    /// keys as `SnapshotView::aggregate` does, e.g. the total number of RPCs by status code
+    /// regardless of which backend served them. Grouping by nothing yields a single grand total
+    /// keyed by an empty map.
+    pub async fn sum_across_entities(&self, group_by: &[&str]) -> BTreeMap<FieldMap, i64> {
+        self.inner.sum_across_entities(group_by).await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tsz::{
-        bucketer::Bucketer, testing::test_entity_labels, testing::test_metric_fields,
+        FieldType, FieldValue, bucketer::Bucketer, testing::test_entity_labels,
+        testing::test_metric_fields,
     };
+    use crate::utils::clock::test::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_new() {
         let config = MetricConfig::default().set_cumulative(true);
@@ -129,14 +364,23 @@ mod tests {
 
     #[tokio::test]
     async fn test_config_overrides() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
         let config = MetricConfig::default().set_bucketer(Bucketer::fixed_width(1.0, 20));
-        let counter = Counter::new("/foo/bar/counter", config);
+        let counter = Counter::new_with(exporter, "/foo/bar/counter", config);
         assert_eq!(
             *counter.config(),
             config.set_cumulative(true).clear_bucketer()
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_counter_config() {
+        let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
+        assert_eq!(*counter.config(), MetricConfig::counter());
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_custom_config() {
         let config = MetricConfig::default()
@@ -152,6 +396,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_by_zero() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -170,6 +415,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_by_one() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -188,6 +434,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_by_two() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -206,6 +453,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_by_delta_twice() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -227,6 +475,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -243,6 +492,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_twice() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -260,6 +510,18 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_exists() {
+        let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(!counter.exists(&entity_labels, &metric_fields).await);
+        counter.increment(&entity_labels, &metric_fields).await;
+        assert!(counter.exists(&entity_labels, &metric_fields).await);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -276,6 +538,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -295,6 +558,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_after_deletion() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -317,6 +581,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing_entity() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -348,6 +613,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_entity() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -381,6 +647,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_another_entity() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -418,6 +685,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_increment_after_entity_deletion() {
         let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
@@ -457,4 +725,376 @@ mod tests {
                 .is_none()
         );
     }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_increment_by_negative_delta() {
+        let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter
+            .increment_by(3, &entity_labels, &metric_fields)
+            .await;
+        counter
+            .increment_by(-2, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(1));
+        assert_eq!(counter.rejected_deltas(), 0);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_reject_negative_delta() {
+        let counter = Counter::new(
+            "/foo/bar/counter",
+            MetricConfig::default().set_reject_negative_deltas(true),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter
+            .increment_by(3, &entity_labels, &metric_fields)
+            .await;
+        counter
+            .increment_by(-2, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(3));
+        assert_eq!(counter.rejected_deltas(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_deltas_are_dropped_samples() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let counter = Counter::new_with(
+            exporter,
+            "/foo/bar/counter",
+            MetricConfig::default().set_reject_negative_deltas(true),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter
+            .increment_by(-2, &entity_labels, &metric_fields)
+            .await;
+        counter
+            .increment_by(-3, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(exporter.dropped_samples("/foo/bar/counter"), 2);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_reset_all() {
+        let counter = Counter::new("/foo/bar/counter/reset_all", MetricConfig::default());
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        counter.increment(&entity_labels1, &metric_fields1).await;
+        counter.increment(&entity_labels1, &metric_fields2).await;
+        counter.increment(&entity_labels2, &metric_fields1).await;
+        assert_eq!(counter.reset_all().await, 3);
+        assert!(
+            counter
+                .get(&entity_labels1, &metric_fields1)
+                .await
+                .is_none()
+        );
+        assert!(
+            counter
+                .get(&entity_labels1, &metric_fields2)
+                .await
+                .is_none()
+        );
+        assert!(
+            counter
+                .get(&entity_labels2, &metric_fields1)
+                .await
+                .is_none()
+        );
+        assert_eq!(counter.reset_all().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sum_across_entities() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let counter = Counter::new_with(exporter, "/foo/bar/counter", MetricConfig::default());
+        let entity_labels1 = FieldMap::from([("backend", FieldValue::Str("foo".into()))]);
+        let entity_labels2 = FieldMap::from([("backend", FieldValue::Str("bar".into()))]);
+        for entity_labels in [&entity_labels1, &entity_labels2] {
+            counter
+                .increment_by(
+                    3,
+                    entity_labels,
+                    &FieldMap::from([
+                        ("code", FieldValue::Int(200)),
+                        ("method", FieldValue::Str("get".into())),
+                    ]),
+                )
+                .await;
+            counter
+                .increment_by(
+                    1,
+                    entity_labels,
+                    &FieldMap::from([
+                        ("code", FieldValue::Int(500)),
+                        ("method", FieldValue::Str("get".into())),
+                    ]),
+                )
+                .await;
+        }
+        assert_eq!(
+            counter.sum_across_entities(&["code"]).await,
+            BTreeMap::from([
+                (FieldMap::from([("code", FieldValue::Int(200))]), 6),
+                (FieldMap::from([("code", FieldValue::Int(500))]), 2),
+            ])
+        );
+        assert_eq!(
+            counter.sum_across_entities(&[]).await,
+            BTreeMap::from([(FieldMap::default(), 8)])
+        );
+        assert_eq!(
+            counter.sum_across_entities(&["backend"]).await,
+            BTreeMap::from([
+                (
+                    FieldMap::from([("backend", FieldValue::Str("bar".into()))]),
+                    4
+                ),
+                (
+                    FieldMap::from([("backend", FieldValue::Str("foo".into()))]),
+                    4
+                ),
+            ])
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_local_exporter() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let counter =
+            Counter::new_with(exporter, "/foo/bar/counter/local", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment(&entity_labels, &metric_fields).await;
+        assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(1));
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar/counter/local", &metric_fields)
+                .await,
+            Some(1)
+        );
+        assert!(
+            EXPORTER
+                .get_metric_config("/foo/bar/counter/local")
+                .is_none()
+        );
+        assert!(
+            EXPORTER
+                .get_int(&entity_labels, "/foo/bar/counter/local", &metric_fields)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_schema_errors_counted() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let counter = Counter::new_with(
+            exporter,
+            "/foo/bar/counter",
+            MetricConfig::default().set_fields(&[("code", FieldType::Int)]),
+        );
+        let entity_labels = test_entity_labels();
+        let good_fields = FieldMap::from([("code", FieldValue::Int(200))]);
+        let bad_fields = FieldMap::from([("code", FieldValue::Bool(true))]);
+        counter.increment(&entity_labels, &good_fields).await;
+        counter.increment(&entity_labels, &bad_fields).await;
+        counter.increment(&entity_labels, &bad_fields).await;
+        assert_eq!(counter.get(&entity_labels, &good_fields).await, Some(1));
+        assert_eq!(counter.get(&entity_labels, &bad_fields).await, None);
+        assert_eq!(exporter.dropped_samples("/foo/bar/counter"), 2);
+    }
+
+    #[test]
+    fn test_rate() {
+        let start = SystemTime::UNIX_EPOCH;
+        let s1 = CounterSnapshot::new(10, start + Duration::from_secs(10));
+        let s2 = CounterSnapshot::new(30, start + Duration::from_secs(20));
+        assert_eq!(
+            Counter::rate(&s1, &s2),
+            CounterRate {
+                per_second: 2.0,
+                reset_detected: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_with_reset() {
+        let start = SystemTime::UNIX_EPOCH;
+        let s1 = CounterSnapshot::new(10, start + Duration::from_secs(10));
+        let s2 = CounterSnapshot::new(3, start + Duration::from_secs(20));
+        let s3 = CounterSnapshot::new(8, start + Duration::from_secs(30));
+        assert_eq!(
+            Counter::rate(&s1, &s2),
+            CounterRate {
+                per_second: 0.3,
+                reset_detected: true,
+            }
+        );
+        assert_eq!(
+            Counter::rate(&s2, &s3),
+            CounterRate {
+                per_second: 0.5,
+                reset_detected: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_without_elapsed_time() {
+        let s1 = CounterSnapshot::new(10, SystemTime::UNIX_EPOCH);
+        let s2 = CounterSnapshot::new(20, SystemTime::UNIX_EPOCH);
+        assert_eq!(Counter::rate(&s1, &s2).per_second, 0.0);
+    }
+
+    #[test]
+    fn test_rate_prefers_instants() {
+        let instant = Instant::now();
+        let s1 = CounterSnapshot::new(10, SystemTime::UNIX_EPOCH + Duration::from_secs(100))
+            .with_instant(instant);
+        let s2 = CounterSnapshot::new(30, SystemTime::UNIX_EPOCH)
+            .with_instant(instant + Duration::from_secs(10));
+        assert_eq!(Counter::rate(&s1, &s2).per_second, 2.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_smoothed_rate() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let counter = Counter::new_with(exporter, "/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            counter
+                .smoothed_rate(0.5, &entity_labels, &metric_fields)
+                .await,
+            0.0
+        );
+        let mut rate = 0.0;
+        for _ in 0..30 {
+            counter
+                .increment_by(10, &entity_labels, &metric_fields)
+                .await;
+            clock.advance(Duration::from_secs(1)).await;
+            rate = counter
+                .smoothed_rate(0.5, &entity_labels, &metric_fields)
+                .await;
+        }
+        assert!((rate - 10.0).abs() < 1e-6);
+        assert_eq!(
+            counter.get_or_zero(&entity_labels, &metric_fields).await,
+            300
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_smoothed_rate_clamps_alpha() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let counter = Counter::new_with(exporter, "/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter
+            .smoothed_rate(5.0, &entity_labels, &metric_fields)
+            .await;
+        counter
+            .increment_by(4, &entity_labels, &metric_fields)
+            .await;
+        clock.advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            counter
+                .smoothed_rate(5.0, &entity_labels, &metric_fields)
+                .await,
+            2.0
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_smoothed_rate_cleared_on_delete() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let counter = Counter::new_with(exporter, "/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = FieldMap::from([("lorem", FieldValue::Int(1))]);
+        let metric_fields2 = FieldMap::from([("lorem", FieldValue::Int(2))]);
+        for metric_fields in [&metric_fields1, &metric_fields2] {
+            counter.increment(&entity_labels, metric_fields).await;
+            counter
+                .smoothed_rate(0.5, &entity_labels, metric_fields)
+                .await;
+        }
+        assert_eq!(counter.smoothed_rates.lock().unwrap().len(), 2);
+        counter.delete(&entity_labels, &metric_fields1).await;
+        assert_eq!(counter.smoothed_rates.lock().unwrap().len(), 1);
+        counter.delete_entity(&entity_labels).await;
+        assert!(counter.smoothed_rates.lock().unwrap().is_empty());
+        counter.increment(&entity_labels, &metric_fields1).await;
+        counter
+            .smoothed_rate(0.5, &entity_labels, &metric_fields1)
+            .await;
+        counter.reset_all().await;
+        assert!(counter.smoothed_rates.lock().unwrap().is_empty());
+        counter
+            .increment_by(4, &entity_labels, &metric_fields1)
+            .await;
+        clock.advance(Duration::from_secs(2)).await;
+        assert_eq!(
+            counter
+                .smoothed_rate(0.5, &entity_labels, &metric_fields1)
+                .await,
+            0.0
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_snapshot() {
+        let counter = Counter::new("/foo/bar/counter", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let s1 = counter.snapshot(&entity_labels, &metric_fields).await;
+        assert_eq!(s1.value(), 0);
+        counter
+            .increment_by(5, &entity_labels, &metric_fields)
+            .await;
+        let s2 = counter.snapshot(&entity_labels, &metric_fields).await;
+        assert_eq!(s2.value(), 5);
+        assert!(s2.timestamp() >= s1.timestamp());
+        assert!(s2.instant().unwrap() >= s1.instant().unwrap());
+        assert!(!Counter::rate(&s1, &s2).reset_detected);
+    }
+
+    #[tokio::test]
+    async fn test_disabled() {
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::default()));
+        let counter = Counter::new_with(
+            exporter,
+            "/foo/bar/counter/disabled",
+            MetricConfig::default().set_enabled(false),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        counter.increment(&entity_labels, &metric_fields).await;
+        counter
+            .increment_by(42, &entity_labels, &metric_fields)
+            .await;
+        assert!(counter.get(&entity_labels, &metric_fields).await.is_none());
+        assert!(
+            exporter
+                .get_metric_config("/foo/bar/counter/disabled")
+                .is_none()
+        );
+        assert!(exporter.snapshot().await.is_empty());
+    }
 }
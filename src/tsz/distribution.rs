@@ -39,6 +39,22 @@ impl Distribution {
         }
     }
 
+    /// Builds a distribution with the specified number of samples in each finite bucket, each
+    /// sample being the midpoint of its bucket (see `Bucketer::sample_in_bucket`). Meant for
+    /// deterministic tests of the statistical helpers. Panics if `counts` doesn't have exactly one
+    /// entry per finite bucket.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_bucket_counts(bucketer: &'static Bucketer, counts: &[usize]) -> Self {
+        assert_eq!(counts.len(), bucketer.num_finite_buckets());
+        let mut distribution = Self::new(bucketer.into());
+        for (i, count) in counts.iter().enumerate() {
+            if *count > 0 {
+                distribution.record_many(bucketer.sample_in_bucket(i), *count);
+            }
+        }
+        distribution
+    }
+
     /// Returns the bucketer associated to this distribution.
     pub fn bucketer(&self) -> BucketerRef {
         self.bucketer
@@ -55,6 +71,64 @@ impl Distribution {
         self.buckets[i]
     }
 
+    /// Returns the lower (inclusive) and upper (exclusive) bounds of the i-th finite bucket, or None
+    /// if i is greater than or equal to `num_finite_buckets`.
+    pub fn bucket_bounds(&self, i: usize) -> Option<(f64, f64)> {
+        if i < self.buckets.len() {
+            let i = i as isize;
+            Some((self.bucketer.lower_bound(i), self.bucketer.upper_bound(i)))
+        } else {
+            None
+        }
+    }
+
+    /// Iterates over the non-empty finite buckets in ascending order, yielding
+    /// `(lower, upper, count)` tuples. Cheaper to render than scanning all buckets when a
+    /// high-resolution histogram is sparse.
+    pub fn iter_nonempty(&self) -> impl Iterator<Item = (f64, f64, usize)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| {
+                let i = i as isize;
+                (
+                    self.bucketer.lower_bound(i),
+                    self.bucketer.upper_bound(i),
+                    *count,
+                )
+            })
+    }
+
+    /// Like `iter_nonempty`, but also yields the underflow bucket first and the overflow bucket
+    /// last when they're not empty, with infinite lower and upper bounds respectively.
+    pub fn iter_all_nonempty(&self) -> impl Iterator<Item = (f64, f64, usize)> {
+        let n = self.buckets.len() as isize;
+        let underflow = (
+            f64::NEG_INFINITY,
+            self.bucketer.lower_bound(0),
+            self.underflow,
+        );
+        let overflow = (self.bucketer.lower_bound(n), f64::INFINITY, self.overflow);
+        std::iter::once(underflow)
+            .filter(|(.., count)| *count > 0)
+            .chain(self.iter_nonempty())
+            .chain(std::iter::once(overflow).filter(|(.., count)| *count > 0))
+    }
+
+    /// Returns the index of the finite bucket with the most samples along with its count, for a
+    /// quick look at the shape of the distribution. Ties go to the lowest index. The underflow and
+    /// overflow buckets are not considered, so this returns None if no finite bucket has samples,
+    /// and in particular if the distribution is empty.
+    pub fn mode_bucket(&self) -> Option<(usize, usize)> {
+        self.buckets
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .max_by_key(|(i, count)| (*count, std::cmp::Reverse(*i)))
+    }
+
     /// Returns the number of samples in the underflow bucket.
     pub fn underflow(&self) -> usize {
         self.underflow
@@ -65,6 +139,27 @@ impl Distribution {
         self.overflow
     }
 
+    /// Returns the share of samples that landed in the underflow bucket, or 0 if the distribution
+    /// is empty.
+    pub fn underflow_fraction(&self) -> f64 {
+        if self.count > 0 {
+            self.underflow as f64 / self.count as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the share of samples that landed in the overflow bucket, or 0 if the distribution
+    /// is empty. A high value usually indicates that the bucketer doesn't cover the range of the
+    /// samples.
+    pub fn overflow_fraction(&self) -> f64 {
+        if self.count > 0 {
+            self.overflow as f64 / self.count as f64
+        } else {
+            0.0
+        }
+    }
+
     /// Returns the sum of all samples.
     pub fn sum(&self) -> f64 {
         self.sum
@@ -98,11 +193,130 @@ impl Distribution {
         self.variance().sqrt()
     }
 
+    /// Returns the confidence interval for the mean as `mean ± z * stddev / sqrt(count)`, e.g. use
+    /// `z = 1.96` for a 95% confidence interval. This relies on the normal approximation, so it's
+    /// only meaningful for a reasonably large number of samples. Returns None if there are fewer
+    /// than 2 samples.
+    pub fn mean_confidence_interval(&self, z: f64) -> Option<(f64, f64)> {
+        if self.count < 2 {
+            return None;
+        }
+        let margin = z * self.stddev() / (self.count as f64).sqrt();
+        Some((self.mean - margin, self.mean + margin))
+    }
+
+    /// Estimates the geometric mean of the samples, which is often more meaningful than the
+    /// arithmetic mean for latencies.
+    ///
+    /// `Distribution` doesn't keep the samples, so each of them is approximated by the midpoint of
+    /// its bucket and the result is `exp(sum(count_i * ln(midpoint_i)) / count)`. Returns None if
+    /// the distribution is empty, if any sample is in the underflow or overflow bucket (they have
+    /// no finite midpoint), or if a non-empty bucket has a non-positive midpoint.
+    pub fn geometric_mean(&self) -> Option<f64> {
+        if self.count == 0 || self.underflow > 0 || self.overflow > 0 {
+            return None;
+        }
+        let mut log_sum = 0.0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            if *count > 0 {
+                let (lower, upper) = self.bucket_bounds(i).unwrap();
+                let midpoint = (lower + upper) / 2.0;
+                if midpoint <= 0.0 {
+                    return None;
+                }
+                log_sum += *count as f64 * midpoint.ln();
+            }
+        }
+        Some((log_sum / self.count as f64).exp())
+    }
+
+    /// Estimates the fraction of samples that are less than (or equal to) `threshold`, i.e. the
+    /// cumulative distribution function at `threshold`. Returns 0 if the distribution is empty.
+    ///
+    /// Samples are assumed to be spread uniformly within their bucket, so the result is exact when
+    /// `threshold` is a bucket boundary and interpolated otherwise. Underflow samples count as
+    /// below any threshold that falls in a finite bucket or above, and overflow samples are never
+    /// counted as below a threshold that falls in a finite bucket.
+    pub fn fraction_less_than(&self, threshold: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let bucket = self.bucketer.get_bucket_for(threshold);
+        let below = if bucket < 0 {
+            0.0
+        } else if bucket as usize >= self.buckets.len() {
+            (self.count - self.overflow) as f64
+        } else {
+            let i = bucket as usize;
+            let (lower, upper) = self.bucket_bounds(i).unwrap();
+            let full: usize = self.buckets[..i].iter().sum();
+            let partial = self.buckets[i] as f64 * (threshold - lower) / (upper - lower);
+            (self.underflow + full) as f64 + partial
+        };
+        below / self.count as f64
+    }
+
+    /// Estimates the `q`-quantile (`0 <= q <= 1`) of the samples exactly like Prometheus'
+    /// `histogram_quantile` does, so that values computed here agree with dashboards querying the
+    /// same histograms from Prometheus.
+    ///
+    /// The buckets are treated as Prometheus `le` buckets: the underflow bucket has upper bound
+    /// `lower_bound(0)`, each finite bucket has its upper bound, and the overflow bucket is the
+    /// `+Inf` bucket. The quantile is found by linear interpolation within the first bucket whose
+    /// cumulative count reaches `q * count()`. Like in Prometheus, the lowest bucket is taken to
+    /// start at 0 (or its upper bound is returned if that's not positive), and a quantile falling
+    /// in the overflow bucket is clamped to the upper bound of the last finite bucket. Unlike
+    /// `fraction_less_than`, which uses the actual bounds of the buckets, the underflow samples are
+    /// thus assumed to lie between 0 and the first bucket.
+    ///
+    /// Returns NaN if the distribution is empty or `q` is NaN, and negative or positive infinity
+    /// if `q` is below 0 or above 1 respectively.
+    pub fn prometheus_quantile(&self, q: f64) -> f64 {
+        if q.is_nan() || self.count == 0 {
+            return f64::NAN;
+        }
+        if q < 0.0 {
+            return f64::NEG_INFINITY;
+        }
+        if q > 1.0 {
+            return f64::INFINITY;
+        }
+        let n = self.buckets.len() as isize;
+        let upper_bounds = std::iter::once(self.bucketer.lower_bound(0))
+            .chain((0..n).map(|i| self.bucketer.upper_bound(i)));
+        let counts = std::iter::once(self.underflow).chain(self.buckets.iter().copied());
+        let rank = q * self.count as f64;
+        let mut cumulative = 0;
+        let mut lower = 0.0;
+        for (i, (upper, count)) in upper_bounds.zip(counts).enumerate() {
+            if count > 0 && (cumulative + count) as f64 >= rank {
+                if i == 0 && upper <= 0.0 {
+                    return upper;
+                }
+                return lower + (upper - lower) * (rank - cumulative as f64) / count as f64;
+            }
+            cumulative += count;
+            lower = upper;
+        }
+        self.bucketer.lower_bound(n)
+    }
+
     /// Records a sample in the corresponding bucket.
     pub fn record(&mut self, sample: f64) {
         self.record_many(sample, 1);
     }
 
+    /// Clamps the sample into `[low, high]` before recording it, so that outliers land in the edge
+    /// buckets rather than in the underflow or overflow buckets. That keeps the latter meaningful as
+    /// a sign that the bucketer is misconfigured rather than of a long tail. `high` must be less
+    /// than the upper bound of the last finite bucket (which is exclusive), otherwise clamped
+    /// samples still overflow.
+    ///
+    /// Note that the clamped value is also what goes into the sum and mean. Panics if `low > high`.
+    pub fn record_clamped(&mut self, sample: f64, low: f64, high: f64) {
+        self.record(sample.clamp(low, high));
+    }
+
     /// Records a sample `times` times.
     pub fn record_many(&mut self, sample: f64, times: usize) {
         let bucket = self.bucketer.get_bucket_for(sample);
@@ -133,10 +347,22 @@ impl Distribution {
         self.mean = new_mean;
     }
 
-    /// Adds `other` to this distribution. The two distributions must have the same bucketer,
-    /// otherwise the operation will fail with an error status.
+    /// Checks whether the distribution uses `bucketer` or a bucketer with the same parameters.
+    /// Bucketers from the constructors are canonical so comparing their addresses is usually enough,
+    /// but copies that don't come from the cache are compared by value.
+    pub fn has_bucketer(&self, bucketer: BucketerRef) -> bool {
+        self.bucketer == bucketer || *self.bucketer == *bucketer
+    }
+
+    /// Checks whether `self` and `other` have compatible bucketers, see `has_bucketer`.
+    pub fn has_same_bucketer(&self, other: &Self) -> bool {
+        self.has_bucketer(other.bucketer)
+    }
+
+    /// Adds `other` to this distribution. The two distributions must have bucketers with the same
+    /// parameters, otherwise the operation will fail with an error status.
     pub fn add(&mut self, other: &Self) -> Result<()> {
-        if self.bucketer != other.bucketer {
+        if !self.has_same_bucketer(other) {
             return Err(anyhow!("incompatible bucketers"));
         }
         for i in 0..self.num_finite_buckets() {
@@ -144,6 +370,54 @@ impl Distribution {
         }
         self.underflow += other.underflow;
         self.overflow += other.overflow;
+        self.add_stats(other);
+        Ok(())
+    }
+
+    /// Returns the sum of this distribution and `other` without modifying either, e.g. to fold over
+    /// many distributions. Fails under the same conditions as `add`.
+    pub fn added(&self, other: &Self) -> Result<Distribution> {
+        let mut result = self.clone();
+        result.add(other)?;
+        Ok(result)
+    }
+
+    /// Like `add`, but accepts a distribution with a different bucketer, re-bucketing its samples
+    /// into the bucketer of `self`. Meant for best-effort aggregation of heterogeneous sources.
+    ///
+    /// `Distribution` doesn't keep the samples, so all samples of a finite bucket of `other` are
+    /// assumed to be at the bucket's midpoint and land in the bucket of `self` containing it. This
+    /// loses precision whenever the buckets of `other` don't nest within those of `self`, and no
+    /// resolution can be recovered from a coarser `other`. The underflow and overflow samples of
+    /// `other` have no known value and go to the underflow and overflow buckets of `self`. Count,
+    /// sum, mean, and sum of squared deviations are combined exactly.
+    pub fn add_rebucketed(&mut self, other: &Self) {
+        if self.has_same_bucketer(other) {
+            self.add(other).unwrap();
+            return;
+        }
+        for (i, count) in other.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let (lower, upper) = other.bucket_bounds(i).unwrap();
+            let bucket = self.bucketer.get_bucket_for((lower + upper) / 2.0);
+            if bucket < 0 {
+                self.underflow += count;
+            } else if bucket as usize >= self.buckets.len() {
+                self.overflow += count;
+            } else {
+                self.buckets[bucket as usize] += count;
+            }
+        }
+        self.underflow += other.underflow;
+        self.overflow += other.overflow;
+        self.add_stats(other);
+    }
+
+    /// Combines the count, sum, mean, and sum of squared deviations of `other` into `self`. The
+    /// caller is responsible for the buckets.
+    fn add_stats(&mut self, other: &Self) {
         let old_count = self.count;
         self.count += other.count;
         self.sum += other.sum;
@@ -153,9 +427,137 @@ impl Distribution {
         } else {
             self.mean = 0.0;
         }
-        let square = (self.mean - old_mean) * (self.mean - other.mean);
-        self.ssd += other.ssd + (old_count as f64) * square + (other.count as f64) * square;
-        Ok(())
+        // Parallel variance formula (Chan et al.): the combined SSD is the sum of the two SSDs plus
+        // a term accounting for the distance between the two means.
+        if old_count > 0 && other.count > 0 {
+            let delta = other.mean - old_mean;
+            let weight = (old_count as f64) * (other.count as f64) / (self.count as f64);
+            self.ssd += other.ssd + delta * delta * weight;
+        } else {
+            self.ssd += other.ssd;
+        }
+    }
+
+    /// Returns a copy of this distribution with at most `max_buckets` finite buckets, obtained by
+    /// merging groups of `k = ceil(num_finite_buckets / max_buckets)` adjacent buckets.
+    ///
+    /// The bucketer of the result has `num_finite_buckets / k` buckets (rounded down), width
+    /// `width * k`, growth factor `growth_factor^k`, and scale factor
+    /// `scale_factor * growth_factor^(k - 1)`, so that each of its buckets spans exactly the merged
+    /// buckets of the source. When `num_finite_buckets` is not a multiple of `k` the trailing source
+    /// buckets lie above the last coarse bucket and are moved to the overflow bucket. Count, sum,
+    /// mean, and sum of squared deviations are preserved.
+    ///
+    /// If the distribution already has at most `max_buckets` buckets an identical copy is returned.
+    /// Panics if `max_buckets` is 0.
+    pub fn coarsen(&self, max_buckets: usize) -> Distribution {
+        assert!(max_buckets > 0);
+        let num_finite_buckets = self.num_finite_buckets();
+        if num_finite_buckets <= max_buckets {
+            return self.clone();
+        }
+        let k = num_finite_buckets.div_ceil(max_buckets);
+        let growth_factor = self.bucketer.growth_factor();
+        let scale_factor = if growth_factor != 0.0 {
+            self.bucketer.scale_factor() * growth_factor.powi(k as i32 - 1)
+        } else {
+            self.bucketer.scale_factor()
+        };
+        let bucketer = Bucketer::custom(
+            self.bucketer.width() * k as f64,
+            growth_factor.powi(k as i32),
+            scale_factor,
+            num_finite_buckets / k,
+        );
+        let mut result = self.clone();
+        result.bucketer = bucketer.into();
+        result.buckets = self
+            .buckets
+            .chunks_exact(k)
+            .map(|chunk| chunk.iter().sum())
+            .collect();
+        result.overflow += self
+            .buckets
+            .chunks_exact(k)
+            .remainder()
+            .iter()
+            .sum::<usize>();
+        result
+    }
+
+    /// Converts the finite buckets to `(value, count)` pairs that can be fed to an HdrHistogram
+    /// recorder (e.g. with `record_n`), one pair per non-empty bucket in increasing bucket order.
+    ///
+    /// The mapping is lossy: all samples of a bucket are reported at its midpoint, which is rounded
+    /// to the nearest integer and clamped at 0 since HDR histograms only track non-negative integer
+    /// values. Samples should therefore be recorded in a unit fine enough for the buckets (e.g.
+    /// microseconds rather than seconds). Narrow adjacent buckets may map to the same value, in
+    /// which case the recorder simply adds up their counts. The underflow and overflow buckets have
+    /// no finite midpoint and are omitted, so the emitted counts add up to
+    /// `count() - underflow() - overflow()`.
+    pub fn to_hdr_buckets(&self) -> Vec<(u64, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| {
+                let lower = self.bucketer.lower_bound(i as isize);
+                let upper = self.bucketer.upper_bound(i as isize);
+                (((lower + upper) / 2.0).round() as u64, *count as u64)
+            })
+            .collect()
+    }
+
+    /// Number of significant digits the sum is rounded to in `canonical_bytes`.
+    const CANONICAL_SUM_SIGNIFICANT_DIGITS: i32 = 9;
+
+    /// Returns a stable byte representation of the distribution, suitable for golden tests.
+    ///
+    /// Unlike `==`, which ignores the stats, the representation includes the count and the sum. The
+    /// sum is rounded to `CANONICAL_SUM_SIGNIFICANT_DIGITS` significant digits so that the same
+    /// samples recorded in a different order yield the same bytes even though floating point
+    /// addition isn't associative. The mean and the sum of squared deviations are left out because
+    /// they're more sensitive to the order of the samples. Only non-empty buckets are included,
+    /// along with their indices.
+    ///
+    /// All integers are encoded as little-endian u64s and all floats as the little-endian bits of
+    /// an f64. The layout is: the four bucketer parameters, underflow, overflow, count, sum, number
+    /// of non-empty buckets, then an (index, count) pair for each of them.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut put_u64 = |value: u64| bytes.extend_from_slice(&value.to_le_bytes());
+        put_u64(self.bucketer.width().to_bits());
+        put_u64(self.bucketer.growth_factor().to_bits());
+        put_u64(self.bucketer.scale_factor().to_bits());
+        put_u64(self.bucketer.num_finite_buckets() as u64);
+        put_u64(self.underflow as u64);
+        put_u64(self.overflow as u64);
+        put_u64(self.count as u64);
+        put_u64(Self::round_sum(self.sum).to_bits());
+        let nonempty: Vec<(usize, usize)> = self
+            .buckets
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        put_u64(nonempty.len() as u64);
+        for (i, count) in nonempty {
+            put_u64(i as u64);
+            put_u64(count as u64);
+        }
+        bytes
+    }
+
+    fn round_sum(sum: f64) -> f64 {
+        if sum == 0.0 || !sum.is_finite() {
+            // Also maps -0 to +0.
+            return sum + 0.0;
+        }
+        let magnitude = sum.abs().log10().floor() as i32;
+        let scale = 10f64.powi(Self::CANONICAL_SUM_SIGNIFICANT_DIGITS - 1 - magnitude);
+        let result = (sum * scale).round() / scale;
+        if result.is_finite() { result } else { sum }
     }
 
     /// Resets all state to an empty distribution.
@@ -180,7 +582,7 @@ impl Default for Distribution {
 
 impl PartialEq for Distribution {
     fn eq(&self, other: &Self) -> bool {
-        self.bucketer == other.bucketer
+        self.has_same_bucketer(other)
             && self.buckets == other.buckets
             && self.underflow == other.underflow
             && self.overflow == other.overflow
@@ -264,6 +666,226 @@ mod tests {
         assert_eq!(d.mean(), 4.0);
     }
 
+    #[test]
+    fn test_bucket_bounds() {
+        let d = Distribution::new(Bucketer::fixed_width(10.0, 5).into());
+        assert_eq!(d.bucket_bounds(0), Some((0.0, 10.0)));
+        assert_eq!(d.bucket_bounds(4), Some((40.0, 50.0)));
+        assert_eq!(d.bucket_bounds(5), None);
+        assert_eq!(Distribution::default().bucket_bounds(0), Some((0.25, 1.0)));
+    }
+
+    #[test]
+    fn test_record_clamped() {
+        let mut d = Distribution::new(Bucketer::fixed_width(10.0, 20).into());
+        d.record_clamped(1000.0, 0.0, 100.0);
+        d.record_clamped(-5.0, 0.0, 100.0);
+        d.record_clamped(42.0, 0.0, 100.0);
+        assert_eq!(d.overflow(), 0);
+        assert_eq!(d.underflow(), 0);
+        assert_eq!(d.bucket(10), 1);
+        assert_eq!(d.bucket(0), 1);
+        assert_eq!(d.bucket(4), 1);
+        assert_eq!(d.sum(), 142.0);
+    }
+
+    #[test]
+    fn test_iter_nonempty() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 1000).into());
+        d.record(-5.0);
+        d.record_many(700.5, 2);
+        d.record(3.5);
+        d.record(42.0);
+        d.record(2000.0);
+        assert_eq!(
+            d.iter_nonempty().collect::<Vec<_>>(),
+            vec![(3.0, 4.0, 1), (42.0, 43.0, 1), (700.0, 701.0, 2)]
+        );
+        assert_eq!(
+            d.iter_all_nonempty().collect::<Vec<_>>(),
+            vec![
+                (f64::NEG_INFINITY, 0.0, 1),
+                (3.0, 4.0, 1),
+                (42.0, 43.0, 1),
+                (700.0, 701.0, 2),
+                (1000.0, f64::INFINITY, 1),
+            ]
+        );
+        assert_eq!(Distribution::default().iter_all_nonempty().count(), 0);
+    }
+
+    #[test]
+    fn test_with_bucket_counts() {
+        let bucketer = Bucketer::fixed_width(1.0, 5);
+        let counts = [0, 2, 5, 0, 1];
+        let d = Distribution::with_bucket_counts(bucketer, &counts);
+        let mut replayed = Distribution::new(bucketer.into());
+        for (i, count) in counts.iter().enumerate() {
+            for _ in 0..*count {
+                replayed.record(bucketer.sample_in_bucket(i));
+            }
+        }
+        for (i, count) in counts.iter().enumerate() {
+            assert_eq!(d.bucket(i), *count);
+            assert_eq!(replayed.bucket(i), *count);
+        }
+        assert_eq!(d, replayed);
+        assert_eq!(d.count(), 8);
+        assert_eq!(d.sum(), replayed.sum());
+        assert_eq!(d.mode_bucket(), Some((2, 5)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_bucket_counts_size_mismatch() {
+        Distribution::with_bucket_counts(Bucketer::fixed_width(1.0, 5), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_mode_bucket() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        assert_eq!(d.mode_bucket(), None);
+        d.record_many(-1.0, 10);
+        d.record_many(20.0, 10);
+        assert_eq!(d.mode_bucket(), None);
+        d.record(0.5);
+        d.record_many(2.5, 3);
+        d.record_many(5.5, 2);
+        assert_eq!(d.mode_bucket(), Some((2, 3)));
+        d.record(5.5);
+        assert_eq!(d.mode_bucket(), Some((2, 3)));
+        d.record(5.5);
+        assert_eq!(d.mode_bucket(), Some((5, 4)));
+    }
+
+    #[test]
+    fn test_geometric_mean() {
+        let mut d = Distribution::new(Bucketer::powers_of(10.0).into());
+        assert_eq!(d.geometric_mean(), None);
+        d.record(1.0);
+        d.record(10.0);
+        d.record(100.0);
+        // The buckets are [1, 10), [10, 100), and [100, 1000), so the samples are approximated by
+        // 5.5, 55, and 550.
+        assert!((d.geometric_mean().unwrap() - 55.0).abs() < 1e-9);
+        d.record_many(1000.0, 3);
+        assert!((d.geometric_mean().unwrap() - 5.5 * 10f64.powf(2.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_mean_confidence_interval() {
+        let mut d = Distribution::default();
+        assert_eq!(d.mean_confidence_interval(1.96), None);
+        d.record(3.0);
+        assert_eq!(d.mean_confidence_interval(1.96), None);
+        for i in 1..10 {
+            d.record((i % 10) as f64);
+        }
+        let (lower1, upper1) = d.mean_confidence_interval(1.96).unwrap();
+        assert!(lower1 < 4.5 && 4.5 < upper1);
+        for i in 10..1000 {
+            d.record((i % 10) as f64);
+        }
+        let (lower2, upper2) = d.mean_confidence_interval(1.96).unwrap();
+        assert!(lower2 < 4.5 && 4.5 < upper2);
+        assert!(upper2 - lower2 < upper1 - lower1);
+    }
+
+    #[test]
+    fn test_geometric_mean_with_underflow() {
+        let mut d = Distribution::new(Bucketer::powers_of(10.0).into());
+        d.record(10.0);
+        d.record(-1.0);
+        assert_eq!(d.geometric_mean(), None);
+    }
+
+    #[test]
+    fn test_fraction_less_than() {
+        let mut d = Distribution::new(Bucketer::fixed_width(10.0, 5).into());
+        assert_eq!(d.fraction_less_than(25.0), 0.0);
+        d.record(-1.0);
+        d.record_many(5.0, 2);
+        d.record_many(15.0, 4);
+        d.record(100.0);
+        assert_eq!(d.fraction_less_than(-5.0), 0.0);
+        assert_eq!(d.fraction_less_than(0.0), 0.125);
+        assert_eq!(d.fraction_less_than(10.0), 0.375);
+        assert_eq!(d.fraction_less_than(15.0), 0.625);
+        assert_eq!(d.fraction_less_than(20.0), 0.875);
+        assert_eq!(d.fraction_less_than(60.0), 0.875);
+    }
+
+    #[test]
+    fn test_prometheus_quantile() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 4).into());
+        assert!(d.prometheus_quantile(0.5).is_nan());
+        d.record_many(0.5, 2);
+        d.record_many(1.5, 4);
+        d.record_many(2.5, 2);
+        d.record_many(3.5, 2);
+        // Cumulative counts for le=0, 1, 2, 3, 4, +Inf are 0, 2, 6, 8, 10, 10.
+        assert_eq!(d.prometheus_quantile(0.1), 0.5);
+        assert_eq!(d.prometheus_quantile(0.5), 1.75);
+        assert_eq!(d.prometheus_quantile(0.9), 3.5);
+        assert_eq!(d.prometheus_quantile(1.0), 4.0);
+        assert_eq!(d.prometheus_quantile(-0.1), f64::NEG_INFINITY);
+        assert_eq!(d.prometheus_quantile(1.1), f64::INFINITY);
+        assert!(d.prometheus_quantile(f64::NAN).is_nan());
+    }
+
+    #[test]
+    fn test_prometheus_quantile_underflow_and_overflow() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 4).into());
+        d.record(-1.0);
+        d.record(0.5);
+        d.record_many(10.0, 2);
+        assert_eq!(d.prometheus_quantile(0.25), 0.0);
+        assert_eq!(d.prometheus_quantile(0.5), 1.0);
+        assert_eq!(d.prometheus_quantile(0.99), 4.0);
+    }
+
+    #[test]
+    fn test_empty_fractions() {
+        let d = Distribution::default();
+        assert_eq!(d.underflow_fraction(), 0.0);
+        assert_eq!(d.overflow_fraction(), 0.0);
+    }
+
+    #[test]
+    fn test_overflow_fraction() {
+        let mut d = Distribution::default();
+        d.record_many(42.0, 2);
+        d.record_many(1e12, 2);
+        assert_eq!(d.overflow(), 2);
+        assert_eq!(d.overflow_fraction(), 0.5);
+        assert_eq!(d.underflow_fraction(), 0.0);
+    }
+
+    #[test]
+    fn test_underflow_fraction() {
+        let mut d = Distribution::default();
+        d.record(-1.0);
+        d.record_many(42.0, 2);
+        d.record(1e12);
+        assert_eq!(d.underflow_fraction(), 0.25);
+        assert_eq!(d.overflow_fraction(), 0.25);
+    }
+
+    #[test]
+    fn test_none_bucketer() {
+        let mut d = Distribution::new(Bucketer::none().into());
+        assert_eq!(d.num_finite_buckets(), 0);
+        d.record(-1.0);
+        d.record(0.0);
+        d.record(5.0);
+        assert_eq!(d.underflow(), 1);
+        assert_eq!(d.overflow(), 2);
+        assert_eq!(d.count(), 3);
+        assert_eq!(d.sum(), 4.0);
+        assert!((d.mean() - 4.0 / 3.0).abs() < 1e-12);
+        assert!((d.sum_of_squared_deviations() - 62.0 / 3.0).abs() < 1e-12);
+    }
+
     #[test]
     fn test_add_empty_to_empty() {
         let mut d1 = Distribution::default();
@@ -371,6 +993,300 @@ mod tests {
         assert_eq!(d1.mean(), 6.0);
     }
 
+    #[test]
+    fn test_added() {
+        let mut d1 = Distribution::default();
+        d1.record(2.0);
+        d1.record(4.0);
+        let mut d2 = Distribution::default();
+        d2.record(1.0);
+        d2.record(3.0);
+        d2.record(5.0);
+        let (original1, original2) = (d1.clone(), d2.clone());
+        let sum = d1.added(&d2).unwrap();
+        assert_eq!(d1, original1);
+        assert_eq!(d1.count(), 2);
+        assert_eq!(d2, original2);
+        assert_eq!(d2.count(), 3);
+        let mut expected = d1.clone();
+        expected.add(&d2).unwrap();
+        assert_eq!(sum, expected);
+        assert_eq!(sum.count(), 5);
+        assert_eq!(sum.sum(), 15.0);
+        assert_eq!(sum.mean(), 3.0);
+    }
+
+    #[test]
+    fn test_added_incompatible() {
+        let d1 = Distribution::default();
+        let d2 = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        assert!(d1.added(&d2).is_err());
+    }
+
+    /// Deterministic xorshift generator for the property tests below.
+    fn random_samples(seed: u64, count: usize, scale: f64) -> Vec<f64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 1_000_000) as f64 / 1_000_000.0 * scale
+            })
+            .collect()
+    }
+
+    fn assert_close(lhs: f64, rhs: f64) {
+        assert!(
+            (lhs - rhs).abs() <= 1e-9 * f64::max(1.0, f64::max(lhs.abs(), rhs.abs())),
+            "{} != {}",
+            lhs,
+            rhs
+        );
+    }
+
+    fn check_add_matches_replay(samples1: &[f64], samples2: &[f64]) {
+        let mut d1 = Distribution::default();
+        for sample in samples1 {
+            d1.record(*sample);
+        }
+        let mut d2 = Distribution::default();
+        for sample in samples2 {
+            d2.record(*sample);
+        }
+        let mut expected = Distribution::default();
+        for sample in samples1.iter().chain(samples2.iter()) {
+            expected.record(*sample);
+        }
+        assert!(d1.add(&d2).is_ok());
+        assert_eq!(d1, expected);
+        assert_eq!(d1.count(), expected.count());
+        assert_close(d1.sum(), expected.sum());
+        assert_close(d1.mean(), expected.mean());
+        assert_close(
+            d1.sum_of_squared_deviations(),
+            expected.sum_of_squared_deviations(),
+        );
+    }
+
+    #[test]
+    fn test_add_different_means() {
+        let mut d1 = Distribution::default();
+        d1.record(1.0);
+        d1.record(3.0);
+        let mut d2 = Distribution::default();
+        d2.record(9.0);
+        d2.record(11.0);
+        assert!(d1.add(&d2).is_ok());
+        assert_eq!(d1.count(), 4);
+        assert_eq!(d1.sum(), 24.0);
+        assert_eq!(d1.mean(), 6.0);
+        assert_eq!(d1.sum_of_squared_deviations(), 68.0);
+    }
+
+    #[test]
+    fn test_add_equivalent_bucketers() {
+        let mut d1 = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        d1.record(2.5);
+        let mut d2 = Distribution::new(Bucketer::custom(1.0, 0.0, 1.0, 10).into());
+        d2.record(3.5);
+        assert!(d1.add(&d2).is_ok());
+        let copy: &'static Bucketer = Box::leak(Box::new(*Bucketer::fixed_width(1.0, 10)));
+        assert!(!copy.same_as(Bucketer::fixed_width(1.0, 10)));
+        let mut d3 = Distribution::new(copy.into());
+        d3.record(4.5);
+        assert!(d1.add(&d3).is_ok());
+        assert_eq!(d1.count(), 3);
+        assert_eq!(d1.bucket(2), 1);
+        assert_eq!(d1.bucket(3), 1);
+        assert_eq!(d1.bucket(4), 1);
+        let d4 = Distribution::new(Bucketer::fixed_width(1.0, 11).into());
+        assert!(d1.add(&d4).is_err());
+    }
+
+    #[test]
+    fn test_eq_equivalent_bucketers() {
+        let copy: &'static Bucketer = Box::leak(Box::new(*Bucketer::fixed_width(1.0, 10)));
+        let mut d1 = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        d1.record(2.5);
+        let mut d2 = Distribution::new(copy.into());
+        d2.record(2.5);
+        assert!(d1.has_same_bucketer(&d2));
+        assert!(d1.has_bucketer(copy.into()));
+        assert_eq!(d1, d2);
+        let mut d3 = Distribution::new(Bucketer::fixed_width(1.0, 11).into());
+        d3.record(2.5);
+        assert!(!d1.has_same_bucketer(&d3));
+        assert_ne!(d1, d3);
+    }
+
+    #[test]
+    fn test_add_matches_replay() {
+        for seed in 1..20 {
+            let samples1 = random_samples(seed, 10 + seed as usize, 100.0);
+            let samples2 = random_samples(seed * 7919, 5 + 3 * seed as usize, 1000.0);
+            check_add_matches_replay(&samples1, &samples2);
+            check_add_matches_replay(&samples2, &samples1);
+        }
+    }
+
+    #[test]
+    fn test_add_matches_replay_with_empty_side() {
+        let samples = random_samples(42, 50, 100.0);
+        check_add_matches_replay(&samples, &[]);
+        check_add_matches_replay(&[], &samples);
+        check_add_matches_replay(&[], &[]);
+    }
+
+    #[test]
+    fn test_add_matches_replay_with_same_mean() {
+        check_add_matches_replay(&[2.0, 4.0, 6.0], &[5.0, 7.0]);
+        check_add_matches_replay(&[3.0, 3.0], &[1.0, 5.0]);
+    }
+
+    #[test]
+    fn test_add_rebucketed() {
+        let mut parent = Distribution::new(Bucketer::powers_of(2.0).into());
+        parent.record(3.0);
+        let mut child = Distribution::new(Bucketer::fixed_width(3.0, 4).into());
+        for sample in [-1.0, 1.0, 4.0, 4.5, 10.0, 100.0] {
+            child.record(sample);
+        }
+        let mut expected = parent.clone();
+        for sample in [-1.0, 1.0, 4.0, 4.5, 10.0, 100.0] {
+            expected.record(sample);
+        }
+        parent.add_rebucketed(&child);
+        assert!(parent.bucketer().same_as(Bucketer::powers_of(2.0)));
+        // The midpoints of the non-empty child buckets are 1.5, 4.5, and 10.5.
+        assert_eq!(parent.bucket(1), 1);
+        assert_eq!(parent.bucket(2), 1);
+        assert_eq!(parent.bucket(3), 2);
+        assert_eq!(parent.bucket(4), 1);
+        assert_eq!(parent.underflow(), 1);
+        assert_eq!(parent.overflow(), 1);
+        assert_eq!(parent.count(), 7);
+        assert_eq!(parent.sum(), expected.sum());
+        assert_close(parent.mean(), expected.mean());
+        assert_close(
+            parent.sum_of_squared_deviations(),
+            expected.sum_of_squared_deviations(),
+        );
+    }
+
+    #[test]
+    fn test_add_rebucketed_same_bucketer() {
+        let mut d1 = Distribution::default();
+        d1.record(1.0);
+        let mut d2 = Distribution::default();
+        d2.record(100.0);
+        let mut expected = d1.clone();
+        expected.add(&d2).unwrap();
+        d1.add_rebucketed(&d2);
+        assert_eq!(d1, expected);
+        assert_eq!(d1.count(), 2);
+    }
+
+    #[test]
+    fn test_coarsen_fixed_width() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 20).into());
+        for sample in [-1.0, 0.5, 3.5, 4.5, 10.5, 19.5, 42.0] {
+            d.record(sample);
+        }
+        let coarse = d.coarsen(5);
+        assert!(coarse.bucketer().same_as(Bucketer::fixed_width(4.0, 5)));
+        assert_eq!(coarse.num_finite_buckets(), 5);
+        assert_eq!(coarse.bucket(0), 2);
+        assert_eq!(coarse.bucket(1), 1);
+        assert_eq!(coarse.bucket(2), 1);
+        assert_eq!(coarse.bucket(3), 0);
+        assert_eq!(coarse.bucket(4), 1);
+        assert_eq!(coarse.underflow(), 1);
+        assert_eq!(coarse.overflow(), 1);
+        assert_eq!(coarse.count(), d.count());
+        assert_eq!(coarse.sum(), d.sum());
+        assert_eq!(coarse.mean(), d.mean());
+        assert_eq!(
+            coarse.sum_of_squared_deviations(),
+            d.sum_of_squared_deviations()
+        );
+    }
+
+    #[test]
+    fn test_coarsen_exponential() {
+        let bucketer = Bucketer::custom(0.0, 2.0, 1.0, 20);
+        let mut d = Distribution::new(bucketer.into());
+        let samples = [0.1, 0.7, 1.5, 3.0, 9.0, 100.0, 3000.0, 1e5, 1e7];
+        for sample in samples {
+            d.record(sample);
+        }
+        let coarse = d.coarsen(5);
+        assert_eq!(coarse.num_finite_buckets(), 5);
+        for i in 0..5 {
+            let lower = coarse.bucketer().lower_bound(i as isize);
+            let upper = coarse.bucketer().upper_bound(i as isize);
+            assert!((lower - bucketer.lower_bound(4 * i as isize)).abs() < 1e-9 * upper);
+            assert!((upper - bucketer.upper_bound(4 * i as isize + 3)).abs() < 1e-9 * upper);
+        }
+        let mut expected = Distribution::new(coarse.bucketer());
+        for sample in samples {
+            expected.record(sample);
+        }
+        assert_eq!(coarse, expected);
+        assert_eq!(coarse.count(), d.count());
+        assert_eq!(coarse.sum(), d.sum());
+    }
+
+    #[test]
+    fn test_coarsen_with_remainder() {
+        let mut d = Distribution::new(Bucketer::fixed_width(1.0, 17).into());
+        d.record(0.5);
+        d.record(16.5);
+        let coarse = d.coarsen(5);
+        assert!(coarse.bucketer().same_as(Bucketer::fixed_width(4.0, 4)));
+        assert_eq!(coarse.bucket(0), 1);
+        assert_eq!(coarse.overflow(), 1);
+        assert_eq!(coarse.count(), 2);
+    }
+
+    #[test]
+    fn test_coarsen_noop() {
+        let mut d = Distribution::default();
+        d.record(42.0);
+        let coarse = d.coarsen(d.num_finite_buckets());
+        assert_eq!(coarse.bucketer(), d.bucketer());
+        assert_eq!(coarse, d);
+    }
+
+    #[test]
+    fn test_to_hdr_buckets() {
+        let mut d = Distribution::new(Bucketer::fixed_width(10.0, 5).into());
+        d.record(-1.0);
+        d.record(12.0);
+        d.record_many(15.0, 2);
+        d.record(41.0);
+        d.record(1000.0);
+        assert_eq!(d.to_hdr_buckets(), vec![(15, 3), (45, 1)]);
+    }
+
+    #[test]
+    fn test_to_hdr_buckets_total_count() {
+        let mut d = Distribution::default();
+        d.record(-3.0);
+        for i in 0..100 {
+            d.record(1.0 + i as f64 * 7.5);
+        }
+        d.record(1e12);
+        let total: u64 = d.to_hdr_buckets().iter().map(|(_, count)| count).sum();
+        assert_eq!(total as usize, d.count() - d.underflow() - d.overflow());
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn test_empty_to_hdr_buckets() {
+        assert!(Distribution::default().to_hdr_buckets().is_empty());
+    }
+
     #[test]
     fn test_clear() {
         let mut d = Distribution::default();
@@ -399,4 +1315,27 @@ mod tests {
         assert!(!d.is_empty());
         assert_eq!(d.mean(), 42.0);
     }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let mut d1 = Distribution::default();
+        let mut d2 = Distribution::default();
+        for sample in [0.1, 0.2, 0.3, 0.7, -1.0, 5.5] {
+            d1.record(sample);
+        }
+        for sample in [0.1, 0.3, -1.0, 5.5, 0.2, 0.7] {
+            d2.record(sample);
+        }
+        assert_ne!(d1.sum(), d2.sum());
+        assert_eq!(d1.canonical_bytes(), d2.canonical_bytes());
+        let mut d3 = d2.clone();
+        d3.record(0.0);
+        assert_ne!(d1.canonical_bytes(), d3.canonical_bytes());
+        let mut d4 = Distribution::default();
+        d4.record(1.0);
+        let mut d5 = Distribution::default();
+        d5.record(1.5);
+        assert_eq!(d4, d5);
+        assert_ne!(d4.canonical_bytes(), d5.canonical_bytes());
+    }
 }
@@ -1,18 +1,49 @@
+#[cfg(not(feature = "no-global-exporter"))]
+use crate::tsz::exporter::EXPORTER;
 use crate::tsz::{
-    FieldMap, bucketer::BucketerRef, config::MetricConfig, distribution::Distribution,
-    exporter::EXPORTER,
+    FieldMap, bucketer::Bucketer, bucketer::BucketerRef, config::MetricConfig,
+    distribution::Distribution, exporter::Exporter,
 };
 use crate::utils::lazy::Lazy;
+use anyhow::Result;
+use std::pin::Pin;
+use std::sync::{Mutex, atomic::AtomicUsize, atomic::Ordering};
+use std::time::Duration;
+
+/// The unit in which durations are recorded by `record_duration_as`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    #[default]
+    Millis,
+    Micros,
+}
+
+impl TimeUnit {
+    /// Converts `duration` to a sample expressed in this unit.
+    pub fn to_sample(self, duration: Duration) -> f64 {
+        match self {
+            TimeUnit::Seconds => duration.as_secs_f64(),
+            TimeUnit::Millis => duration.as_secs_f64() * 1000.0,
+            TimeUnit::Micros => duration.as_secs_f64() * 1000000.0,
+        }
+    }
+}
 
 #[derive(Debug)]
 struct EventMetricImpl {
+    exporter: Pin<&'static Exporter<'static>>,
     name: &'static str,
 }
 
 impl EventMetricImpl {
-    fn new(name: &'static str, config: MetricConfig) -> Self {
-        EXPORTER.define_metric_redundant(name, config);
-        Self { name }
+    fn new(
+        exporter: Pin<&'static Exporter<'static>>,
+        name: &'static str,
+        config: MetricConfig,
+    ) -> Self {
+        exporter.define_metric_redundant(name, config);
+        Self { exporter, name }
     }
 
     async fn get(
@@ -20,7 +51,7 @@ impl EventMetricImpl {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) -> Option<Distribution> {
-        EXPORTER
+        self.exporter
             .get_distribution(entity_labels, self.name, metric_fields)
             .await
     }
@@ -32,20 +63,44 @@ impl EventMetricImpl {
         times: usize,
         metric_fields: &FieldMap,
     ) {
-        EXPORTER
+        self.exporter
             .add_many_to_distribution(entity_labels, self.name, sample, times, metric_fields)
             .await
+            .ok();
+    }
+
+    async fn record_and_get(
+        &self,
+        entity_labels: &FieldMap,
+        sample: f64,
+        times: usize,
+        metric_fields: &FieldMap,
+    ) -> Option<Distribution> {
+        self.exporter
+            .add_many_to_distribution_and_get(
+                entity_labels,
+                self.name,
+                sample,
+                times,
+                metric_fields,
+            )
+            .await
+            .ok()
+    }
+
+    async fn redefine(&self, config: MetricConfig) -> Result<()> {
+        self.exporter.redefine_metric(self.name, config).await
     }
 
     async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_value(entity_labels, self.name, metric_fields)
             .await
             .is_some()
     }
 
     async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_metric_from_entity(entity_labels, self.name)
             .await
     }
@@ -55,11 +110,25 @@ impl EventMetricImpl {
 pub struct EventMetric {
     name: &'static str,
     config: MetricConfig,
+    /// The config stored in the exporter by the last `set_bucketer`, which overrides `config`.
+    redefined_config: Mutex<Option<&'static MetricConfig>>,
+    observed: AtomicUsize,
     inner: Lazy<EventMetricImpl>,
 }
 
 impl EventMetric {
-    pub fn new(name: &'static str, mut config: MetricConfig) -> Self {
+    #[cfg(not(feature = "no-global-exporter"))]
+    pub fn new(name: &'static str, config: MetricConfig) -> Self {
+        Self::new_with(EXPORTER.get_ref(), name, config)
+    }
+
+    /// Creates an event metric exported by `exporter` rather than the global exporter.
+    pub fn new_with(
+        exporter: &'static Exporter<'static>,
+        name: &'static str,
+        mut config: MetricConfig,
+    ) -> Self {
+        let exporter = Pin::new(exporter);
         config.cumulative = true;
         if config.bucketer.is_none() {
             config.bucketer = Some(BucketerRef::default());
@@ -67,7 +136,9 @@ impl EventMetric {
         Self {
             name,
             config,
-            inner: Lazy::new(move || EventMetricImpl::new(name, config)),
+            redefined_config: Mutex::default(),
+            observed: AtomicUsize::new(0),
+            inner: Lazy::new(move || EventMetricImpl::new(exporter, name, config)),
         }
     }
 
@@ -76,11 +147,26 @@ impl EventMetric {
     }
 
     pub fn config(&self) -> &MetricConfig {
-        &self.config
+        match *self.redefined_config.lock().unwrap() {
+            Some(config) => config,
+            None => &self.config,
+        }
     }
 
     pub fn bucketer(&self) -> BucketerRef {
-        self.config.bucketer.unwrap()
+        self.config().bucketer.unwrap()
+    }
+
+    /// Switches the metric to a different bucketer.
+    ///
+    /// Existing cells can't be converted because their samples are only known by bucket, so they
+    /// are all deleted (see `Exporter::redefine_metric`) and subsequent records start over with the
+    /// new bucketer.
+    pub async fn set_bucketer(&self, bucketer: &'static Bucketer) -> Result<()> {
+        let config = self.config().set_bucketer(bucketer);
+        self.inner.redefine(config).await?;
+        *self.redefined_config.lock().unwrap() = self.inner.exporter.get_metric_config(self.name);
+        Ok(())
     }
 
     pub async fn get(
@@ -103,6 +189,33 @@ impl EventMetric {
             .unwrap()
     }
 
+    /// Returns the fraction (between 0 and 1) of the samples of the specified cell that are at or
+    /// below `threshold`, as estimated by `Distribution::fraction_less_than`. Returns None if the
+    /// cell has no samples.
+    pub async fn slo_compliance(
+        &self,
+        threshold: f64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> Option<f64> {
+        self.get(entity_labels, metric_fields)
+            .await
+            .filter(|distribution| !distribution.is_empty())
+            .map(|distribution| distribution.fraction_less_than(threshold))
+    }
+
+    /// Returns the number of samples actually passed to the record functions of this metric, across
+    /// all cells.
+    ///
+    /// This differs from the counts of the exported distributions when the config has a sampling
+    /// rate N > 1, because then each observed sample is recorded N times. Those counts (and sums)
+    /// are population estimates that assume the sampled events are representative: their error is
+    /// high when few samples were observed, and rare outliers get either missed or overweighted.
+    pub fn observed_samples(&self) -> usize {
+        self.observed.load(Ordering::Relaxed)
+    }
+
+    /// Records `sample` `times` times, multiplied by the sampling rate of the metric (if any).
     pub async fn record_many(
         &self,
         sample: f64,
@@ -110,14 +223,64 @@ impl EventMetric {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.observed.fetch_add(times, Ordering::Relaxed);
+        let times = times.saturating_mul(self.config().sampling_weight());
         self.inner
             .record(entity_labels, sample, times, metric_fields)
             .await
     }
 
+    /// Records `sample` once, or N times if the config has a sampling rate of N. See
+    /// `observed_samples` for the implications of sampling.
     pub async fn record(&self, sample: f64, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        self.record_many(sample, 1, entity_labels, metric_fields)
+            .await
+    }
+
+    /// Like `record`, but also returns the resulting distribution of the cell. The sample is
+    /// recorded and the distribution read with the entity locked, so no concurrent write can slip
+    /// in between. If the metric is disabled nothing is recorded and an empty distribution is
+    /// returned.
+    pub async fn record_and_get(
+        &self,
+        sample: f64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> Distribution {
+        if !self.config.enabled() {
+            return Distribution::new(self.bucketer());
+        }
+        self.observed.fetch_add(1, Ordering::Relaxed);
+        let times = self.config().sampling_weight();
         self.inner
-            .record(entity_labels, sample, 1, metric_fields)
+            .record_and_get(entity_labels, sample, times, metric_fields)
+            .await
+            .unwrap_or_else(|| Distribution::new(self.bucketer()))
+    }
+
+    /// Records a duration in milliseconds, which is the conventional unit of latency metrics.
+    pub async fn record_duration(
+        &self,
+        duration: Duration,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) {
+        self.record_duration_as(duration, TimeUnit::Millis, entity_labels, metric_fields)
+            .await
+    }
+
+    /// Records a duration in the specified unit.
+    pub async fn record_duration_as(
+        &self,
+        duration: Duration,
+        unit: TimeUnit,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) {
+        self.record(unit.to_sample(duration), entity_labels, metric_fields)
             .await
     }
 
@@ -137,6 +300,7 @@ mod tests {
         bucketer::Bucketer, testing::test_entity_labels, testing::test_metric_fields,
     };
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_new() {
         let config = MetricConfig::default()
@@ -159,6 +323,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_config_overrides() {
         let config = MetricConfig::default();
@@ -171,6 +336,17 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_distribution_config() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        assert_eq!(
+            *metric.config(),
+            MetricConfig::distribution(Bucketer::default())
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_custom_config() {
         let config = MetricConfig::default()
@@ -187,6 +363,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_sample() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -211,6 +388,44 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_record_and_get() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(12.0, &entity_labels, &metric_fields).await;
+        let d = metric
+            .record_and_get(34.0, &entity_labels, &metric_fields)
+            .await;
+        let mut expected = Distribution::default();
+        expected.record(12.0);
+        expected.record(34.0);
+        assert_eq!(d, expected);
+        assert_eq!(d.count(), 2);
+        assert_eq!(d.sum(), 46.0);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_record_and_get_disabled() {
+        let metric = EventMetric::new(
+            "/foo/bar/distribution",
+            MetricConfig::default().set_enabled(false),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(
+            metric
+                .record_and_get(34.0, &entity_labels, &metric_fields)
+                .await
+                .is_empty()
+        );
+        assert!(metric.get(&entity_labels, &metric_fields).await.is_none());
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_sample_twice() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -237,6 +452,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_with_custom_bucketer() {
         let bucketer = Bucketer::custom(1.0, 2.0, 0.5, 20);
@@ -269,6 +485,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_two_samples() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -295,6 +512,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -314,6 +532,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -334,6 +553,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_after_deletion() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -360,6 +580,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing_entity() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -391,6 +612,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_entity() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -424,6 +646,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_another_entity() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -462,6 +685,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_record_after_entity_deletion() {
         let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
@@ -500,4 +724,132 @@ mod tests {
                 .is_none()
         );
     }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_record_duration() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric
+            .record_duration(Duration::from_millis(42), &entity_labels, &metric_fields)
+            .await;
+        let mut d = Distribution::default();
+        d.record(42.0);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_record_duration_as() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric
+            .record_duration_as(
+                Duration::from_millis(1500),
+                TimeUnit::Seconds,
+                &entity_labels,
+                &metric_fields,
+            )
+            .await;
+        metric
+            .record_duration_as(
+                Duration::from_millis(3),
+                TimeUnit::Micros,
+                &entity_labels,
+                &metric_fields,
+            )
+            .await;
+        let mut d = Distribution::default();
+        d.record(1.5);
+        d.record(3000.0);
+        assert_eq!(metric.get(&entity_labels, &metric_fields).await, Some(d));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_set_bucketer() {
+        let metric = EventMetric::new("/foo/bar/distribution/rebucketed", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(42.0, &entity_labels, &metric_fields).await;
+        let bucketer = Bucketer::fixed_width(10.0, 10);
+        metric.set_bucketer(bucketer).await.unwrap();
+        assert!(metric.bucketer().same_as(bucketer));
+        assert_eq!(metric.config().bucketer, Some(bucketer.into()));
+        assert!(metric.get(&entity_labels, &metric_fields).await.is_none());
+        metric.record(42.0, &entity_labels, &metric_fields).await;
+        let mut d = Distribution::new(bucketer.into());
+        d.record(42.0);
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert!(actual.bucketer().same_as(bucketer));
+        assert_eq!(actual, d);
+    }
+
+    #[tokio::test]
+    async fn test_set_bucketer_repeatedly() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let metric = EventMetric::new_with(exporter, "/foo/bar", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        metric.record(42.0, &entity_labels, &metric_fields).await;
+        let bucketer1 = Bucketer::fixed_width(10.0, 10);
+        let bucketer2 = Bucketer::fixed_width(20.0, 10);
+        for _ in 0..100 {
+            metric.set_bucketer(bucketer1).await.unwrap();
+            metric.set_bucketer(bucketer2).await.unwrap();
+        }
+        assert!(metric.bucketer().same_as(bucketer2));
+        assert_eq!(exporter.retired_configs(), 2);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_sampling_rate() {
+        let metric = EventMetric::new(
+            "/foo/bar/distribution/sampled",
+            MetricConfig::default().set_sampling_rate(10),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(metric.observed_samples(), 0);
+        metric.record(42.0, &entity_labels, &metric_fields).await;
+        assert_eq!(metric.observed_samples(), 1);
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual.count(), 10);
+        assert_eq!(actual.sum(), 420.0);
+        assert_eq!(actual.mean(), 42.0);
+        metric
+            .record_many(12.0, 2, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(metric.observed_samples(), 3);
+        let actual = metric.get(&entity_labels, &metric_fields).await.unwrap();
+        assert_eq!(actual.count(), 30);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_slo_compliance() {
+        let metric = EventMetric::new("/foo/bar/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            metric
+                .slo_compliance(100.0, &entity_labels, &metric_fields)
+                .await,
+            None
+        );
+        metric
+            .record_many(10.0, 10, &entity_labels, &metric_fields)
+            .await;
+        metric
+            .record_many(1000.0, 10, &entity_labels, &metric_fields)
+            .await;
+        let compliance = metric
+            .slo_compliance(100.0, &entity_labels, &metric_fields)
+            .await
+            .unwrap();
+        assert!((compliance - 0.5).abs() < 1e-9);
+    }
 }
@@ -1,14 +1,19 @@
-use crate::tsz::{FieldMap, bucketer::Bucketer, config::MetricConfig, distribution::Distribution};
+use crate::tsz::{
+    FieldMap, FieldValue, bucketer::Bucketer, config::MetricConfig, distribution::Distribution,
+};
 use crate::utils::{clock::Clock, clock::RealClock, f64::F64};
 use anyhow::{Result, anyhow};
 use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::future::Future;
-use std::ops::Deref;
+use std::ops::{Bound, Deref};
 use std::pin::Pin;
-use std::sync::{Arc, LazyLock, Mutex as SyncMutex, atomic::AtomicUsize, atomic::Ordering};
-use std::time::SystemTime;
+use std::sync::{
+    Arc, Mutex as SyncMutex, RwLock as SyncRwLock, atomic::AtomicBool, atomic::AtomicU64,
+    atomic::AtomicUsize, atomic::Ordering,
+};
+use std::time::{Duration, SystemTime};
 use tokio::sync::Mutex;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -20,18 +25,82 @@ pub enum Value {
     Dist(Distribution),
 }
 
+/// A distribution delta along with the times of the first and last events it contains, see
+/// `Exporter::add_timestamped_distribution_deltas`.
+pub type TimestampedDistribution = (Distribution, SystemTime, SystemTime);
+
+/// How `SnapshotView::aggregate` combines the values of a group of cells.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Adds up the values. This is the only aggregation supported for distributions.
+    Sum,
+    /// Takes the largest value.
+    Max,
+    /// Takes the arithmetic mean of the values. The result is always a float.
+    Mean,
+}
+
+/// Outcome of `Exporter::delete_entity`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeleteEntityStatus {
+    /// The entity was cleared and removed.
+    Removed,
+    /// The entity was cleared but it's still registered because a write was in flight, in which
+    /// case the write may still add a cell after the deletion.
+    Retained,
+    /// The entity didn't exist.
+    NotFound,
+}
+
 #[derive(Debug, Clone)]
-struct Cell {
+pub struct Cell {
     value: Value,
     start_timestamp: SystemTime,
     update_timestamp: SystemTime,
 }
 
+impl Cell {
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn start_timestamp(&self) -> SystemTime {
+        self.start_timestamp
+    }
+
+    pub fn update_timestamp(&self) -> SystemTime {
+        self.update_timestamp
+    }
+
+    /// Rough estimate of the memory taken by the cell, including its metric fields. Used to enforce
+    /// `Exporter::set_memory_budget`.
+    fn estimated_size(&self, metric_fields: &FieldMap) -> usize {
+        let fields_size: usize = metric_fields
+            .iter()
+            .map(|(key, value)| {
+                let value_size = match value {
+                    FieldValue::Str(value) => value.len(),
+                    _ => 0,
+                };
+                size_of::<(Arc<str>, FieldValue)>() + key.len() + value_size
+            })
+            .sum();
+        let value_size = match &self.value {
+            Value::Str(value) => value.len(),
+            Value::Dist(value) => value.num_finite_buckets() * size_of::<usize>(),
+            _ => 0,
+        };
+        size_of::<Cell>() + size_of::<FieldMap>() + fields_size + value_size
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Metric<'a> {
     name: String,
     config: &'a MetricConfig,
     cells: BTreeMap<FieldMap, Cell>,
+    /// Sum of the estimated sizes of `cells`, kept up to date by every write.
+    size: usize,
 }
 
 impl<'a> Metric<'a> {
@@ -40,9 +109,21 @@ impl<'a> Metric<'a> {
             name,
             config,
             cells: BTreeMap::default(),
+            size: 0,
         }
     }
 
+    fn cell_size(&self, metric_fields: &FieldMap) -> usize {
+        self.cells
+            .get(metric_fields)
+            .map_or(0, |cell| cell.estimated_size(metric_fields))
+    }
+
+    /// Updates `size` after the cell at `metric_fields` was written, given its size before.
+    fn resize_cell(&mut self, metric_fields: &FieldMap, before: usize) {
+        self.size = self.size + self.cell_size(metric_fields) - before;
+    }
+
     fn is_empty(&self) -> bool {
         self.cells.is_empty()
     }
@@ -55,6 +136,10 @@ impl<'a> Metric<'a> {
         }
     }
 
+    fn contains(&self, metric_fields: &FieldMap) -> bool {
+        self.cells.contains_key(metric_fields)
+    }
+
     fn get_bool(&self, metric_fields: &FieldMap) -> Option<bool> {
         if let Some(cell) = self.cells.get(metric_fields) {
             match cell.value {
@@ -111,9 +196,13 @@ impl<'a> Metric<'a> {
     }
 
     fn set_value(&mut self, value: Value, metric_fields: &FieldMap, now: SystemTime) {
+        let before = self.cell_size(metric_fields);
         if let Some(cell) = self.cells.get_mut(metric_fields) {
             cell.value = value;
             cell.update_timestamp = now;
+            if self.config.reset_start_on_update && !self.config.cumulative {
+                cell.start_timestamp = now;
+            }
         } else {
             self.cells.insert(
                 metric_fields.clone(),
@@ -124,9 +213,17 @@ impl<'a> Metric<'a> {
                 },
             );
         };
+        self.resize_cell(metric_fields, before);
+    }
+
+    fn set_values(&mut self, values: BTreeMap<FieldMap, Value>, now: SystemTime) {
+        for (metric_fields, value) in values {
+            self.set_value(value, &metric_fields, now);
+        }
     }
 
     fn add_to_int(&mut self, delta: i64, metric_fields: &FieldMap, now: SystemTime) {
+        let before = self.cell_size(metric_fields);
         if let Some(cell) = self.cells.get_mut(metric_fields) {
             match &mut cell.value {
                 Value::Int(value) => *value += delta,
@@ -143,26 +240,12 @@ impl<'a> Metric<'a> {
                 },
             );
         };
+        self.resize_cell(metric_fields, before);
     }
 
     fn add_int_deltas(&mut self, deltas: BTreeMap<FieldMap, i64>, now: SystemTime) {
         for (metric_fields, delta) in deltas {
-            if let Some(cell) = self.cells.get_mut(&metric_fields) {
-                match &mut cell.value {
-                    Value::Int(value) => *value += delta,
-                    _ => panic!(),
-                };
-                cell.update_timestamp = now;
-            } else {
-                self.cells.insert(
-                    metric_fields,
-                    Cell {
-                        value: Value::Int(delta),
-                        start_timestamp: now,
-                        update_timestamp: now,
-                    },
-                );
-            };
+            self.add_to_int(delta, &metric_fields, now);
         }
     }
 
@@ -173,6 +256,7 @@ impl<'a> Metric<'a> {
         metric_fields: &FieldMap,
         now: SystemTime,
     ) {
+        let before = self.cell_size(metric_fields);
         if let Some(cell) = self.cells.get_mut(metric_fields) {
             match &mut cell.value {
                 Value::Dist(value) => value.record_many(sample, times),
@@ -195,6 +279,7 @@ impl<'a> Metric<'a> {
                 },
             );
         };
+        self.resize_cell(metric_fields, before);
     }
 
     fn add_distribution_deltas(
@@ -203,6 +288,7 @@ impl<'a> Metric<'a> {
         now: SystemTime,
     ) {
         for (metric_fields, delta) in deltas {
+            let before = self.cell_size(&metric_fields);
             if let Some(cell) = self.cells.get_mut(&metric_fields) {
                 match &mut cell.value {
                     Value::Dist(value) => value.add(&delta).unwrap(),
@@ -211,7 +297,7 @@ impl<'a> Metric<'a> {
                 cell.update_timestamp = now;
             } else {
                 self.cells.insert(
-                    metric_fields,
+                    metric_fields.clone(),
                     Cell {
                         value: Value::Dist(delta),
                         start_timestamp: now,
@@ -219,11 +305,67 @@ impl<'a> Metric<'a> {
                     },
                 );
             }
+            self.resize_cell(&metric_fields, before);
+        }
+    }
+
+    fn add_timestamped_distribution_deltas(
+        &mut self,
+        deltas: BTreeMap<FieldMap, TimestampedDistribution>,
+    ) {
+        for (metric_fields, (delta, first, last)) in deltas {
+            let before = self.cell_size(&metric_fields);
+            if let Some(cell) = self.cells.get_mut(&metric_fields) {
+                match &mut cell.value {
+                    Value::Dist(value) => value.add(&delta).unwrap(),
+                    _ => panic!(),
+                };
+                cell.update_timestamp = std::cmp::max(cell.update_timestamp, last);
+            } else {
+                self.cells.insert(
+                    metric_fields.clone(),
+                    Cell {
+                        value: Value::Dist(delta),
+                        start_timestamp: first,
+                        update_timestamp: last,
+                    },
+                );
+            }
+            self.resize_cell(&metric_fields, before);
+        }
+    }
+
+    fn clear_distribution(&mut self, metric_fields: &FieldMap, now: SystemTime) -> bool {
+        let before = self.cell_size(metric_fields);
+        match self.cells.get_mut(metric_fields) {
+            Some(Cell {
+                value: Value::Dist(value),
+                update_timestamp,
+                ..
+            }) => {
+                value.clear();
+                *update_timestamp = now;
+                self.resize_cell(metric_fields, before);
+                true
+            }
+            _ => false,
         }
     }
 
+    fn insert_cell(&mut self, metric_fields: FieldMap, cell: Cell) {
+        let before = self.cell_size(&metric_fields);
+        self.size = self.size + cell.estimated_size(&metric_fields) - before;
+        self.cells.insert(metric_fields, cell);
+    }
+
+    fn remove_cell(&mut self, metric_fields: &FieldMap) -> Option<Cell> {
+        let cell = self.cells.remove(metric_fields)?;
+        self.size -= cell.estimated_size(metric_fields);
+        Some(cell)
+    }
+
     fn delete_value(&mut self, metric_fields: &FieldMap) -> Option<Value> {
-        self.cells.remove(metric_fields).map(|cell| cell.value)
+        self.remove_cell(metric_fields).map(|cell| cell.value)
     }
 }
 
@@ -256,10 +398,33 @@ impl<'a> Borrow<str> for Metric<'a> {
 trait EntityManager: Debug + Send + Sync {
     fn get_metric_config_internal<'a>(&'a self, metric_name: &str) -> &'a MetricConfig;
 
+    fn auto_remove_empty_entities(&self) -> bool;
+
+    /// Records that a cell of the specified metric of the specified entity was written.
+    fn mark_dirty(&self, entity_labels: &FieldMap, metric_name: &str);
+
+    /// Accounts for a write that changed the estimated size of the cells of an entity from `before`
+    /// to `after` bytes.
+    fn update_memory_usage(&self, before: usize, after: usize);
+
+    /// Removes the specified entity unless it's pinned.
     fn remove_entity<'a>(
         &'a self,
         entity_labels: &'a FieldMap,
-    ) -> Pin<Box<dyn Future<Output = ()> + 'a>>;
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+    /// Like `remove_entity`, but for entities left empty by the deletion of their cells rather than
+    /// deleted explicitly, so it's a no-op if automatic removal of empty entities is disabled.
+    fn remove_empty_entity<'a>(
+        &'a self,
+        entity_labels: &'a FieldMap,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            if self.auto_remove_empty_entities() {
+                self.remove_entity(entity_labels).await;
+            }
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -292,6 +457,13 @@ impl<'a> Entity<'a> {
         self.pin_count.fetch_sub(1, Ordering::AcqRel) == 1
     }
 
+    async fn contains_cell(&self, metric_name: &str, metric_fields: &FieldMap) -> bool {
+        let metrics = self.metrics.lock().await;
+        metrics
+            .get(metric_name)
+            .is_some_and(|metric| metric.contains(metric_fields))
+    }
+
     async fn get_value(&self, metric_name: &str, metric_fields: &FieldMap) -> Option<Value> {
         let metrics = self.metrics.lock().await;
         if let Some(metric) = metrics.get(metric_name) {
@@ -366,8 +538,127 @@ impl<'a> Entity<'a> {
                 self.parent.get_metric_config_internal(metric_name),
             )
         };
+        let size = metric.size;
+        metric.set_value(value, metric_fields, now);
+        self.parent.update_memory_usage(size, metric.size);
+        metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+    }
+
+    async fn set_values(
+        &self,
+        metric_name: &str,
+        values: BTreeMap<FieldMap, Value>,
+        now: SystemTime,
+    ) {
+        let mut metrics = self.metrics.lock().await;
+        let mut metric = if let Some(metric) = metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.parent.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
+        metric.set_values(values, now);
+        self.parent.update_memory_usage(size, metric.size);
+        metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+    }
+
+    async fn update_value(
+        &self,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        now: SystemTime,
+        f: impl FnOnce(Option<Value>) -> Result<Value>,
+    ) -> Result<()> {
+        let mut metrics = self.metrics.lock().await;
+        // `f` runs before the metric is taken out of the set, so that the other cells of the
+        // metric aren't lost if it fails or panics.
+        let current = metrics
+            .get(metric_name)
+            .and_then(|metric| metric.get_value(metric_fields));
+        let value = f(current)?;
+        let mut metric = if let Some(metric) = metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.parent.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
         metric.set_value(value, metric_fields, now);
+        self.parent.update_memory_usage(size, metric.size);
+        metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+        Ok(())
+    }
+
+    async fn get_or_insert_value(
+        &self,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        now: SystemTime,
+        init: impl FnOnce() -> Value,
+    ) -> Value {
+        let mut metrics = self.metrics.lock().await;
+        if let Some(value) = metrics
+            .get(metric_name)
+            .and_then(|metric| metric.get_value(metric_fields))
+        {
+            return value;
+        }
+        // `init` runs before the metric is taken out of the set, so that the other cells of the
+        // metric aren't lost if it panics.
+        let value = init();
+        let mut metric = if let Some(metric) = metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.parent.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
+        metric.set_value(value.clone(), metric_fields, now);
+        self.parent.update_memory_usage(size, metric.size);
         metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+        value
+    }
+
+    async fn compare_and_set_value(
+        &self,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        expected: Option<&Value>,
+        value: Value,
+        now: SystemTime,
+    ) -> bool {
+        let mut metrics = self.metrics.lock().await;
+        let mut metric = if let Some(metric) = metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.parent.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
+        let current = metric.cells.get(metric_fields).map(|cell| &cell.value);
+        let applied = current == expected;
+        if applied {
+            metric.set_value(value, metric_fields, now);
+            self.parent.mark_dirty(&self.labels, metric_name);
+        }
+        self.parent.update_memory_usage(size, metric.size);
+        if !metric.is_empty() {
+            metrics.insert(metric);
+        }
+        applied
     }
 
     async fn add_to_int(
@@ -386,8 +677,11 @@ impl<'a> Entity<'a> {
                 self.parent.get_metric_config_internal(metric_name),
             )
         };
+        let size = metric.size;
         metric.add_to_int(delta, metric_fields, now);
+        self.parent.update_memory_usage(size, metric.size);
         metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
     }
 
     async fn add_int_deltas(
@@ -405,18 +699,24 @@ impl<'a> Entity<'a> {
                 self.parent.get_metric_config_internal(metric_name),
             )
         };
+        let size = metric.size;
         metric.add_int_deltas(deltas, now);
+        self.parent.update_memory_usage(size, metric.size);
         metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
     }
 
-    async fn add_to_distribution(
+    /// Records `sample` `times` times and returns the result of `inspect` on the resulting
+    /// distribution, which runs with the entity still locked.
+    async fn add_to_distribution<R>(
         &self,
         metric_name: &str,
         sample: f64,
         times: usize,
         metric_fields: &FieldMap,
         now: SystemTime,
-    ) {
+        inspect: impl FnOnce(&Distribution) -> R,
+    ) -> R {
         let mut metrics = self.metrics.lock().await;
         let mut metric = if let Some(metric) = metrics.take(metric_name) {
             metric
@@ -426,8 +726,16 @@ impl<'a> Entity<'a> {
                 self.parent.get_metric_config_internal(metric_name),
             )
         };
+        let size = metric.size;
         metric.add_to_distribution(sample, times, metric_fields, now);
+        let result = match &metric.cells[metric_fields].value {
+            Value::Dist(value) => inspect(value),
+            _ => panic!(),
+        };
+        self.parent.update_memory_usage(size, metric.size);
         metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+        result
     }
 
     async fn add_distribution_deltas(
@@ -445,14 +753,61 @@ impl<'a> Entity<'a> {
                 self.parent.get_metric_config_internal(metric_name),
             )
         };
+        let size = metric.size;
         metric.add_distribution_deltas(deltas, now);
+        self.parent.update_memory_usage(size, metric.size);
+        metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+    }
+
+    async fn add_timestamped_distribution_deltas(
+        &self,
+        metric_name: &str,
+        deltas: BTreeMap<FieldMap, TimestampedDistribution>,
+    ) {
+        let mut metrics = self.metrics.lock().await;
+        let mut metric = if let Some(metric) = metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.parent.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
+        metric.add_timestamped_distribution_deltas(deltas);
+        self.parent.update_memory_usage(size, metric.size);
         metrics.insert(metric);
+        self.parent.mark_dirty(&self.labels, metric_name);
+    }
+
+    async fn clear_distribution(
+        &self,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        now: SystemTime,
+    ) -> bool {
+        let mut metrics = self.metrics.lock().await;
+        if let Some(mut metric) = metrics.take(metric_name) {
+            let size = metric.size;
+            let result = metric.clear_distribution(metric_fields, now);
+            self.parent.update_memory_usage(size, metric.size);
+            metrics.insert(metric);
+            if result {
+                self.parent.mark_dirty(&self.labels, metric_name);
+            }
+            result
+        } else {
+            false
+        }
     }
 
     async fn delete_value(&self, metric_name: &str, metric_fields: &FieldMap) -> Option<Value> {
         let mut metrics = self.metrics.lock().await;
         let result = if let Some(mut metric) = metrics.take(metric_name) {
+            let size = metric.size;
             let result = metric.delete_value(metric_fields);
+            self.parent.update_memory_usage(size, metric.size);
             if !metric.is_empty() {
                 metrics.insert(metric);
             }
@@ -461,25 +816,114 @@ impl<'a> Entity<'a> {
             None
         };
         if metrics.is_empty() && !self.is_pinned() {
-            self.parent.remove_entity(&self.labels).await;
+            self.parent.remove_empty_entity(&self.labels).await;
         }
         result
     }
 
-    async fn delete_metric(&self, metric_name: &str) -> bool {
+    /// Removes the specified metric from the entity. Returns the number of cells removed, or `None`
+    /// if the entity didn't have the metric.
+    async fn delete_metric(&self, metric_name: &str) -> Option<usize> {
         let mut metrics = self.metrics.lock().await;
-        let result = metrics.remove(metric_name);
+        let result = metrics.take(metric_name).map(|metric| {
+            self.parent.update_memory_usage(metric.size, 0);
+            metric.cells.len()
+        });
         if metrics.is_empty() && !self.is_pinned() {
-            self.parent.remove_entity(&self.labels).await;
+            self.parent.remove_empty_entity(&self.labels).await;
         }
         result
     }
 
-    async fn clear(&self) {
+    /// Removes the specified metrics from the entity under a single lock, then removes the entity
+    /// once if it became empty. Returns the number of metrics removed.
+    async fn delete_metrics(&self, metric_names: &[&str]) -> usize {
+        let mut metrics = self.metrics.lock().await;
+        let mut count = 0;
+        for metric_name in metric_names {
+            if let Some(metric) = metrics.take(*metric_name) {
+                self.parent.update_memory_usage(metric.size, 0);
+                count += 1;
+            }
+        }
+        if metrics.is_empty() && !self.is_pinned() {
+            self.parent.remove_empty_entity(&self.labels).await;
+        }
+        count
+    }
+
+    async fn for_each_cell(
+        &self,
+        metric_name: &str,
+        f: &mut impl FnMut(&FieldMap, &FieldMap, &Value, SystemTime, SystemTime),
+    ) {
+        let metrics = self.metrics.lock().await;
+        if let Some(metric) = metrics.get(metric_name) {
+            for (metric_fields, cell) in &metric.cells {
+                f(
+                    &self.labels,
+                    metric_fields,
+                    &cell.value,
+                    cell.start_timestamp,
+                    cell.update_timestamp,
+                );
+            }
+        }
+    }
+
+    async fn metric_names(&self) -> Vec<String> {
+        let metrics = self.metrics.lock().await;
+        metrics
+            .iter()
+            .filter(|metric| !metric.is_empty())
+            .map(|metric| metric.name.clone())
+            .collect()
+    }
+
+    async fn metric_fields(&self, metric_name: &str) -> Vec<FieldMap> {
+        let metrics = self.metrics.lock().await;
+        metrics
+            .get(metric_name)
+            .map_or(vec![], |metric| metric.cells.keys().cloned().collect())
+    }
+
+    async fn cell_count(&self) -> usize {
+        let metrics = self.metrics.lock().await;
+        metrics.iter().map(|metric| metric.cells.len()).sum()
+    }
+
+    async fn metric_estimated_size(&self, metric_name: &str) -> usize {
+        let metrics = self.metrics.lock().await;
+        metrics.get(metric_name).map_or(0, |metric| metric.size)
+    }
+
+    /// Copies all cells of the metrics of the entity that pass `predicate`. The metrics lock is held
+    /// for the whole copy, and every write updates its cells under that same lock, so the copy
+    /// never observes a write half-applied.
+    async fn snapshot(
+        &self,
+        predicate: &impl Fn(&str) -> bool,
+    ) -> BTreeMap<String, BTreeMap<FieldMap, Cell>> {
+        let metrics = self.metrics.lock().await;
+        metrics
+            .iter()
+            .filter(|metric| predicate(&metric.name))
+            .map(|metric| (metric.name.clone(), metric.cells.clone()))
+            .collect()
+    }
+
+    /// Removes all metrics from the entity, then removes the entity unless it's pinned. Returns
+    /// whether the entity was removed.
+    async fn clear(&self) -> bool {
         let mut metrics = self.metrics.lock().await;
+        let size = metrics.iter().map(|metric| metric.size).sum();
+        self.parent.update_memory_usage(size, 0);
         metrics.clear();
-        if !self.is_pinned() {
+        if self.is_pinned() {
+            false
+        } else {
             self.parent.remove_entity(&self.labels).await;
+            true
         }
     }
 }
@@ -510,12 +954,15 @@ impl<'a> Borrow<FieldMap> for Arc<Entity<'a>> {
     }
 }
 
+/// Keeps an entity pinned for the lifetime of the handle. The pin is taken in `new` and released on
+/// drop, so every `unpin` is balanced by a `pin` and `pin_count` can't underflow.
 struct EntityPin<'a> {
     entity: Arc<Entity<'a>>,
 }
 
 impl<'a> EntityPin<'a> {
     fn new(entity: Arc<Entity<'a>>) -> Self {
+        entity.pin();
         Self { entity }
     }
 }
@@ -534,45 +981,518 @@ impl<'a> Drop for EntityPin<'a> {
     }
 }
 
-#[derive(Debug)]
-pub struct Exporter<'a> {
-    clock: Arc<dyn Clock>,
-    metric_configs: SyncMutex<BTreeMap<String, Pin<Box<MetricConfig>>>>,
-    entities: Mutex<BTreeSet<Arc<Entity<'a>>>>,
+/// A handle to the metrics of an entity within `Exporter::transaction`. The entity stays locked for
+/// the lifetime of the handle, so all reads see a consistent view and no other write to the entity
+/// can interleave with the ones made through the handle.
+pub struct EntityTxn<'t, 'a> {
+    exporter: &'a Exporter<'a>,
+    entity_labels: &'t FieldMap,
+    metrics: &'t mut BTreeSet<Metric<'a>>,
+    now: SystemTime,
 }
 
-impl<'a> Exporter<'a> {
-    pub fn define_metric(&self, metric_name: &str, config: MetricConfig) -> Result<()> {
-        let mut configs = self.metric_configs.lock().unwrap();
-        if configs.contains_key(metric_name) {
-            return Err(anyhow!("metric {} is already defined", metric_name));
+impl<'t, 'a> EntityTxn<'t, 'a> {
+    /// Applies `f` to the specified metric, creating it if needed and dropping it if it ends up
+    /// with no cells.
+    fn update_metric<R>(&mut self, metric_name: &str, f: impl FnOnce(&mut Metric<'a>) -> R) -> R {
+        let mut metric = if let Some(metric) = self.metrics.take(metric_name) {
+            metric
+        } else {
+            Metric::new(
+                metric_name.into(),
+                self.exporter.get_metric_config_internal(metric_name),
+            )
+        };
+        let size = metric.size;
+        let result = f(&mut metric);
+        self.exporter.update_memory_usage(size, metric.size);
+        if !metric.is_empty() {
+            self.metrics.insert(metric);
         }
-        configs.insert(metric_name.into(), Box::pin(config));
-        Ok(())
+        result
     }
 
-    pub fn define_metric_redundant(&self, metric_name: &str, config: MetricConfig) {
-        let mut configs = self.metric_configs.lock().unwrap();
-        if !configs.contains_key(metric_name) {
-            configs.insert(metric_name.into(), Box::pin(config));
-        }
+    pub fn get_value(&self, metric_name: &str, metric_fields: &FieldMap) -> Option<Value> {
+        self.metrics
+            .get(metric_name)
+            .and_then(|metric| metric.get_value(metric_fields))
     }
 
-    pub fn get_metric_config(&self, metric_name: &str) -> Option<&'static MetricConfig> {
-        let configs = self.metric_configs.lock().unwrap();
-        match configs.get(metric_name) {
-            Some(config) => {
-                let config = config.as_ref().get_ref();
-                unsafe { std::mem::transmute(config) }
-            }
-            None => None,
-        }
+    pub fn get_int(&self, metric_name: &str, metric_fields: &FieldMap) -> Option<i64> {
+        self.metrics
+            .get(metric_name)
+            .and_then(|metric| metric.get_int(metric_fields))
     }
 
-    async fn get_ephemeral_entity(&self, labels: &FieldMap) -> Option<Arc<Entity<'a>>> {
-        let entities = self.entities.lock().await;
-        entities.get(labels).cloned()
-    }
+    pub fn set_value(
+        &mut self,
+        metric_name: &str,
+        value: Value,
+        metric_fields: &FieldMap,
+    ) -> Result<()> {
+        self.exporter
+            .ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.exporter.check_fields(metric_name, metric_fields)?;
+        let now = self.now;
+        self.update_metric(metric_name, |metric| {
+            metric.set_value(value, metric_fields, now)
+        });
+        self.exporter.mark_dirty(self.entity_labels, metric_name);
+        Ok(())
+    }
+
+    pub fn add_to_int(
+        &mut self,
+        metric_name: &str,
+        delta: i64,
+        metric_fields: &FieldMap,
+    ) -> Result<()> {
+        self.exporter
+            .ensure_defined(metric_name, MetricConfig::counter())?;
+        self.exporter.check_fields(metric_name, metric_fields)?;
+        let now = self.now;
+        self.update_metric(metric_name, |metric| {
+            metric.add_to_int(delta, metric_fields, now)
+        });
+        self.exporter.mark_dirty(self.entity_labels, metric_name);
+        Ok(())
+    }
+
+    pub fn delete_value(&mut self, metric_name: &str, metric_fields: &FieldMap) -> Option<Value> {
+        if !self.metrics.contains(metric_name) {
+            return None;
+        }
+        self.update_metric(metric_name, |metric| metric.delete_value(metric_fields))
+    }
+}
+
+/// An immutable copy of the cells of an `Exporter`, organized by entity labels, metric name, and
+/// metric fields.
+///
+/// Iteration order (and therefore the `Debug` output) is fully deterministic: entities are sorted
+/// by labels, metrics by name, and cells by metric fields. `FieldMap`s compare their entries in key
+/// order, and values of different types are ordered by type (bools, then ints, then strings), so
+/// the order doesn't depend on how or when the cells were written.
+#[derive(Debug, Clone)]
+pub struct SnapshotView {
+    timestamp: SystemTime,
+    entities: BTreeMap<FieldMap, BTreeMap<String, BTreeMap<FieldMap, Cell>>>,
+}
+
+impl SnapshotView {
+    /// Returns the time at which the snapshot was taken.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn get_cell(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+    ) -> Option<&Cell> {
+        self.entities
+            .get(entity_labels)?
+            .get(metric_name)?
+            .get(metric_fields)
+    }
+
+    pub fn get_value(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+    ) -> Option<&Value> {
+        self.get_cell(entity_labels, metric_name, metric_fields)
+            .map(|cell| cell.value())
+    }
+
+    /// Iterates over all cells as (entity labels, metric name, metric fields, cell) tuples.
+    pub fn iter(&self) -> impl Iterator<Item = (&FieldMap, &str, &FieldMap, &Cell)> {
+        self.entities.iter().flat_map(|(entity_labels, metrics)| {
+            metrics.iter().flat_map(move |(metric_name, cells)| {
+                cells.iter().map(move |(metric_fields, cell)| {
+                    (entity_labels, metric_name.as_str(), metric_fields, cell)
+                })
+            })
+        })
+    }
+
+    /// Aggregates the cells of `metric_name` across all entities, producing one value per group.
+    ///
+    /// Cells are grouped by the values of the `group_by` keys, which are looked up in the metric
+    /// fields first and then in the entity labels. Keys missing from both are left out of the group
+    /// key, so grouping by nothing aggregates all cells into a single value.
+    ///
+    /// Int and float metrics support all aggregations. Distributions only support `Sum`, and the
+    /// distributions of each group must have the same bucketer. Fails for bool and string metrics.
+    pub fn aggregate(
+        &self,
+        metric_name: &str,
+        group_by: &[&str],
+        aggregation: Aggregation,
+    ) -> Result<BTreeMap<FieldMap, Value>> {
+        let mut groups: BTreeMap<FieldMap, Vec<&Value>> = BTreeMap::default();
+        for (entity_labels, metrics) in &self.entities {
+            let Some(cells) = metrics.get(metric_name) else {
+                continue;
+            };
+            for (metric_fields, cell) in cells {
+                let key = FieldMap::from_entries(group_by.iter().filter_map(|key| {
+                    metric_fields
+                        .get(key)
+                        .or_else(|| entity_labels.get(key))
+                        .map(|value| ((*key).into(), value.clone()))
+                }));
+                groups.entry(key).or_default().push(&cell.value);
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(key, values)| Ok((key, Self::aggregate_values(&values, aggregation)?)))
+            .collect()
+    }
+
+    fn aggregate_values(values: &[&Value], aggregation: Aggregation) -> Result<Value> {
+        match (values[0], aggregation) {
+            (Value::Int(_), _) => {
+                let values: Vec<i64> = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Int(value) => Ok(*value),
+                        _ => Err(anyhow!("mixed value types in aggregation")),
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(match aggregation {
+                    Aggregation::Sum => Value::Int(values.iter().sum()),
+                    Aggregation::Max => Value::Int(*values.iter().max().unwrap()),
+                    Aggregation::Mean => Value::Float(
+                        (values.iter().map(|value| *value as f64).sum::<f64>()
+                            / values.len() as f64)
+                            .into(),
+                    ),
+                })
+            }
+            (Value::Float(_), _) => {
+                let values: Vec<F64> = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Float(value) => Ok(*value),
+                        _ => Err(anyhow!("mixed value types in aggregation")),
+                    })
+                    .collect::<Result<_>>()?;
+                let sum: f64 = values.iter().map(|value| value.value).sum();
+                Ok(Value::Float(match aggregation {
+                    Aggregation::Sum => sum.into(),
+                    Aggregation::Max => *values.iter().max().unwrap(),
+                    Aggregation::Mean => (sum / values.len() as f64).into(),
+                }))
+            }
+            (Value::Dist(first), Aggregation::Sum) => {
+                let mut result = first.clone();
+                for value in &values[1..] {
+                    match value {
+                        Value::Dist(value) => result.add(value)?,
+                        _ => return Err(anyhow!("mixed value types in aggregation")),
+                    }
+                }
+                Ok(Value::Dist(result))
+            }
+            (Value::Dist(_), _) => Err(anyhow!(
+                "{:?} aggregation is not supported for distributions",
+                aggregation
+            )),
+            (value, _) => Err(anyhow!("cannot aggregate {:?}", value)),
+        }
+    }
+}
+
+impl Default for SnapshotView {
+    fn default() -> Self {
+        Self {
+            timestamp: SystemTime::UNIX_EPOCH,
+            entities: BTreeMap::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Exporter<'a> {
+    clock: Arc<dyn Clock>,
+    metric_configs: SyncMutex<BTreeMap<String, Pin<Box<MetricConfig>>>>,
+    /// Configs replaced by `redefine_metric` or removed by `undefine_metric`. They are kept alive
+    /// for as long as the exporter because concurrent writers and existing `Metric` objects may
+    /// still be referring to them, and are reused when a metric is redefined with an equal config.
+    retired_configs: SyncMutex<Vec<Pin<Box<MetricConfig>>>>,
+    entities: Mutex<BTreeSet<Arc<Entity<'a>>>>,
+    snapshot: SyncRwLock<Arc<SnapshotView>>,
+    memory_budget: AtomicUsize,
+    memory_usage: AtomicUsize,
+    evicted_cells: AtomicU64,
+    dropped_samples: SyncMutex<BTreeMap<String, u64>>,
+    strict: AtomicBool,
+    auto_remove_empty_entities: AtomicBool,
+    max_timestamp_skew: SyncMutex<Duration>,
+    /// The values of the delta-mode cells as of the last `export_snapshot`, keyed by entity labels,
+    /// metric name, and metric fields.
+    last_exported: SyncMutex<BTreeMap<(FieldMap, String, FieldMap), Value>>,
+    dirty_tracking: AtomicBool,
+    /// The (entity labels, metric name) pairs written since the last `snapshot_dirty`. Only kept
+    /// up to date while `dirty_tracking` is on.
+    dirty: SyncMutex<BTreeSet<(FieldMap, String)>>,
+}
+
+impl<'a> Exporter<'a> {
+    /// See `set_max_timestamp_skew`.
+    pub const DEFAULT_MAX_TIMESTAMP_SKEW: Duration = Duration::from_secs(300);
+
+    /// Creates an exporter that reads the time from `clock` rather than from the system clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the clock used to timestamp the cells.
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Defines a metric with the specified config. Disabled metrics are not registered, so this is
+    /// a no-op for them.
+    pub fn define_metric(&self, metric_name: &str, config: MetricConfig) -> Result<()> {
+        if !config.enabled() {
+            return Ok(());
+        }
+        let mut configs = self.metric_configs.lock().unwrap();
+        if configs.contains_key(metric_name) {
+            return Err(anyhow!("metric {} is already defined", metric_name));
+        }
+        configs.insert(metric_name.into(), Box::pin(config));
+        Ok(())
+    }
+
+    pub fn define_metric_redundant(&self, metric_name: &str, config: MetricConfig) {
+        if !config.enabled() {
+            return;
+        }
+        let mut configs = self.metric_configs.lock().unwrap();
+        if !configs.contains_key(metric_name) {
+            configs.insert(metric_name.into(), Box::pin(config));
+        }
+    }
+
+    /// Replaces the config of an already defined metric and deletes all of its cells.
+    ///
+    /// The existing cells were created under the old config (e.g. distributions carry the old
+    /// bucketer), so rather than attempting to convert them we start over: all cells written after
+    /// this call use the new config. Fails if the metric is not defined.
+    ///
+    /// The old config is retired rather than dropped because concurrent writers may still be
+    /// referring to it (see `retired_configs`). Retired configs equal to `config` are reused, so
+    /// switching back and forth between a few configs doesn't grow the exporter.
+    pub async fn redefine_metric(&self, metric_name: &str, config: MetricConfig) -> Result<()> {
+        {
+            let mut configs = self.metric_configs.lock().unwrap();
+            match configs.get_mut(metric_name) {
+                Some(current) => {
+                    if **current != config {
+                        let mut retired = self.retired_configs.lock().unwrap();
+                        let new_config = match retired.iter().position(|old| **old == config) {
+                            Some(index) => retired.swap_remove(index),
+                            None => Box::pin(config),
+                        };
+                        retired.push(std::mem::replace(current, new_config));
+                    }
+                }
+                None => return Err(anyhow!("metric {} is not defined", metric_name)),
+            }
+        }
+        self.delete_metric(metric_name).await;
+        Ok(())
+    }
+
+    /// Removes the definition of a metric along with all of its cells. Returns false if the metric
+    /// is not defined.
+    ///
+    /// After this call the metric must be defined again before being written to. As with
+    /// `redefine_metric`, the old config is retired rather than dropped because concurrent writers
+    /// and existing `Metric` objects may still be referring to it.
+    pub async fn undefine_metric(&self, metric_name: &str) -> bool {
+        let old_config = match self.metric_configs.lock().unwrap().remove(metric_name) {
+            Some(old_config) => old_config,
+            None => return false,
+        };
+        self.retired_configs.lock().unwrap().push(old_config);
+        self.delete_metric(metric_name).await;
+        true
+    }
+
+    /// Returns the number of configs retired by `redefine_metric` and `undefine_metric` that are
+    /// kept alive until the exporter is dropped.
+    pub fn retired_configs(&self) -> usize {
+        self.retired_configs.lock().unwrap().len()
+    }
+
+    pub fn get_metric_config(&self, metric_name: &str) -> Option<&'static MetricConfig> {
+        let configs = self.metric_configs.lock().unwrap();
+        match configs.get(metric_name) {
+            Some(config) => {
+                let config = config.as_ref().get_ref();
+                unsafe { std::mem::transmute(config) }
+            }
+            None => None,
+        }
+    }
+
+    /// Returns the sorted names of the defined metrics starting with `prefix`, e.g. `/rpc/` for all
+    /// RPC metrics. Only the matching range of the config map is visited.
+    pub fn metric_names_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let configs = self.metric_configs.lock().unwrap();
+        configs
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(metric_name, _)| metric_name)
+            .take_while(|metric_name| metric_name.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Makes writes to undefined metrics fail rather than defining the metrics on the fly (see
+    /// `ensure_defined`). Off by default.
+    pub fn set_strict(&self, strict: bool) {
+        self.strict.store(strict, Ordering::Relaxed);
+    }
+
+    /// Sets how far past the current time the explicit timestamps passed to `set_value_at` may be.
+    /// Writes with later timestamps are rejected. The default is `DEFAULT_MAX_TIMESTAMP_SKEW`.
+    pub fn set_max_timestamp_skew(&self, value: Duration) {
+        *self.max_timestamp_skew.lock().unwrap() = value;
+    }
+
+    /// Controls whether entities are removed as soon as their last cell is deleted. When off, empty
+    /// entities are kept so that their labels remain enumerable (e.g. to export them as zero-valued
+    /// rows) until they're deleted explicitly with `delete_entity`. On by default.
+    pub fn set_auto_remove_empty_entities(&self, value: bool) {
+        self.auto_remove_empty_entities
+            .store(value, Ordering::Relaxed);
+    }
+
+    /// Controls whether writes record the metrics they touch for `snapshot_dirty`. Recording takes a
+    /// lock shared by all writes, so it's off by default and should only be turned on by exporters
+    /// that call `snapshot_dirty` regularly. Turning it off forgets the recorded metrics.
+    pub fn set_dirty_tracking(&self, value: bool) {
+        self.dirty_tracking.store(value, Ordering::Relaxed);
+        if !value {
+            self.dirty.lock().unwrap().clear();
+        }
+    }
+
+    /// Checks that a metric is defined before writing to it. In strict mode undefined metrics
+    /// result in an error, otherwise they're defined with `default_config`, which depends on the
+    /// kind of write (e.g. a counter config for `add_to_int`).
+    fn ensure_defined(&self, metric_name: &str, default_config: MetricConfig) -> Result<()> {
+        self.ensure_defined_for_cells(metric_name, default_config, 1)
+    }
+
+    /// Like `ensure_defined`, for a batch write of `cells` cells. A rejected batch counts as
+    /// `cells` dropped samples.
+    fn ensure_defined_for_cells(
+        &self,
+        metric_name: &str,
+        default_config: MetricConfig,
+        cells: usize,
+    ) -> Result<()> {
+        if self.get_metric_config(metric_name).is_some() {
+            Ok(())
+        } else if self.strict.load(Ordering::Relaxed) {
+            self.record_dropped_samples(metric_name, cells as u64);
+            Err(anyhow!("metric {} is not defined", metric_name))
+        } else {
+            self.define_metric_redundant(metric_name, default_config);
+            Ok(())
+        }
+    }
+
+    /// Checks `metric_fields` against the field schema declared for the metric, if any. Failures
+    /// are counted in `dropped_samples`.
+    fn check_fields(&self, metric_name: &str, metric_fields: &FieldMap) -> Result<()> {
+        let schema = self
+            .get_metric_config(metric_name)
+            .and_then(|config| config.fields);
+        match schema {
+            Some(schema) if !metric_fields.matches_schema(schema) => {
+                self.record_dropped_samples(metric_name, 1);
+                Err(anyhow!(
+                    "fields {:?} don't match the schema {:?} of metric {}",
+                    metric_fields,
+                    schema,
+                    metric_name
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the number of writes (or individual deltas of batched writes) of the specified
+    /// metric that were dropped so far, plus the number of its cells evicted to enforce the memory
+    /// budget. A non-zero value means that data is being lost.
+    ///
+    /// The exporter counts writes rejected because of strict mode or field schemas, while metric
+    /// wrappers report the data they reject themselves (e.g. negative counter deltas) with
+    /// `record_dropped_samples`.
+    pub fn dropped_samples(&self, metric_name: &str) -> u64 {
+        self.dropped_samples
+            .lock()
+            .unwrap()
+            .get(metric_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the sum of `dropped_samples` across all metrics.
+    pub fn total_dropped_samples(&self) -> u64 {
+        self.dropped_samples.lock().unwrap().values().sum()
+    }
+
+    /// Adds `count` to the `dropped_samples` of the specified metric.
+    pub fn record_dropped_samples(&self, metric_name: &str, count: u64) {
+        let mut dropped_samples = self.dropped_samples.lock().unwrap();
+        if let Some(dropped) = dropped_samples.get_mut(metric_name) {
+            *dropped += count;
+        } else {
+            dropped_samples.insert(metric_name.into(), count);
+        }
+    }
+
+    /// Drops the deltas (or values) whose fields don't match the schema of the metric. Returns an
+    /// error if any was dropped.
+    fn retain_conforming<V>(
+        &self,
+        metric_name: &str,
+        deltas: &mut BTreeMap<FieldMap, V>,
+    ) -> Result<()> {
+        let count = deltas.len();
+        deltas.retain(|metric_fields, _| self.check_fields(metric_name, metric_fields).is_ok());
+        if deltas.len() < count {
+            Err(anyhow!(
+                "{} deltas of metric {} don't match its field schema",
+                count - deltas.len(),
+                metric_name
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_ephemeral_entity(&self, labels: &FieldMap) -> Option<Arc<Entity<'a>>> {
+        let entities = self.entities.lock().await;
+        entities.get(labels).cloned()
+    }
 
     async fn get_pinned_entity(self: Pin<&'a Self>, labels: &FieldMap) -> EntityPin<'a> {
         let mut entities = self.entities.lock().await;
@@ -585,6 +1505,21 @@ impl<'a> Exporter<'a> {
         }
     }
 
+    /// Checks whether a cell exists without copying its value, which is cheaper than `get_value`
+    /// for distributions. Doesn't create the entity.
+    pub async fn contains_cell(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+    ) -> bool {
+        if let Some(entity) = self.get_ephemeral_entity(entity_labels).await {
+            entity.contains_cell(metric_name, metric_fields).await
+        } else {
+            false
+        }
+    }
+
     pub async fn get_value(
         &self,
         entity_labels: &FieldMap,
@@ -669,12 +1604,167 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: Value,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, value, metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
+    }
+
+    /// Like `set_value`, but the cell is timestamped with `timestamp` rather than the current time,
+    /// e.g. to backfill historical values. A new cell also starts at `timestamp`. Fails if
+    /// `timestamp` is later than the current time plus the max skew (see
+    /// `set_max_timestamp_skew`), counting the write as a dropped sample.
+    pub async fn set_value_at(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        value: Value,
+        timestamp: SystemTime,
+        metric_fields: &FieldMap,
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
+        let max_skew = *self.max_timestamp_skew.lock().unwrap();
+        if timestamp > self.clock.now() + max_skew {
+            self.record_dropped_samples(metric_name, 1);
+            return Err(anyhow!(
+                "timestamp of metric {} is too far in the future",
+                metric_name
+            ));
+        }
+        self.get_pinned_entity(entity_labels)
+            .await
+            .set_value(metric_name, value, metric_fields, timestamp)
+            .await;
+        self.enforce_memory_budget().await;
+        Ok(())
+    }
+
+    /// Sets several cells of the same entity and metric at once, pinning the entity and locking its
+    /// metrics only once. All cells get the same update timestamp.
+    pub async fn set_values(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        mut values: BTreeMap<FieldMap, Value>,
+    ) -> Result<()> {
+        self.ensure_defined_for_cells(metric_name, MetricConfig::gauge(), values.len())?;
+        let rejected = self.retain_conforming(metric_name, &mut values);
+        if values.is_empty() {
+            return rejected;
+        }
+        let now = self.clock.now();
+        self.get_pinned_entity(entity_labels)
+            .await
+            .set_values(metric_name, values, now)
+            .await;
+        self.enforce_memory_budget().await;
+        rejected
+    }
+
+    /// Atomically replaces the value of a cell with the result of `f`, which receives the current
+    /// value (or `None` if the cell doesn't exist). `f` runs with the entity locked, so no other
+    /// write to the same entity can interleave between the read and the write. It should therefore
+    /// be quick. If `f` fails (e.g. because the current value has an unexpected type) the cell is
+    /// left untouched and the error is returned.
+    pub async fn update_value(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        f: impl FnOnce(Option<Value>) -> Result<Value>,
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
+        let now = self.clock.now();
+        self.get_pinned_entity(entity_labels)
+            .await
+            .update_value(metric_name, metric_fields, now, f)
+            .await?;
+        self.enforce_memory_budget().await;
+        Ok(())
+    }
+
+    /// Returns the value of a cell, first setting it to the result of `init` if the cell doesn't
+    /// exist. The lookup and the insertion happen with the entity locked, so `init` runs at most
+    /// once even if several tasks race to initialize the same cell. Existing cells are left
+    /// untouched, including their update timestamp.
+    pub async fn get_or_insert_value(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        init: impl FnOnce() -> Value,
+    ) -> Result<Value> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
+        let now = self.clock.now();
+        let value = self
+            .get_pinned_entity(entity_labels)
+            .await
+            .get_or_insert_value(metric_name, metric_fields, now, init)
+            .await;
+        self.enforce_memory_budget().await;
+        Ok(value)
+    }
+
+    /// Runs `f` with exclusive access to the metrics of an entity, so that it can make several reads
+    /// and writes, possibly across different metrics, that no other write to the entity can
+    /// interleave with. This is the general form of `update_value` and `compare_and_set_value`,
+    /// e.g. for a read of a gauge followed by a conditional increment of a counter. Returns the
+    /// result of `f`.
+    ///
+    /// `f` runs with the entity locked and can't await, so it should be quick.
+    pub async fn transaction<R>(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        f: impl FnOnce(&mut EntityTxn<'_, 'a>) -> R,
+    ) -> R {
+        let now = self.clock.now();
+        let entity = self.get_pinned_entity(entity_labels).await;
+        let result = {
+            let mut metrics = entity.metrics.lock().await;
+            f(&mut EntityTxn {
+                exporter: self.get_ref(),
+                entity_labels,
+                metrics: &mut metrics,
+                now,
+            })
+        };
+        drop(entity);
+        self.enforce_memory_budget().await;
+        result
+    }
+
+    /// Sets a cell to `value` only if its current value equals `expected`, `None` meaning that the
+    /// cell must not exist. The comparison and the write happen with the entity locked. Returns
+    /// whether the value was set.
+    pub async fn compare_and_set_value(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<bool> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
+        let now = self.clock.now();
+        let applied = self
+            .get_pinned_entity(entity_labels)
+            .await
+            .compare_and_set_value(metric_name, metric_fields, expected, value, now)
+            .await;
+        if applied {
+            self.enforce_memory_budget().await;
+        }
+        Ok(applied)
     }
 
     pub async fn set_bool(
@@ -683,12 +1773,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: bool,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, Value::Bool(value), metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn set_int(
@@ -697,12 +1791,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: i64,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, Value::Int(value), metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn set_float(
@@ -711,12 +1809,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: f64,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, Value::Float(value.into()), metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn set_string(
@@ -725,12 +1827,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: String,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, Value::Str(value), metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn set_distribution(
@@ -739,12 +1845,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         value: Distribution,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::gauge())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .set_value(metric_name, Value::Dist(value), metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn add_to_int(
@@ -753,25 +1863,36 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         delta: i64,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::counter())?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .add_to_int(metric_name, delta, metric_fields, now)
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn add_int_deltas(
         self: Pin<&'a Self>,
         entity_labels: &FieldMap,
         metric_name: &str,
-        deltas: BTreeMap<FieldMap, i64>,
-    ) {
+        mut deltas: BTreeMap<FieldMap, i64>,
+    ) -> Result<()> {
+        self.ensure_defined_for_cells(metric_name, MetricConfig::counter(), deltas.len())?;
+        let rejected = self.retain_conforming(metric_name, &mut deltas);
+        if deltas.is_empty() {
+            return rejected;
+        }
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .add_int_deltas(metric_name, deltas, now)
             .await;
+        self.enforce_memory_budget().await;
+        rejected
     }
 
     pub async fn add_to_distribution(
@@ -780,12 +1901,16 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
         sample: f64,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::distribution(Bucketer::default()))?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
-            .add_to_distribution(metric_name, sample, 1, metric_fields, now)
+            .add_to_distribution(metric_name, sample, 1, metric_fields, now, |_| ())
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
     pub async fn add_many_to_distribution(
@@ -795,25 +1920,190 @@ impl<'a> Exporter<'a> {
         sample: f64,
         times: usize,
         metric_fields: &FieldMap,
-    ) {
+    ) -> Result<()> {
+        self.ensure_defined(metric_name, MetricConfig::distribution(Bucketer::default()))?;
+        self.check_fields(metric_name, metric_fields)?;
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
-            .add_to_distribution(metric_name, sample, times, metric_fields, now)
+            .add_to_distribution(metric_name, sample, times, metric_fields, now, |_| ())
             .await;
+        self.enforce_memory_budget().await;
+        Ok(())
     }
 
-    pub async fn add_distribution_deltas(
+    /// Like `add_many_to_distribution`, but also returns the resulting distribution. The write and
+    /// the read happen with the entity locked, so the result reflects exactly the state before the
+    /// write plus the new samples, with no other write in between.
+    pub async fn add_many_to_distribution_and_get(
         self: Pin<&'a Self>,
         entity_labels: &FieldMap,
         metric_name: &str,
-        deltas: BTreeMap<FieldMap, Distribution>,
-    ) {
+        sample: f64,
+        times: usize,
+        metric_fields: &FieldMap,
+    ) -> Result<Distribution> {
+        self.ensure_defined(metric_name, MetricConfig::distribution(Bucketer::default()))?;
+        self.check_fields(metric_name, metric_fields)?;
+        let now = self.clock.now();
+        let result = self
+            .get_pinned_entity(entity_labels)
+            .await
+            .add_to_distribution(
+                metric_name,
+                sample,
+                times,
+                metric_fields,
+                now,
+                Distribution::clone,
+            )
+            .await;
+        self.enforce_memory_budget().await;
+        Ok(result)
+    }
+
+    pub async fn add_distribution_deltas(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        mut deltas: BTreeMap<FieldMap, Distribution>,
+    ) -> Result<()> {
+        self.ensure_defined_for_cells(
+            metric_name,
+            MetricConfig::distribution(Bucketer::default()),
+            deltas.len(),
+        )?;
+        let rejected = self.retain_conforming(metric_name, &mut deltas);
+        if deltas.is_empty() {
+            return rejected;
+        }
         let now = self.clock.now();
         self.get_pinned_entity(entity_labels)
             .await
             .add_distribution_deltas(metric_name, deltas, now)
             .await;
+        self.enforce_memory_budget().await;
+        rejected
+    }
+
+    /// Like `add_distribution_deltas`, but the cells are timestamped with the times of the events
+    /// rather than with the current time, as required by metrics with `user_timestamps`. New cells
+    /// start at the time of their first event, and the update timestamp of a cell is the time of
+    /// its latest event.
+    pub async fn add_timestamped_distribution_deltas(
+        self: Pin<&'a Self>,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        mut deltas: BTreeMap<FieldMap, TimestampedDistribution>,
+    ) -> Result<()> {
+        self.ensure_defined_for_cells(
+            metric_name,
+            MetricConfig::distribution(Bucketer::default()),
+            deltas.len(),
+        )?;
+        let rejected = self.retain_conforming(metric_name, &mut deltas);
+        if deltas.is_empty() {
+            return rejected;
+        }
+        self.get_pinned_entity(entity_labels)
+            .await
+            .add_timestamped_distribution_deltas(metric_name, deltas)
+            .await;
+        self.enforce_memory_budget().await;
+        rejected
+    }
+
+    /// Moves a cell to a different entity, e.g. to relabel it. The cell keeps its value and
+    /// timestamps, replacing the destination cell if any. Both entities are locked for the whole
+    /// operation, so the cell is never observed in both or neither of them, and the source entity
+    /// is removed if it becomes empty. Returns false if the source cell doesn't exist.
+    pub async fn move_cell(
+        self: Pin<&'a Self>,
+        from_labels: &FieldMap,
+        to_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+    ) -> bool {
+        let source = match self.get_ephemeral_entity(from_labels).await {
+            Some(entity) => entity,
+            None => return false,
+        };
+        if from_labels == to_labels {
+            return source
+                .metrics
+                .lock()
+                .await
+                .get(metric_name)
+                .is_some_and(|metric| metric.cells.contains_key(metric_fields));
+        }
+        let destination = self.get_pinned_entity(to_labels).await;
+        // Lock the two entities in label order to avoid deadlocking with concurrent moves in the
+        // opposite direction.
+        let (mut source_metrics, mut destination_metrics) = if from_labels < to_labels {
+            let source_metrics = source.metrics.lock().await;
+            (source_metrics, destination.metrics.lock().await)
+        } else {
+            let destination_metrics = destination.metrics.lock().await;
+            (source.metrics.lock().await, destination_metrics)
+        };
+        let cell = match source_metrics.take(metric_name) {
+            Some(mut metric) => {
+                let size = metric.size;
+                let cell = metric.remove_cell(metric_fields);
+                self.get_ref().update_memory_usage(size, metric.size);
+                if !metric.is_empty() {
+                    source_metrics.insert(metric);
+                }
+                cell
+            }
+            None => None,
+        };
+        let moved = cell.is_some();
+        if let Some(cell) = cell {
+            let mut metric = match destination_metrics.take(metric_name) {
+                Some(metric) => metric,
+                None => Metric::new(
+                    metric_name.into(),
+                    self.get_ref().get_metric_config_internal(metric_name),
+                ),
+            };
+            let size = metric.size;
+            metric.insert_cell(metric_fields.clone(), cell);
+            self.get_ref().update_memory_usage(size, metric.size);
+            destination_metrics.insert(metric);
+            self.get_ref().mark_dirty(to_labels, metric_name);
+        }
+        let source_empty = source_metrics.is_empty();
+        let destination_empty = destination_metrics.is_empty();
+        drop(source_metrics);
+        drop(destination_metrics);
+        drop(destination);
+        if source_empty {
+            self.get_ref().remove_empty_entity(from_labels).await;
+        }
+        if destination_empty {
+            self.get_ref().remove_empty_entity(to_labels).await;
+        }
+        moved
+    }
+
+    /// Resets an existing distribution cell to an empty distribution, keeping its bucketer and its
+    /// start timestamp. Unlike `delete_value` the cell survives, which is what periodically reset
+    /// windowed histograms need. Returns false, leaving the cell untouched, if the cell doesn't
+    /// exist or isn't a distribution.
+    pub async fn clear_distribution(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+        metric_fields: &FieldMap,
+    ) -> bool {
+        if let Some(entity) = self.get_ephemeral_entity(entity_labels).await {
+            entity
+                .clear_distribution(metric_name, metric_fields, self.clock.now())
+                .await
+        } else {
+            false
+        }
     }
 
     pub async fn delete_value(
@@ -835,32 +2125,333 @@ impl<'a> Exporter<'a> {
         metric_name: &str,
     ) -> bool {
         if let Some(entity) = self.get_ephemeral_entity(entity_labels).await {
-            entity.delete_metric(metric_name).await
+            entity.delete_metric(metric_name).await.is_some()
         } else {
             false
         }
     }
 
-    pub async fn delete_metric(&self, metric_name: &str) {
-        let entities = self.entities.lock().await;
-        for entity in entities.iter() {
-            entity.delete_metric(metric_name).await;
+    /// Deletes several metrics from an entity at once, which is cheaper than calling
+    /// `delete_metric_from_entity` for each of them because the entity is locked only once. Returns
+    /// the number of metrics that were removed.
+    pub async fn delete_metrics_from_entity(
+        &self,
+        entity_labels: &FieldMap,
+        metric_names: &[&str],
+    ) -> usize {
+        if let Some(entity) = self.get_ephemeral_entity(entity_labels).await {
+            entity.delete_metrics(metric_names).await
+        } else {
+            0
         }
     }
 
-    pub async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
-        if let Some(entity) = self.get_ephemeral_entity(entity_labels).await {
-            entity.clear().await;
-            true
-        } else {
-            false
+    /// Deletes all cells of the specified metric across all entities. Returns the number of cells
+    /// removed.
+    pub async fn delete_metric(&self, metric_name: &str) -> usize {
+        // Entities remove themselves from `entities` when they become empty, so we must not hold
+        // the lock while deleting.
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        let mut count = 0;
+        for entity in entities {
+            count += entity.delete_metric(metric_name).await.unwrap_or_default();
+        }
+        count
+    }
+
+    /// Deletes all cells of the specified entity. The entity itself is retained if a write to it is
+    /// in flight, see `DeleteEntityStatus`.
+    pub async fn delete_entity(&self, entity_labels: &FieldMap) -> DeleteEntityStatus {
+        match self.get_ephemeral_entity(entity_labels).await {
+            Some(entity) if entity.clear().await => DeleteEntityStatus::Removed,
+            Some(_) => DeleteEntityStatus::Retained,
+            None => DeleteEntityStatus::NotFound,
+        }
+    }
+
+    /// Sets a rough ceiling on the memory used by the cells of this exporter. When a write brings
+    /// the estimated usage over `bytes` the least recently updated cells are evicted until the
+    /// usage fits again. Cells of entities that are being written concurrently are never evicted.
+    ///
+    /// The estimate accounts for the cell, its metric fields, and variable-size values (strings and
+    /// distribution buckets), but not for the overhead of the containers. The usage is tracked
+    /// incrementally, so writes that fit in the budget are cheap, but evicting requires scanning all
+    /// cells; the budget is meant as a safety net against cardinality explosions rather than a
+    /// precise limit. The budget is unlimited by default.
+    pub fn set_memory_budget(&self, bytes: usize) {
+        self.memory_budget.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the estimated memory currently used by the cells of this exporter, computed like for
+    /// `set_memory_budget`.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of cells evicted so far to enforce the memory budget.
+    pub fn evicted_cells(&self) -> u64 {
+        self.evicted_cells.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of entities that currently have at least one cell (or are being written,
+    /// or are kept empty because of `set_auto_remove_empty_entities`).
+    pub async fn entity_count(&self) -> usize {
+        self.entities.lock().await.len()
+    }
+
+    /// Returns the names of the metrics that have at least one cell in the specified entity, in
+    /// name order, e.g. for debug pages. Returns an empty vector if the entity doesn't exist.
+    pub async fn entity_metrics(&self, entity_labels: &FieldMap) -> Vec<String> {
+        match self.get_ephemeral_entity(entity_labels).await {
+            Some(entity) => entity.metric_names().await,
+            None => vec![],
+        }
+    }
+
+    /// Returns the metric fields of the cells of the specified metric in the specified entity, in
+    /// order. Returns an empty vector if the entity or the metric don't exist.
+    pub async fn entity_metric_fields(
+        &self,
+        entity_labels: &FieldMap,
+        metric_name: &str,
+    ) -> Vec<FieldMap> {
+        match self.get_ephemeral_entity(entity_labels).await {
+            Some(entity) => entity.metric_fields(metric_name).await,
+            None => vec![],
+        }
+    }
+
+    /// Invokes `f` on every cell of the specified metric with the entity labels, the metric fields,
+    /// the value, the start timestamp, and the update timestamp of the cell. Unlike `snapshot` this
+    /// doesn't copy the cells, which makes it cheaper for custom exporters.
+    ///
+    /// Each entity is locked while its cells are visited, so `f` sees every cell of an entity in a
+    /// consistent state, but it mustn't write to this exporter and it should be quick.
+    pub async fn for_each_cell(
+        &self,
+        metric_name: &str,
+        mut f: impl FnMut(&FieldMap, &FieldMap, &Value, SystemTime, SystemTime),
+    ) {
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        for entity in &entities {
+            entity.for_each_cell(metric_name, &mut f).await;
+        }
+    }
+
+    /// Returns the total number of cells across all entities and metrics.
+    pub async fn cell_count(&self) -> usize {
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        let mut count = 0;
+        for entity in &entities {
+            count += entity.cell_count().await;
+        }
+        count
+    }
+
+    /// Returns a rough estimate of the memory taken by the cells of the specified metric across all
+    /// entities, computed like for `set_memory_budget`. Useful to find out which metrics drive the
+    /// memory usage. Returns 0 if the metric has no cells.
+    pub async fn metric_memory_estimate(&self, metric_name: &str) -> usize {
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        let mut size = 0;
+        for entity in &entities {
+            size += entity.metric_estimated_size(metric_name).await;
+        }
+        size
+    }
+
+    async fn enforce_memory_budget(&self) {
+        let budget = self.memory_budget.load(Ordering::Relaxed);
+        let mut usage = self.memory_usage();
+        if usage <= budget {
+            return;
+        }
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        let mut candidates = vec![];
+        for entity in &entities {
+            if entity.is_pinned() {
+                continue;
+            }
+            let metrics = entity.metrics.lock().await;
+            for metric in metrics.iter() {
+                for (metric_fields, cell) in &metric.cells {
+                    candidates.push((
+                        cell.update_timestamp,
+                        cell.estimated_size(metric_fields),
+                        entity.clone(),
+                        metric.name.clone(),
+                        metric_fields.clone(),
+                    ));
+                }
+            }
+        }
+        candidates.sort_by_key(|(update_timestamp, ..)| *update_timestamp);
+        for (_, size, entity, metric_name, metric_fields) in candidates {
+            if usage <= budget {
+                break;
+            }
+            if entity
+                .delete_value(&metric_name, &metric_fields)
+                .await
+                .is_some()
+            {
+                usage = usage.saturating_sub(size);
+                self.evicted_cells.fetch_add(1, Ordering::Relaxed);
+                self.record_dropped_samples(&metric_name, 1);
+            }
+        }
+    }
+
+    /// Takes a snapshot of all cells.
+    ///
+    /// Every entity is copied atomically: a write that updates several cells of the same entity at
+    /// once (e.g. `set_values`) is either entirely visible in the snapshot or not at all. Different
+    /// entities are copied at slightly different times though, so the snapshot may interleave with
+    /// concurrent writes to different entities.
+    pub async fn snapshot(&self) -> SnapshotView {
+        self.snapshot_filtered(|_| true).await
+    }
+
+    /// Like `snapshot`, but only includes the metrics whose name passes `predicate`, e.g. to export
+    /// a subset of the metrics to a specific backend. The other metrics aren't copied at all, so
+    /// this is cheaper than filtering a full snapshot.
+    pub async fn snapshot_filtered(&self, predicate: impl Fn(&str) -> bool) -> SnapshotView {
+        let entities: Vec<Arc<Entity<'a>>> = self.entities.lock().await.iter().cloned().collect();
+        let mut snapshot = SnapshotView {
+            timestamp: self.clock.now(),
+            entities: BTreeMap::default(),
+        };
+        for entity in entities {
+            let metrics = entity.snapshot(&predicate).await;
+            if !metrics.is_empty() {
+                snapshot.entities.insert(entity.labels.clone(), metrics);
+            }
+        }
+        snapshot
+    }
+
+    /// Like `snapshot`, but only includes the metrics written since the previous `snapshot_dirty`
+    /// call (or since dirty tracking was turned on, see `set_dirty_tracking`), with all of their
+    /// cells. Only the touched entities are locked and copied, so the cost is proportional to the
+    /// amount of changed data rather than to the total number of cells, which makes this suitable
+    /// for frequent incremental exports.
+    ///
+    /// The set of written metrics is swapped out before copying, so writes racing with this call
+    /// are never lost: at worst they're included both in this snapshot and in the next one.
+    /// Deletions don't mark metrics as written.
+    pub async fn snapshot_dirty(&self) -> SnapshotView {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        let mut by_entity: BTreeMap<FieldMap, BTreeSet<String>> = BTreeMap::default();
+        for (entity_labels, metric_name) in dirty {
+            by_entity
+                .entry(entity_labels)
+                .or_default()
+                .insert(metric_name);
+        }
+        let mut snapshot = SnapshotView {
+            timestamp: self.clock.now(),
+            entities: BTreeMap::default(),
+        };
+        for (entity_labels, metric_names) in by_entity {
+            let entity = match self.get_ephemeral_entity(&entity_labels).await {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let metrics = entity
+                .snapshot(&|metric_name| metric_names.contains(metric_name))
+                .await;
+            if !metrics.is_empty() {
+                snapshot.entities.insert(entity_labels, metrics);
+            }
+        }
+        snapshot
+    }
+
+    /// Takes a snapshot meant to be shipped to a collector. It's the same as `snapshot`, except that
+    /// the int and float cells of metrics configured with `delta_mode` report the difference from
+    /// the value they had at the previous `export_snapshot` call (or their whole value the first
+    /// time).
+    ///
+    /// Gauge deltas are signed, so a gauge going from 8 to 3 exports -5. Cumulative metrics only
+    /// grow unless their source is reset, so a decrease is taken to be a reset and the whole new
+    /// value is exported.
+    pub async fn export_snapshot(&self) -> SnapshotView {
+        let mut snapshot = self.snapshot().await;
+        let mut last_exported = self.last_exported.lock().unwrap();
+        let mut exported = BTreeMap::default();
+        for (entity_labels, metrics) in &mut snapshot.entities {
+            for (metric_name, cells) in metrics.iter_mut() {
+                let config = match self.get_metric_config(metric_name) {
+                    Some(config) if config.delta_mode => config,
+                    _ => continue,
+                };
+                for (metric_fields, cell) in cells.iter_mut() {
+                    let key = (
+                        entity_labels.clone(),
+                        metric_name.clone(),
+                        metric_fields.clone(),
+                    );
+                    let delta = match (&cell.value, last_exported.get(&key)) {
+                        (Value::Int(value), Some(Value::Int(previous))) => {
+                            if config.cumulative && value < previous {
+                                Value::Int(*value)
+                            } else {
+                                Value::Int(value - previous)
+                            }
+                        }
+                        (Value::Float(value), Some(Value::Float(previous))) => {
+                            if config.cumulative && value < previous {
+                                Value::Float(*value)
+                            } else {
+                                Value::Float((value.value - previous.value).into())
+                            }
+                        }
+                        _ => cell.value.clone(),
+                    };
+                    exported.insert(key, std::mem::replace(&mut cell.value, delta));
+                }
+            }
         }
+        // Cells that no longer exist are forgotten, so they start over if they come back.
+        *last_exported = exported;
+        snapshot
+    }
+
+    /// Takes a new snapshot and publishes it to `snapshot_arc` readers.
+    pub async fn refresh_snapshot(&self) {
+        let snapshot = Arc::new(self.snapshot().await);
+        *self.snapshot.write().unwrap() = snapshot;
+    }
+
+    /// Returns the last snapshot published by `refresh_snapshot`. This is a synchronous call that
+    /// doesn't contend with writers, so it can be used outside of the async runtime (e.g. by debug
+    /// handlers).
+    ///
+    /// The returned snapshot is as stale as the last `refresh_snapshot` call. The `MetricManager`
+    /// refreshes the snapshot of the global exporter after every flush cycle, so when the manager
+    /// is running the snapshot is at most `MetricManager::FLUSH_PERIOD` old.
+    pub fn snapshot_arc(&self) -> Arc<SnapshotView> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Aggregates the cells of `metric_name` across all entities of a fresh snapshot, see
+    /// `SnapshotView::aggregate`.
+    pub async fn aggregate(
+        &self,
+        metric_name: &str,
+        group_by: &[&str],
+        aggregation: Aggregation,
+    ) -> Result<BTreeMap<FieldMap, Value>> {
+        self.snapshot_filtered(|name| name == metric_name)
+            .await
+            .aggregate(metric_name, group_by, aggregation)
     }
 
     #[cfg(test)]
     pub async fn clear(&self) {
         let mut entities = self.entities.lock().await;
         entities.clear();
+        self.memory_usage.store(0, Ordering::Relaxed);
     }
 }
 
@@ -869,10 +2460,28 @@ impl<'a> EntityManager for Exporter<'a> {
         self.get_metric_config(metric_name).unwrap()
     }
 
+    fn auto_remove_empty_entities(&self) -> bool {
+        self.auto_remove_empty_entities.load(Ordering::Relaxed)
+    }
+
+    fn mark_dirty(&self, entity_labels: &FieldMap, metric_name: &str) {
+        if !self.dirty_tracking.load(Ordering::Relaxed) {
+            return;
+        }
+        let key = (entity_labels.clone(), metric_name.to_string());
+        self.dirty.lock().unwrap().insert(key);
+    }
+
+    fn update_memory_usage(&self, before: usize, after: usize) {
+        // Add first so that concurrent updates never make the counter wrap around.
+        self.memory_usage.fetch_add(after, Ordering::Relaxed);
+        self.memory_usage.fetch_sub(before, Ordering::Relaxed);
+    }
+
     fn remove_entity<'b>(
         &'b self,
         entity_labels: &'b FieldMap,
-    ) -> Pin<Box<dyn Future<Output = ()> + 'b>> {
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'b>> {
         Box::pin(async move {
             let mut entities = self.entities.lock().await;
             if let Some(entity) = entities.get(entity_labels) {
@@ -889,21 +2498,41 @@ impl<'a> Default for Exporter<'a> {
         Self {
             clock: Arc::new(RealClock::default()),
             metric_configs: SyncMutex::default(),
+            retired_configs: SyncMutex::default(),
             entities: Mutex::default(),
+            snapshot: SyncRwLock::default(),
+            memory_budget: AtomicUsize::new(usize::MAX),
+            memory_usage: AtomicUsize::default(),
+            evicted_cells: AtomicU64::default(),
+            dropped_samples: SyncMutex::default(),
+            strict: AtomicBool::default(),
+            auto_remove_empty_entities: AtomicBool::new(true),
+            max_timestamp_skew: SyncMutex::new(Self::DEFAULT_MAX_TIMESTAMP_SKEW),
+            last_exported: SyncMutex::default(),
+            dirty_tracking: AtomicBool::default(),
+            dirty: SyncMutex::default(),
         }
     }
 }
 
-static EXPORTER_INSTANCE: LazyLock<Pin<Box<Exporter>>> =
-    LazyLock::new(|| Box::pin(Exporter::default()));
+#[cfg(not(feature = "no-global-exporter"))]
+static EXPORTER_INSTANCE: std::sync::LazyLock<Pin<Box<Exporter>>> =
+    std::sync::LazyLock::new(|| Box::pin(Exporter::default()));
 
-pub static EXPORTER: LazyLock<Pin<&Exporter>> = LazyLock::new(|| EXPORTER_INSTANCE.as_ref());
+/// The global exporter used by the metric wrappers created with `new`. It can be left out with the
+/// `no-global-exporter` feature, in which case all metrics must be created with `new_with`.
+#[cfg(not(feature = "no-global-exporter"))]
+pub static EXPORTER: std::sync::LazyLock<Pin<&Exporter>> =
+    std::sync::LazyLock::new(|| EXPORTER_INSTANCE.as_ref());
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tsz::FieldValue;
+    use crate::tsz::{
+        FieldType, FieldValue, testing::test_entity_labels, testing::test_metric_fields,
+    };
     use crate::utils::clock::test::MockClock;
+    use std::time::Duration;
 
     #[test]
     fn test_empty_metric() {
@@ -1347,5 +2976,1727 @@ mod tests {
         assert_eq!(metric.get_int(&metric_fields), Some(43));
     }
 
-    // TODO
+    #[tokio::test]
+    async fn test_empty_snapshot() {
+        let exporter = Exporter::default();
+        assert!(exporter.snapshot().await.is_empty());
+        assert!(exporter.snapshot_arc().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 43, &metric_fields2)
+            .await
+            .unwrap();
+        let snapshot = exporter.snapshot().await;
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields1),
+            Some(&Value::Int(42))
+        );
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields2),
+            Some(&Value::Int(43))
+        );
+        assert_eq!(snapshot.iter().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_dirty() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        exporter
+            .define_metric("/foo/baz", MetricConfig::default())
+            .unwrap();
+        exporter.set_dirty_tracking(true);
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels, "/foo/baz", 43, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(exporter.snapshot_dirty().await.iter().count(), 2);
+        assert!(exporter.snapshot_dirty().await.is_empty());
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 44, &metric_fields)
+            .await
+            .unwrap();
+        let snapshot = exporter.snapshot_dirty().await;
+        assert_eq!(snapshot.iter().count(), 1);
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields),
+            Some(&Value::Int(44))
+        );
+        assert!(exporter.snapshot_dirty().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dirty_tracking_disabled() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .add_to_int(&entity_labels, "/foo/baz", 1, &metric_fields)
+            .await
+            .unwrap();
+        assert!(exporter.dirty.lock().unwrap().is_empty());
+        assert!(exporter.snapshot_dirty().await.is_empty());
+        exporter.set_dirty_tracking(true);
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 43, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(exporter.dirty.lock().unwrap().len(), 1);
+        exporter.set_dirty_tracking(false);
+        assert!(exporter.dirty.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_entity() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            exporter.delete_entity(&entity_labels).await,
+            DeleteEntityStatus::NotFound
+        );
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            exporter.delete_entity(&entity_labels).await,
+            DeleteEntityStatus::Removed
+        );
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_none()
+        );
+        assert_eq!(
+            exporter.delete_entity(&entity_labels).await,
+            DeleteEntityStatus::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_pinned_entity() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        let pin = exporter.get_pinned_entity(&entity_labels).await;
+        assert_eq!(
+            exporter.delete_entity(&entity_labels).await,
+            DeleteEntityStatus::Retained
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            None
+        );
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_some()
+        );
+        drop(pin);
+    }
+
+    #[tokio::test]
+    async fn test_remove_entity_after_last_cell() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 1, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 2, &metric_fields2)
+            .await
+            .unwrap();
+        assert_eq!(exporter.entity_count().await, 1);
+        assert_eq!(
+            exporter
+                .delete_value(&entity_labels, "/foo/bar", &metric_fields1)
+                .await,
+            Some(Value::Int(1))
+        );
+        assert_eq!(exporter.entity_count().await, 1);
+        assert_eq!(
+            exporter
+                .delete_value(&entity_labels, "/foo/bar", &metric_fields2)
+                .await,
+            Some(Value::Int(2))
+        );
+        assert_eq!(exporter.entity_count().await, 0);
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_empty_entities() {
+        let exporter = Exporter::default();
+        exporter.set_auto_remove_empty_entities(false);
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(exporter.entity_count().await, 1);
+        assert_eq!(
+            exporter
+                .delete_value(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            Some(Value::Int(42))
+        );
+        assert_eq!(exporter.entity_count().await, 1);
+        assert_eq!(exporter.cell_count().await, 0);
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_some()
+        );
+        assert_eq!(
+            exporter.delete_entity(&entity_labels).await,
+            DeleteEntityStatus::Removed
+        );
+        assert_eq!(exporter.entity_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_metrics_from_entity() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for metric_name in ["/foo/bar", "/foo/baz", "/foo/qux"] {
+            exporter
+                .set_int(&entity_labels, metric_name, 42, &metric_fields)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            exporter
+                .delete_metrics_from_entity(&entity_labels, &["/foo/bar", "/foo/qux", "/foo/lorem"])
+                .await,
+            2
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            None
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/baz", &metric_fields)
+                .await,
+            Some(42)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/qux", &metric_fields)
+                .await,
+            None
+        );
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_some()
+        );
+        assert_eq!(
+            exporter
+                .delete_metrics_from_entity(&entity_labels, &["/foo/baz"])
+                .await,
+            1
+        );
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_none()
+        );
+    }
+
+    async fn set_aggregation_test_values<'a>(exporter: Pin<&'a Exporter<'a>>) {
+        for (zone, host, value) in [("us", "a", 1), ("us", "b", 5), ("eu", "c", 3)] {
+            let entity_labels = FieldMap::from([
+                ("zone", FieldValue::Str(zone.into())),
+                ("host", FieldValue::Str(host.into())),
+            ]);
+            exporter
+                .set_int(&entity_labels, "/foo/bar", value, &FieldMap::from([]))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_sum() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        set_aggregation_test_values(exporter).await;
+        let result = exporter
+            .aggregate("/foo/bar", &["zone"], Aggregation::Sum)
+            .await
+            .unwrap();
+        assert_eq!(
+            result,
+            BTreeMap::from([
+                (
+                    FieldMap::from([("zone", FieldValue::Str("eu".into()))]),
+                    Value::Int(3)
+                ),
+                (
+                    FieldMap::from([("zone", FieldValue::Str("us".into()))]),
+                    Value::Int(6)
+                ),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_max() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        set_aggregation_test_values(exporter).await;
+        let result = exporter
+            .aggregate("/foo/bar", &["zone"], Aggregation::Max)
+            .await
+            .unwrap();
+        assert_eq!(
+            result,
+            BTreeMap::from([
+                (
+                    FieldMap::from([("zone", FieldValue::Str("eu".into()))]),
+                    Value::Int(3)
+                ),
+                (
+                    FieldMap::from([("zone", FieldValue::Str("us".into()))]),
+                    Value::Int(5)
+                ),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let result = exporter
+            .transaction(&entity_labels, |txn| {
+                assert_eq!(txn.get_int("/foo/bar", &metric_fields), None);
+                txn.set_value("/foo/bar", Value::Int(42), &metric_fields)
+                    .unwrap();
+                txn.add_to_int("/foo/baz", 2, &metric_fields).unwrap();
+                txn.add_to_int("/foo/baz", 3, &metric_fields).unwrap();
+                txn.get_int("/foo/bar", &metric_fields)
+            })
+            .await;
+        assert_eq!(result, Some(42));
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/baz", &metric_fields)
+                .await,
+            Some(5)
+        );
+        let deleted = exporter
+            .transaction(&entity_labels, |txn| {
+                txn.delete_value("/foo/bar", &metric_fields)
+            })
+            .await;
+        assert_eq!(deleted, Some(Value::Int(42)));
+        assert!(
+            !exporter
+                .contains_cell(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_transactions() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let exporter = Pin::new(exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/quota", 10, &metric_fields)
+            .await
+            .unwrap();
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let entity_labels = entity_labels.clone();
+                let metric_fields = metric_fields.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        exporter
+                            .transaction(&entity_labels, |txn| {
+                                let quota = txn.get_int("/foo/quota", &metric_fields).unwrap();
+                                if quota > 0 {
+                                    txn.set_value(
+                                        "/foo/quota",
+                                        Value::Int(quota - 1),
+                                        &metric_fields,
+                                    )
+                                    .unwrap();
+                                    txn.add_to_int("/foo/granted", 1, &metric_fields).unwrap();
+                                }
+                            })
+                            .await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/quota", &metric_fields)
+                .await,
+            Some(0)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/granted", &metric_fields)
+                .await,
+            Some(10)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_under_concurrent_writes() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        let writer = {
+            let entity_labels = entity_labels.clone();
+            let metric_fields1 = metric_fields1.clone();
+            let metric_fields2 = metric_fields2.clone();
+            tokio::spawn(async move {
+                for i in 0..1000 {
+                    exporter
+                        .set_values(
+                            &entity_labels,
+                            "/foo/bar",
+                            BTreeMap::from([
+                                (metric_fields1.clone(), Value::Int(i)),
+                                (metric_fields2.clone(), Value::Int(i)),
+                            ]),
+                        )
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        let mut last = -1;
+        while !writer.is_finished() {
+            let snapshot = exporter.snapshot().await;
+            let value1 = snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields1);
+            let value2 = snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields2);
+            assert_eq!(value1, value2);
+            if let Some(&Value::Int(value)) = value1 {
+                assert!(value >= last);
+                last = value;
+            }
+        }
+        writer.await.unwrap();
+        let snapshot = exporter.snapshot().await;
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar", &metric_fields1),
+            Some(&Value::Int(999))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_filtered() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for metric_name in ["/rpc/latency", "/rpc/count", "/db/count"] {
+            exporter
+                .set_int(&entity_labels, metric_name, 42, &metric_fields)
+                .await
+                .unwrap();
+        }
+        let snapshot = exporter
+            .snapshot_filtered(|name| name.starts_with("/rpc/"))
+            .await;
+        let metric_names: Vec<&str> = snapshot
+            .iter()
+            .map(|(_, metric_name, ..)| metric_name)
+            .collect();
+        assert_eq!(metric_names, vec!["/rpc/count", "/rpc/latency"]);
+        assert!(
+            snapshot
+                .get_value(&entity_labels, "/db/count", &metric_fields)
+                .is_none()
+        );
+        assert!(
+            exporter
+                .snapshot_filtered(|name| name.starts_with("/foo/"))
+                .await
+                .is_empty()
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_snapshot_arc() {
+        EXPORTER.define_metric_redundant("/foo/bar/snapshot", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        EXPORTER
+            .set_int(&entity_labels, "/foo/bar/snapshot", 42, &metric_fields)
+            .await
+            .unwrap();
+        EXPORTER.refresh_snapshot().await;
+        let snapshot = EXPORTER.snapshot_arc();
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar/snapshot", &metric_fields),
+            Some(&Value::Int(42))
+        );
+        EXPORTER
+            .set_int(&entity_labels, "/foo/bar/snapshot", 43, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            snapshot.get_value(&entity_labels, "/foo/bar/snapshot", &metric_fields),
+            Some(&Value::Int(42))
+        );
+        EXPORTER.refresh_snapshot().await;
+        assert_eq!(
+            EXPORTER.snapshot_arc().get_value(
+                &entity_labels,
+                "/foo/bar/snapshot",
+                &metric_fields
+            ),
+            Some(&Value::Int(43))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_field_schema() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric(
+                "/foo/bar",
+                MetricConfig::default().set_fields(&[("code", FieldType::Int)]),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let good_fields = FieldMap::from([("code", FieldValue::Int(200))]);
+        let bad_fields = FieldMap::from([("coed", FieldValue::Int(200))]);
+        assert!(
+            exporter
+                .add_to_int(&entity_labels, "/foo/bar", 1, &good_fields)
+                .await
+                .is_ok()
+        );
+        assert!(
+            exporter
+                .add_to_int(&entity_labels, "/foo/bar", 1, &bad_fields)
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &good_fields)
+                .await,
+            Some(1)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &bad_fields)
+                .await,
+            None
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_field_schema_deltas() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric(
+                "/foo/bar",
+                MetricConfig::default().set_fields(&[("code", FieldType::Int)]),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let good_fields = FieldMap::from([("code", FieldValue::Int(200))]);
+        let bad_fields = FieldMap::from([("code", FieldValue::Str("200".into()))]);
+        assert!(
+            exporter
+                .add_int_deltas(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(good_fields.clone(), 2), (bad_fields.clone(), 3)]),
+                )
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &good_fields)
+                .await,
+            Some(2)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &bad_fields)
+                .await,
+            None
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_field_schema_deltas_all_rejected() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric(
+                "/foo/bar",
+                MetricConfig::default().set_fields(&[("code", FieldType::Int)]),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let bad_fields1 = FieldMap::from([("code", FieldValue::Str("200".into()))]);
+        let bad_fields2 = FieldMap::from([("coed", FieldValue::Int(200))]);
+        assert!(
+            exporter
+                .add_int_deltas(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(bad_fields1, 2), (bad_fields2, 3)]),
+                )
+                .await
+                .is_err()
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 2);
+        assert_eq!(exporter.entity_count().await, 0);
+        assert!(
+            exporter
+                .get_ephemeral_entity(&entity_labels)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_value_error() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        assert!(
+            exporter
+                .update_value(&entity_labels, "/foo/bar", &metric_fields, |_| {
+                    Err(anyhow!("lorem"))
+                })
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            Some(42)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_value_panic() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let exporter = Pin::new(exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = FieldMap::from([("lorem", FieldValue::Int(1))]);
+        let metric_fields2 = FieldMap::from([("lorem", FieldValue::Int(2))]);
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields1)
+            .await
+            .unwrap();
+        let task = {
+            let entity_labels = entity_labels.clone();
+            let metric_fields2 = metric_fields2.clone();
+            tokio::spawn(async move {
+                exporter
+                    .update_value(&entity_labels, "/foo/bar", &metric_fields2, |_| panic!())
+                    .await
+            })
+        };
+        assert!(task.await.is_err());
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields1)
+                .await,
+            Some(42)
+        );
+        assert!(
+            exporter
+                .get_value(&entity_labels, "/foo/bar", &metric_fields2)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_or_insert_value_panic() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let exporter = Pin::new(exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = FieldMap::from([("lorem", FieldValue::Int(1))]);
+        let metric_fields2 = FieldMap::from([("lorem", FieldValue::Int(2))]);
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields1)
+            .await
+            .unwrap();
+        let task = {
+            let entity_labels = entity_labels.clone();
+            let metric_fields2 = metric_fields2.clone();
+            tokio::spawn(async move {
+                exporter
+                    .get_or_insert_value(&entity_labels, "/foo/bar", &metric_fields2, || panic!())
+                    .await
+            })
+        };
+        assert!(task.await.is_err());
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields1)
+                .await,
+            Some(42)
+        );
+        assert!(
+            exporter
+                .get_value(&entity_labels, "/foo/bar", &metric_fields2)
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redefine_metric() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .add_to_distribution(&entity_labels, "/foo/bar", 42.0, &metric_fields)
+            .await
+            .unwrap();
+        let bucketer = Bucketer::fixed_width(10.0, 10);
+        exporter
+            .redefine_metric("/foo/bar", MetricConfig::default().set_bucketer(bucketer))
+            .await
+            .unwrap();
+        assert_eq!(
+            *exporter.get_metric_config("/foo/bar").unwrap(),
+            MetricConfig::default().set_bucketer(bucketer)
+        );
+        assert!(
+            exporter
+                .get_distribution(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+                .is_none()
+        );
+        exporter
+            .add_to_distribution(&entity_labels, "/foo/bar", 42.0, &metric_fields)
+            .await
+            .unwrap();
+        let d = exporter
+            .get_distribution(&entity_labels, "/foo/bar", &metric_fields)
+            .await
+            .unwrap();
+        assert!(d.bucketer().same_as(bucketer));
+    }
+
+    #[tokio::test]
+    async fn test_redefine_metric_reuses_retired_configs() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let config1 = MetricConfig::default().set_bucketer(Bucketer::fixed_width(10.0, 10));
+        let config2 = MetricConfig::default().set_bucketer(Bucketer::fixed_width(20.0, 10));
+        exporter.redefine_metric("/foo/bar", config1).await.unwrap();
+        let retired = exporter.get_metric_config("/foo/bar").unwrap();
+        exporter.redefine_metric("/foo/bar", config2).await.unwrap();
+        assert_eq!(exporter.retired_configs(), 2);
+        for _ in 0..100 {
+            exporter.redefine_metric("/foo/bar", config1).await.unwrap();
+            exporter.redefine_metric("/foo/bar", config2).await.unwrap();
+        }
+        assert_eq!(exporter.retired_configs(), 2);
+        assert_eq!(*retired, config1);
+        assert_eq!(*exporter.get_metric_config("/foo/bar").unwrap(), config2);
+    }
+
+    #[tokio::test]
+    async fn test_redefine_metric_same_config() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        exporter
+            .redefine_metric("/foo/bar", MetricConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(exporter.retired_configs(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_redefine_undefined_metric() {
+        let exporter = Exporter::default();
+        assert!(
+            exporter
+                .redefine_metric("/foo/bar", MetricConfig::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undefine_metric() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        assert!(exporter.undefine_metric("/foo/bar").await);
+        assert!(exporter.get_metric_config("/foo/bar").is_none());
+        assert!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+                .is_none()
+        );
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default().set_cumulative(true))
+            .unwrap();
+        assert_eq!(
+            *exporter.get_metric_config("/foo/bar").unwrap(),
+            MetricConfig::default().set_cumulative(true)
+        );
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 43, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            Some(43)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_undefined_metric_strict() {
+        let exporter = Exporter::default();
+        exporter.set_strict(true);
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(
+            exporter
+                .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+                .await
+                .is_err()
+        );
+        assert!(
+            exporter
+                .add_int_deltas(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(metric_fields.clone(), 1)])
+                )
+                .await
+                .is_err()
+        );
+        assert!(exporter.get_metric_config("/foo/bar").is_none());
+        assert!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+                .is_none()
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 2);
+        assert_eq!(exporter.dropped_samples("/foo/baz"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_undefined_metric_strict_batch() {
+        let exporter = Exporter::default();
+        exporter.set_strict(true);
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let fields = |i| FieldMap::from([("lorem", FieldValue::Int(i))]);
+        assert!(
+            exporter
+                .add_int_deltas(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(fields(1), 1), (fields(2), 2), (fields(3), 3)])
+                )
+                .await
+                .is_err()
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 3);
+        assert!(
+            exporter
+                .set_values(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(fields(1), Value::Int(1)), (fields(2), Value::Int(2))])
+                )
+                .await
+                .is_err()
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 5);
+        assert!(
+            exporter
+                .add_distribution_deltas(
+                    &entity_labels,
+                    "/foo/bar",
+                    BTreeMap::from([(fields(1), Distribution::default())])
+                )
+                .await
+                .is_err()
+        );
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 6);
+        assert!(exporter.get_metric_config("/foo/bar").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_undefined_metric_permissive() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/gauge", 42, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .add_to_int(&entity_labels, "/foo/counter", 3, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .add_to_distribution(&entity_labels, "/foo/distribution", 5.0, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            *exporter.get_metric_config("/foo/gauge").unwrap(),
+            MetricConfig::gauge()
+        );
+        assert_eq!(
+            *exporter.get_metric_config("/foo/counter").unwrap(),
+            MetricConfig::counter()
+        );
+        assert_eq!(
+            *exporter.get_metric_config("/foo/distribution").unwrap(),
+            MetricConfig::distribution(Bucketer::default())
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/gauge", &metric_fields)
+                .await,
+            Some(42)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/counter", &metric_fields)
+                .await,
+            Some(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_undefine_unknown_metric() {
+        let exporter = Exporter::default();
+        assert!(!exporter.undefine_metric("/foo/bar").await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_clock_timestamps() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(123);
+        let clock = Arc::new(MockClock::new(start));
+        let exporter = Exporter::with_clock(clock.clone());
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 1, &metric_fields)
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(10)).await;
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 2, &metric_fields)
+            .await
+            .unwrap();
+        let snapshot = exporter.snapshot().await;
+        let cell = snapshot
+            .get_cell(&entity_labels, "/foo/bar", &metric_fields)
+            .unwrap();
+        assert_eq!(cell.start_timestamp(), start);
+        assert_eq!(cell.update_timestamp(), start + Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset_start_on_update() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(123);
+        let clock = Arc::new(MockClock::new(start));
+        let exporter = Exporter::with_clock(clock.clone());
+        exporter
+            .define_metric(
+                "/foo/gauge",
+                MetricConfig::gauge().set_reset_start_on_update(true),
+            )
+            .unwrap();
+        exporter
+            .define_metric(
+                "/foo/cumulative",
+                MetricConfig::gauge()
+                    .set_cumulative(true)
+                    .set_reset_start_on_update(true),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for value in [1, 2] {
+            for metric_name in ["/foo/gauge", "/foo/cumulative"] {
+                exporter
+                    .set_int(&entity_labels, metric_name, value, &metric_fields)
+                    .await
+                    .unwrap();
+            }
+            clock.advance(Duration::from_secs(10)).await;
+        }
+        let snapshot = exporter.snapshot().await;
+        let cell = snapshot
+            .get_cell(&entity_labels, "/foo/gauge", &metric_fields)
+            .unwrap();
+        assert_eq!(cell.start_timestamp(), start + Duration::from_secs(10));
+        assert_eq!(cell.update_timestamp(), start + Duration::from_secs(10));
+        let cell = snapshot
+            .get_cell(&entity_labels, "/foo/cumulative", &metric_fields)
+            .unwrap();
+        assert_eq!(cell.start_timestamp(), start);
+        assert_eq!(cell.update_timestamp(), start + Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_entity_and_cell_count() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        assert_eq!(exporter.entity_count().await, 0);
+        assert_eq!(exporter.cell_count().await, 0);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 1, &test_metric_fields())
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 2, &test_metric_fields())
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels2, "/foo/baz", 3, &test_metric_fields())
+            .await
+            .unwrap();
+        assert_eq!(exporter.entity_count().await, 2);
+        assert_eq!(exporter.cell_count().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_entity_metrics() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        assert!(exporter.entity_metrics(&entity_labels1).await.is_empty());
+        exporter
+            .set_int(&entity_labels1, "/foo/baz", 1, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 2, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 3, &metric_fields2)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels2, "/foo/qux", 4, &metric_fields1)
+            .await
+            .unwrap();
+        assert_eq!(
+            exporter.entity_metrics(&entity_labels1).await,
+            vec!["/foo/bar".to_string(), "/foo/baz".to_string()]
+        );
+        let mut expected = vec![metric_fields1.clone(), metric_fields2];
+        expected.sort();
+        assert_eq!(
+            exporter
+                .entity_metric_fields(&entity_labels1, "/foo/bar")
+                .await,
+            expected
+        );
+        assert_eq!(
+            exporter
+                .entity_metric_fields(&entity_labels1, "/foo/baz")
+                .await,
+            vec![metric_fields1.clone()]
+        );
+        assert!(
+            exporter
+                .entity_metric_fields(&entity_labels1, "/foo/qux")
+                .await
+                .is_empty()
+        );
+        assert!(
+            exporter
+                .entity_metric_fields(&test_entity_labels(), "/foo/bar")
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metric_memory_estimate() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(exporter.metric_memory_estimate("/foo/int").await, 0);
+        exporter
+            .set_int(&entity_labels1, "/foo/int", 42, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .add_to_distribution(&entity_labels1, "/foo/dist", 42.0, &metric_fields)
+            .await
+            .unwrap();
+        let int_size = exporter.metric_memory_estimate("/foo/int").await;
+        assert!(int_size > 0);
+        assert!(exporter.metric_memory_estimate("/foo/dist").await > int_size);
+        exporter
+            .set_int(&entity_labels2, "/foo/int", 43, &metric_fields)
+            .await
+            .unwrap();
+        assert_eq!(
+            exporter.metric_memory_estimate("/foo/int").await,
+            int_size * 2
+        );
+        assert_eq!(exporter.metric_memory_estimate("/foo/bar").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_for_each_cell() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 1, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels1, "/foo/bar", 2, &metric_fields2)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels2, "/foo/bar", 3, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels2, "/foo/baz", 4, &metric_fields1)
+            .await
+            .unwrap();
+        let mut cells = BTreeMap::new();
+        exporter
+            .for_each_cell(
+                "/foo/bar",
+                |entity_labels, metric_fields, value, start_timestamp, update_timestamp| {
+                    assert!(start_timestamp <= update_timestamp);
+                    cells.insert(
+                        (entity_labels.clone(), metric_fields.clone()),
+                        value.clone(),
+                    );
+                },
+            )
+            .await;
+        assert_eq!(
+            cells,
+            BTreeMap::from([
+                (
+                    (entity_labels1.clone(), metric_fields1.clone()),
+                    Value::Int(1)
+                ),
+                ((entity_labels1, metric_fields2), Value::Int(2)),
+                ((entity_labels2, metric_fields1), Value::Int(3)),
+            ])
+        );
+        let mut count = 0;
+        exporter
+            .for_each_cell("/lorem/ipsum", |_, _, _, _, _| count += 1)
+            .await;
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_export_gauge_deltas() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::gauge().set_delta_mode(true))
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let mut deltas = vec![];
+        for value in [5, 8, 3] {
+            exporter
+                .set_int(&entity_labels, "/foo/bar", value, &metric_fields)
+                .await
+                .unwrap();
+            exporter
+                .set_int(&entity_labels, "/foo/baz", value, &metric_fields)
+                .await
+                .unwrap();
+            let snapshot = exporter.export_snapshot().await;
+            deltas.push(
+                snapshot
+                    .get_value(&entity_labels, "/foo/bar", &metric_fields)
+                    .unwrap()
+                    .clone(),
+            );
+            assert_eq!(
+                snapshot.get_value(&entity_labels, "/foo/baz", &metric_fields),
+                Some(&Value::Int(value))
+            );
+        }
+        assert_eq!(deltas, vec![Value::Int(5), Value::Int(3), Value::Int(-5)]);
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields)
+                .await,
+            Some(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_counter_deltas() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::counter().set_delta_mode(true))
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let mut deltas = vec![];
+        for value in [10, 25, 5] {
+            exporter
+                .set_int(&entity_labels, "/foo/bar", value, &metric_fields)
+                .await
+                .unwrap();
+            let snapshot = exporter.export_snapshot().await;
+            deltas.push(
+                snapshot
+                    .get_value(&entity_labels, "/foo/bar", &metric_fields)
+                    .unwrap()
+                    .clone(),
+            );
+        }
+        assert_eq!(deltas, vec![Value::Int(10), Value::Int(15), Value::Int(5)]);
+    }
+
+    #[test]
+    fn test_metric_names_with_prefix() {
+        let exporter = Exporter::default();
+        for metric_name in ["/rpc/latency", "/db/queries", "/rpc/count", "/rpcs", "/rp"] {
+            exporter
+                .define_metric(metric_name, MetricConfig::default())
+                .unwrap();
+        }
+        assert_eq!(
+            exporter.metric_names_with_prefix("/rpc/"),
+            vec!["/rpc/count".to_string(), "/rpc/latency".to_string()]
+        );
+        assert_eq!(
+            exporter.metric_names_with_prefix("/db/"),
+            vec!["/db/queries"]
+        );
+        assert!(exporter.metric_names_with_prefix("/foo/").is_empty());
+        assert_eq!(exporter.metric_names_with_prefix("").len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_memory_budget() {
+        let clock = Arc::new(MockClock::default());
+        let exporter = Exporter {
+            clock: clock.clone(),
+            ..Exporter::default()
+        };
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields: Vec<FieldMap> = (0..5).map(|_| test_metric_fields()).collect();
+        let cell_size = Cell {
+            value: Value::Int(0),
+            start_timestamp: clock.now(),
+            update_timestamp: clock.now(),
+        }
+        .estimated_size(&metric_fields[0]);
+        exporter.set_memory_budget(cell_size * 3);
+        for (i, metric_fields) in metric_fields.iter().enumerate() {
+            let entity_labels = if i % 2 == 0 {
+                &entity_labels1
+            } else {
+                &entity_labels2
+            };
+            exporter
+                .set_int(entity_labels, "/foo/bar", i as i64, metric_fields)
+                .await
+                .unwrap();
+            clock.advance(Duration::from_secs(1)).await;
+        }
+        assert_eq!(exporter.evicted_cells(), 2);
+        assert_eq!(exporter.dropped_samples("/foo/bar"), 2);
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels1, "/foo/bar", &metric_fields[0])
+                .await,
+            None
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels2, "/foo/bar", &metric_fields[1])
+                .await,
+            None
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels1, "/foo/bar", &metric_fields[2])
+                .await,
+            Some(2)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels2, "/foo/bar", &metric_fields[3])
+                .await,
+            Some(3)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels1, "/foo/bar", &metric_fields[4])
+                .await,
+            Some(4)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_usage() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        assert_eq!(exporter.memory_usage(), 0);
+        exporter
+            .set_string(&entity_labels1, "/foo/bar", "lorem".into(), &metric_fields1)
+            .await
+            .unwrap();
+        let usage = exporter.memory_usage();
+        assert!(usage > 0);
+        exporter
+            .set_string(
+                &entity_labels1,
+                "/foo/bar",
+                "lorem ipsum".into(),
+                &metric_fields1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(exporter.memory_usage(), usage + " ipsum".len());
+        exporter
+            .add_to_int(&entity_labels2, "/foo/baz", 1, &metric_fields2)
+            .await
+            .unwrap();
+        let usage = exporter.memory_usage();
+        assert!(
+            exporter
+                .move_cell(
+                    &entity_labels2,
+                    &entity_labels1,
+                    "/foo/baz",
+                    &metric_fields2
+                )
+                .await
+        );
+        assert_eq!(exporter.memory_usage(), usage);
+        assert_eq!(
+            exporter.metric_memory_estimate("/foo/bar").await
+                + exporter.metric_memory_estimate("/foo/baz").await,
+            usage
+        );
+        assert!(
+            exporter
+                .delete_value(&entity_labels1, "/foo/bar", &metric_fields1)
+                .await
+                .is_some()
+        );
+        assert_eq!(
+            exporter.memory_usage(),
+            exporter.metric_memory_estimate("/foo/baz").await
+        );
+        assert_eq!(
+            exporter.delete_entity(&entity_labels1).await,
+            DeleteEntityStatus::Removed
+        );
+        assert_eq!(exporter.memory_usage(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_memory_budget() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        for i in 0..10 {
+            exporter
+                .set_int(&entity_labels, "/foo/bar", i, &test_metric_fields())
+                .await
+                .unwrap();
+        }
+        assert_eq!(exporter.evicted_cells(), 0);
+        assert_eq!(exporter.snapshot().await.iter().count(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_snapshot() {
+        async fn build(reverse: bool) -> String {
+            let exporter = Exporter {
+                clock: Arc::new(MockClock::default()),
+                ..Exporter::default()
+            };
+            exporter
+                .define_metric("/foo/bar", MetricConfig::default())
+                .unwrap();
+            exporter
+                .define_metric("/foo/baz", MetricConfig::default())
+                .unwrap();
+            let exporter = Pin::new(&exporter);
+            let entity_labels = [
+                FieldMap::from([
+                    ("lorem", FieldValue::Str("ipsum".into())),
+                    ("dolor", FieldValue::Int(1)),
+                ]),
+                FieldMap::from([
+                    ("dolor", FieldValue::Int(1)),
+                    ("lorem", FieldValue::Str("amet".into())),
+                ]),
+            ];
+            let metric_fields = [
+                FieldMap::from([
+                    ("code", FieldValue::Int(200)),
+                    ("ok", FieldValue::Bool(true)),
+                ]),
+                FieldMap::from([
+                    ("ok", FieldValue::Bool(false)),
+                    ("code", FieldValue::Int(500)),
+                ]),
+                FieldMap::from([("code", FieldValue::Str("200".into()))]),
+                FieldMap::from([("code", FieldValue::Bool(true))]),
+            ];
+            let mut writes = vec![];
+            for entity_labels in &entity_labels {
+                for metric_name in ["/foo/bar", "/foo/baz"] {
+                    for (i, metric_fields) in metric_fields.iter().enumerate() {
+                        writes.push((entity_labels, metric_name, i as i64, metric_fields));
+                    }
+                }
+            }
+            if reverse {
+                writes.reverse();
+            }
+            for (entity_labels, metric_name, value, metric_fields) in writes {
+                exporter
+                    .set_int(entity_labels, metric_name, value, metric_fields)
+                    .await
+                    .unwrap();
+            }
+            format!("{:?}", exporter.snapshot().await)
+        }
+        assert_eq!(build(false).await, build(true).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_move_cell() {
+        let clock = Arc::new(MockClock::default());
+        let exporter = Exporter {
+            clock: clock.clone(),
+            ..Exporter::default()
+        };
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let from_labels = test_entity_labels();
+        let to_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_int(&from_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        let start_timestamp = clock.now();
+        clock.advance(Duration::from_secs(10)).await;
+        assert!(
+            exporter
+                .move_cell(&from_labels, &to_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+        assert_eq!(
+            exporter
+                .get_int(&from_labels, "/foo/bar", &metric_fields)
+                .await,
+            None
+        );
+        let snapshot = exporter.snapshot().await;
+        let cell = snapshot
+            .get_cell(&to_labels, "/foo/bar", &metric_fields)
+            .unwrap();
+        assert_eq!(*cell.value(), Value::Int(42));
+        assert_eq!(cell.start_timestamp(), start_timestamp);
+        assert_eq!(snapshot.iter().count(), 1);
+        assert!(exporter.get_ephemeral_entity(&from_labels).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_move_cell_memory_usage() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let from_labels = test_entity_labels();
+        let to_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_string(
+                &from_labels,
+                "/foo/bar",
+                "lorem ipsum".into(),
+                &metric_fields,
+            )
+            .await
+            .unwrap();
+        let usage = exporter.memory_usage();
+        exporter
+            .set_string(&to_labels, "/foo/bar", "lorem".into(), &metric_fields)
+            .await
+            .unwrap();
+        assert!(
+            exporter
+                .move_cell(&from_labels, &to_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+        assert_eq!(exporter.memory_usage(), usage);
+        assert_eq!(exporter.metric_memory_estimate("/foo/bar").await, usage);
+    }
+
+    #[tokio::test]
+    async fn test_move_missing_cell() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::default())
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let from_labels = test_entity_labels();
+        let to_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        assert!(
+            !exporter
+                .move_cell(&from_labels, &to_labels, "/foo/bar", &metric_fields1)
+                .await
+        );
+        exporter
+            .set_int(&from_labels, "/foo/bar", 42, &metric_fields1)
+            .await
+            .unwrap();
+        assert!(
+            !exporter
+                .move_cell(&from_labels, &to_labels, "/foo/bar", &metric_fields2)
+                .await
+        );
+        assert_eq!(
+            exporter
+                .get_int(&from_labels, "/foo/bar", &metric_fields1)
+                .await,
+            Some(42)
+        );
+        assert!(exporter.get_ephemeral_entity(&to_labels).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clear_distribution_not_a_distribution() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = FieldMap::from([("lorem", FieldValue::Int(1))]);
+        let metric_fields2 = FieldMap::from([("lorem", FieldValue::Int(2))]);
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields1)
+            .await
+            .unwrap();
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 43, &metric_fields2)
+            .await
+            .unwrap();
+        assert!(
+            !exporter
+                .clear_distribution(&entity_labels, "/foo/bar", &metric_fields1)
+                .await
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields1)
+                .await,
+            Some(42)
+        );
+        assert_eq!(
+            exporter
+                .get_int(&entity_labels, "/foo/bar", &metric_fields2)
+                .await,
+            Some(43)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clear_distribution() {
+        let clock = Arc::new(MockClock::default());
+        let exporter = Exporter {
+            clock: clock.clone(),
+            ..Exporter::default()
+        };
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(
+            !exporter
+                .clear_distribution(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+        exporter
+            .add_many_to_distribution(&entity_labels, "/foo/bar", 42.0, 3, &metric_fields)
+            .await
+            .unwrap();
+        let start_timestamp = clock.now();
+        clock.advance(Duration::from_secs(10)).await;
+        assert!(
+            exporter
+                .clear_distribution(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+        assert!(
+            exporter
+                .contains_cell(&entity_labels, "/foo/bar", &metric_fields)
+                .await
+        );
+        let snapshot = exporter.snapshot().await;
+        let cell = snapshot
+            .get_cell(&entity_labels, "/foo/bar", &metric_fields)
+            .unwrap();
+        match cell.value() {
+            Value::Dist(value) => {
+                assert_eq!(value.count(), 0);
+                assert_eq!(value.bucketer(), Bucketer::default().into());
+            }
+            _ => panic!(),
+        };
+        assert_eq!(cell.start_timestamp(), start_timestamp);
+        assert_eq!(cell.update_timestamp(), clock.now());
+    }
 }
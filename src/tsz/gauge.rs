@@ -1,98 +1,317 @@
-use crate::tsz::{FieldMap, config::MetricConfig, distribution::Distribution, exporter::EXPORTER};
+#[cfg(not(feature = "no-global-exporter"))]
+use crate::tsz::exporter::EXPORTER;
+use crate::tsz::{
+    FieldMap, bucketer::Bucketer, config::MetricConfig, distribution::Distribution, exporter,
+    exporter::Exporter,
+};
 use crate::utils::lazy::Lazy;
+use anyhow::anyhow;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-pub trait Value: Debug + Send + Sync {}
+pub trait Value: Debug + Clone + Send + Sync {
+    /// Wraps the value in the corresponding `exporter::Value` variant.
+    fn into_exporter_value(self) -> exporter::Value;
 
-impl Value for bool {}
-impl Value for i64 {}
-impl Value for f64 {}
-impl Value for String {}
-impl Value for Distribution {}
+    /// Unwraps the corresponding `exporter::Value` variant. Panics on any other variant.
+    fn from_exporter_value(value: exporter::Value) -> Self;
+}
+
+impl Value for bool {
+    fn into_exporter_value(self) -> exporter::Value {
+        exporter::Value::Bool(self)
+    }
+
+    fn from_exporter_value(value: exporter::Value) -> Self {
+        match value {
+            exporter::Value::Bool(value) => value,
+            _ => panic!(),
+        }
+    }
+}
+
+impl Value for i64 {
+    fn into_exporter_value(self) -> exporter::Value {
+        exporter::Value::Int(self)
+    }
+
+    fn from_exporter_value(value: exporter::Value) -> Self {
+        match value {
+            exporter::Value::Int(value) => value,
+            _ => panic!(),
+        }
+    }
+}
+
+impl Value for f64 {
+    fn into_exporter_value(self) -> exporter::Value {
+        exporter::Value::Float(self.into())
+    }
+
+    fn from_exporter_value(value: exporter::Value) -> Self {
+        match value {
+            exporter::Value::Float(value) => value.value,
+            _ => panic!(),
+        }
+    }
+}
+
+impl Value for String {
+    fn into_exporter_value(self) -> exporter::Value {
+        exporter::Value::Str(self)
+    }
+
+    fn from_exporter_value(value: exporter::Value) -> Self {
+        match value {
+            exporter::Value::Str(value) => value,
+            _ => panic!(),
+        }
+    }
+}
+
+impl Value for Distribution {
+    fn into_exporter_value(self) -> exporter::Value {
+        exporter::Value::Dist(self)
+    }
+
+    fn from_exporter_value(value: exporter::Value) -> Self {
+        match value {
+            exporter::Value::Dist(value) => value,
+            _ => panic!(),
+        }
+    }
+}
 
 #[derive(Debug)]
 struct GaugeImpl<V: Value> {
+    exporter: Pin<&'static Exporter<'static>>,
     name: &'static str,
     _value: PhantomData<V>,
 }
 
 impl<V: Value> GaugeImpl<V> {
-    fn new(name: &'static str, config: MetricConfig) -> Self {
-        EXPORTER.define_metric_redundant(name, config);
+    fn new(
+        exporter: Pin<&'static Exporter<'static>>,
+        name: &'static str,
+        config: MetricConfig,
+    ) -> Self {
+        exporter.define_metric_redundant(name, config);
         Self {
+            exporter,
             name,
             _value: PhantomData::default(),
         }
     }
 
+    async fn exists(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.exporter
+            .contains_cell(entity_labels, self.name, metric_fields)
+            .await
+    }
+
     async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_value(entity_labels, self.name, metric_fields)
             .await
             .is_some()
     }
 
     async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
-        EXPORTER
+        self.exporter
             .delete_metric_from_entity(entity_labels, self.name)
             .await
     }
+
+    async fn set_many(
+        &self,
+        entity_labels: &FieldMap,
+        values: BTreeMap<FieldMap, exporter::Value>,
+    ) {
+        self.exporter
+            .set_values(entity_labels, self.name, values)
+            .await
+            .ok();
+    }
+
+    async fn delete_all(&self) -> usize {
+        self.exporter.delete_metric(self.name).await
+    }
+
+    async fn set_at(
+        &self,
+        entity_labels: &FieldMap,
+        value: V,
+        timestamp: SystemTime,
+        metric_fields: &FieldMap,
+    ) {
+        self.exporter
+            .set_value_at(
+                entity_labels,
+                self.name,
+                value.into_exporter_value(),
+                timestamp,
+                metric_fields,
+            )
+            .await
+            .ok();
+    }
+
+    async fn get_or_insert_with(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        init: impl FnOnce() -> V,
+    ) -> V {
+        // Keep `init` around so that we can still call it if the exporter rejects the write before
+        // reaching the cell.
+        let mut init = Some(init);
+        let result = self
+            .exporter
+            .get_or_insert_value(entity_labels, self.name, metric_fields, || {
+                init.take().unwrap()().into_exporter_value()
+            })
+            .await;
+        match result {
+            Ok(value) => V::from_exporter_value(value),
+            Err(_) => init.take().unwrap()(),
+        }
+    }
 }
 
 impl GaugeImpl<bool> {
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<bool> {
-        EXPORTER
+        self.exporter
             .get_bool(entity_labels, self.name, metric_fields)
             .await
     }
 
     async fn set(&self, entity_labels: &FieldMap, value: bool, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .set_bool(entity_labels, self.name, value, metric_fields)
-            .await;
+            .await
+            .ok();
     }
 }
 
 impl GaugeImpl<i64> {
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
-        EXPORTER
+        self.exporter
             .get_int(entity_labels, self.name, metric_fields)
             .await
     }
 
     async fn set(&self, entity_labels: &FieldMap, value: i64, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .set_int(entity_labels, self.name, value, metric_fields)
+            .await
+            .ok();
+    }
+
+    async fn compare_and_set(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        expected: Option<i64>,
+        value: i64,
+    ) -> bool {
+        self.exporter
+            .compare_and_set_value(
+                entity_labels,
+                self.name,
+                metric_fields,
+                expected.map(exporter::Value::Int).as_ref(),
+                exporter::Value::Int(value),
+            )
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn current_distribution(&self, bucketer: &'static Bucketer) -> Distribution {
+        let mut distribution = Distribution::new(bucketer.into());
+        let snapshot = self
+            .exporter
+            .snapshot_filtered(|metric_name| metric_name == self.name)
             .await;
+        for (.., cell) in snapshot.iter() {
+            match cell.value() {
+                exporter::Value::Int(value) => distribution.record(*value as f64),
+                _ => panic!(),
+            }
+        }
+        distribution
+    }
+
+    async fn update(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        f: impl FnOnce(Option<i64>) -> i64,
+    ) {
+        self.exporter
+            .update_value(entity_labels, self.name, metric_fields, |value| {
+                let value = match value {
+                    None => None,
+                    Some(exporter::Value::Int(value)) => Some(value),
+                    Some(value) => return Err(anyhow!("{:?} is not an integer", value)),
+                };
+                Ok(exporter::Value::Int(f(value)))
+            })
+            .await
+            .ok();
     }
 }
 
 impl GaugeImpl<f64> {
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<f64> {
-        EXPORTER
+        self.exporter
             .get_float(entity_labels, self.name, metric_fields)
             .await
     }
 
     async fn set(&self, entity_labels: &FieldMap, value: f64, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .set_float(entity_labels, self.name, value, metric_fields)
-            .await;
+            .await
+            .ok();
+    }
+
+    async fn update(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        f: impl FnOnce(Option<f64>) -> f64,
+    ) {
+        self.exporter
+            .update_value(entity_labels, self.name, metric_fields, |value| {
+                let value = match value {
+                    None => None,
+                    Some(exporter::Value::Float(value)) => Some(value.value),
+                    Some(value) => return Err(anyhow!("{:?} is not a float", value)),
+                };
+                Ok(exporter::Value::Float(f(value).into()))
+            })
+            .await
+            .ok();
     }
 }
 
 impl GaugeImpl<String> {
     async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<String> {
-        EXPORTER
+        self.exporter
             .get_string(entity_labels, self.name, metric_fields)
             .await
     }
 
     async fn set(&self, entity_labels: &FieldMap, value: String, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .set_string(entity_labels, self.name, value, metric_fields)
-            .await;
+            .await
+            .ok();
     }
 }
 
@@ -102,34 +321,78 @@ impl GaugeImpl<Distribution> {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) -> Option<Distribution> {
-        EXPORTER
+        self.exporter
             .get_distribution(entity_labels, self.name, metric_fields)
             .await
     }
 
     async fn set(&self, entity_labels: &FieldMap, value: Distribution, metric_fields: &FieldMap) {
-        EXPORTER
+        self.exporter
             .set_distribution(entity_labels, self.name, value, metric_fields)
-            .await;
+            .await
+            .ok();
     }
 }
 
+/// A value returned by `Gauge::get` and the (monotonic) time it was read at.
+type CachedRead<V> = (Instant, Option<V>);
+
 #[derive(Debug)]
 pub struct Gauge<V: Value> {
     name: &'static str,
     config: MetricConfig,
+    /// See `with_read_cache`. Zero disables the cache.
+    read_cache_ttl: Duration,
+    /// Values returned by `get`, keyed by entity labels and metric fields.
+    read_cache: Mutex<BTreeMap<(FieldMap, FieldMap), CachedRead<V>>>,
+    /// Bumped by every invalidation, so that a `get` racing with a write doesn't cache the value it
+    /// read before the write.
+    read_cache_generation: AtomicU64,
+    /// Number of values cached so far, used to prune the expired ones periodically.
+    read_cache_inserts: AtomicU64,
     inner: Lazy<GaugeImpl<V>>,
 }
 
 impl<V: Value> Gauge<V> {
+    /// Expired entries are pruned from the read cache once every this many insertions, so that the
+    /// cost of scanning the cache is amortized across cache misses.
+    const READ_CACHE_PRUNE_PERIOD: u64 = 64;
+
+    #[cfg(not(feature = "no-global-exporter"))]
     pub fn new(name: &'static str, config: MetricConfig) -> Self {
+        Self::new_with(EXPORTER.get_ref(), name, config)
+    }
+
+    /// Creates a gauge exported by `exporter` rather than the global exporter.
+    pub fn new_with(
+        exporter: &'static Exporter<'static>,
+        name: &'static str,
+        config: MetricConfig,
+    ) -> Self {
+        let exporter = Pin::new(exporter);
         Self {
             name,
             config,
-            inner: Lazy::new(move || GaugeImpl::<V>::new(name, config)),
+            read_cache_ttl: Duration::ZERO,
+            read_cache: Mutex::default(),
+            read_cache_generation: AtomicU64::default(),
+            read_cache_inserts: AtomicU64::default(),
+            inner: Lazy::new(move || GaugeImpl::<V>::new(exporter, name, config)),
         }
     }
 
+    /// Makes `get` remember the value it read for each cell and return it again, without locking
+    /// the exporter, until `ttl` has elapsed on the clock of the exporter. Meant for hot gauges
+    /// polled at high frequency, e.g. by dashboards.
+    ///
+    /// Values read through the cache may be up to `ttl` stale with respect to writes made by other
+    /// means (e.g. another `Gauge` object for the same metric, or the exporter directly). Writes
+    /// made through this object invalidate the affected cells, so they're always read back.
+    pub fn with_read_cache(mut self, ttl: Duration) -> Self {
+        self.read_cache_ttl = ttl;
+        self
+    }
+
     pub fn name(&self) -> &'static str {
         self.name
     }
@@ -138,52 +401,274 @@ impl<V: Value> Gauge<V> {
         &self.config
     }
 
+    /// Returns the cached value of the cell if it was read less than `read_cache_ttl` ago,
+    /// otherwise awaits `read` and caches its result.
+    async fn cached_get(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        read: impl Future<Output = Option<V>>,
+    ) -> Option<V> {
+        if self.read_cache_ttl.is_zero() {
+            return read.await;
+        }
+        let key = (entity_labels.clone(), metric_fields.clone());
+        let now = self.inner.exporter.clock().monotonic_now();
+        if let Some((_, value)) = self
+            .read_cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(time, _)| now < *time + self.read_cache_ttl)
+        {
+            return value.clone();
+        }
+        let generation = self.read_cache_generation.load(Ordering::Acquire);
+        let value = read.await;
+        let mut read_cache = self.read_cache.lock().unwrap();
+        if self.read_cache_generation.load(Ordering::Acquire) == generation {
+            let inserts = self.read_cache_inserts.fetch_add(1, Ordering::Relaxed) + 1;
+            if inserts.is_multiple_of(Self::READ_CACHE_PRUNE_PERIOD) {
+                read_cache.retain(|_, (time, _)| now < *time + self.read_cache_ttl);
+            }
+            read_cache.insert(key, (now, value.clone()));
+        }
+        value
+    }
+
+    /// Must be called after writing the cell, so that a concurrent `get` can't cache the value from
+    /// before the write.
+    fn invalidate(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.read_cache_ttl.is_zero() {
+            self.read_cache_generation.fetch_add(1, Ordering::AcqRel);
+            self.read_cache
+                .lock()
+                .unwrap()
+                .remove(&(entity_labels.clone(), metric_fields.clone()));
+        }
+    }
+
+    fn invalidate_all(&self) {
+        if !self.read_cache_ttl.is_zero() {
+            self.read_cache_generation.fetch_add(1, Ordering::AcqRel);
+            self.read_cache.lock().unwrap().clear();
+        }
+    }
+
+    /// Checks whether the gauge is set for the specified cell without fetching its value.
+    pub async fn exists(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
+        self.inner.exists(entity_labels, metric_fields).await
+    }
+
     pub async fn delete(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> bool {
-        self.inner.delete(entity_labels, metric_fields).await
+        let deleted = self.inner.delete(entity_labels, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
+        deleted
     }
 
     pub async fn delete_entity(&self, entity_labels: &FieldMap) -> bool {
-        self.inner.delete_entity(entity_labels).await
+        let deleted = self.inner.delete_entity(entity_labels).await;
+        self.invalidate_all();
+        deleted
+    }
+
+    /// Sets the gauge for several metric field maps of the same entity at once. This is much
+    /// cheaper than setting them one by one because the entity is looked up and locked only once.
+    pub async fn set_many(
+        &self,
+        entity_labels: &FieldMap,
+        values: impl IntoIterator<Item = (FieldMap, V)>,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        let values = values
+            .into_iter()
+            .map(|(metric_fields, value)| (metric_fields, value.into_exporter_value()))
+            .collect();
+        self.inner.set_many(entity_labels, values).await;
+        self.invalidate_all();
+    }
+
+    /// Deletes every cell of the gauge across all entities. Returns the number of cells removed.
+    pub async fn clear_all(&self) -> usize {
+        let count = self.inner.delete_all().await;
+        self.invalidate_all();
+        count
+    }
+
+    /// Returns the current value, first setting it to the result of `init` if the gauge isn't set.
+    /// Unlike a `get` followed by a `set`, this is atomic with respect to other writes to the
+    /// entity, so `init` runs at most once for a given cell. If the gauge is disabled `init` is
+    /// called and its result returned without being stored.
+    pub async fn get_or_insert_with(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        init: impl FnOnce() -> V,
+    ) -> V {
+        if !self.config.enabled() {
+            return init();
+        }
+        let value = self
+            .inner
+            .get_or_insert_with(entity_labels, metric_fields, init)
+            .await;
+        self.invalidate(entity_labels, metric_fields);
+        value
+    }
+
+    /// Sets the gauge with an explicit update timestamp rather than the current time, e.g. to
+    /// backfill historical values. Timestamps too far in the future are rejected, see
+    /// `Exporter::set_max_timestamp_skew`.
+    pub async fn set_at(
+        &self,
+        value: V,
+        timestamp: SystemTime,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner
+            .set_at(entity_labels, value, timestamp, metric_fields)
+            .await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
 impl Gauge<bool> {
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<bool> {
-        self.inner.get(entity_labels, metric_fields).await
+        self.cached_get(
+            entity_labels,
+            metric_fields,
+            self.inner.get(entity_labels, metric_fields),
+        )
+        .await
     }
 
     pub async fn set(&self, value: bool, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.set(entity_labels, value, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
 impl Gauge<i64> {
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<i64> {
-        self.inner.get(entity_labels, metric_fields).await
+        self.cached_get(
+            entity_labels,
+            metric_fields,
+            self.inner.get(entity_labels, metric_fields),
+        )
+        .await
     }
 
     pub async fn set(&self, value: i64, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.set(entity_labels, value, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
+    }
+
+    /// Sets the gauge to `value` only if its current value is `expected`, with `None` meaning that
+    /// the cell must not be set. Returns whether the value was set. The comparison and the write
+    /// are atomic with respect to other writes to the entity.
+    pub async fn compare_and_set(
+        &self,
+        expected: Option<i64>,
+        value: i64,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> bool {
+        if !self.config.enabled() {
+            return false;
+        }
+        let applied = self
+            .inner
+            .compare_and_set(entity_labels, metric_fields, expected, value)
+            .await;
+        self.invalidate(entity_labels, metric_fields);
+        applied
+    }
+
+    /// Returns the distribution of the current values of all cells of the gauge across all
+    /// entities, e.g. the distribution of queue depths right now. This is computed on the fly from
+    /// a snapshot of the exporter and isn't stored anywhere.
+    pub async fn current_distribution(&self, bucketer: &'static Bucketer) -> Distribution {
+        self.inner.current_distribution(bucketer).await
+    }
+
+    /// Replaces the value with the result of `f`, which receives the current value (or `None` if
+    /// not set). The read and the write are atomic with respect to other writes to the entity.
+    pub async fn update(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        f: impl FnOnce(Option<i64>) -> i64,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner.update(entity_labels, metric_fields, f).await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
 impl Gauge<f64> {
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<f64> {
-        self.inner.get(entity_labels, metric_fields).await
+        self.cached_get(
+            entity_labels,
+            metric_fields,
+            self.inner.get(entity_labels, metric_fields),
+        )
+        .await
     }
 
     pub async fn set(&self, value: f64, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.set(entity_labels, value, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
+    }
+
+    /// Replaces the value with the result of `f`, which receives the current value (or `None` if
+    /// not set). The read and the write are atomic with respect to other writes to the entity.
+    pub async fn update(
+        &self,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+        f: impl FnOnce(Option<f64>) -> f64,
+    ) {
+        if !self.config.enabled() {
+            return;
+        }
+        self.inner.update(entity_labels, metric_fields, f).await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
 impl Gauge<String> {
     pub async fn get(&self, entity_labels: &FieldMap, metric_fields: &FieldMap) -> Option<String> {
-        self.inner.get(entity_labels, metric_fields).await
+        self.cached_get(
+            entity_labels,
+            metric_fields,
+            self.inner.get(entity_labels, metric_fields),
+        )
+        .await
     }
 
     pub async fn set(&self, value: String, entity_labels: &FieldMap, metric_fields: &FieldMap) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.set(entity_labels, value, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
@@ -193,7 +678,12 @@ impl Gauge<Distribution> {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) -> Option<Distribution> {
-        self.inner.get(entity_labels, metric_fields).await
+        self.cached_get(
+            entity_labels,
+            metric_fields,
+            self.inner.get(entity_labels, metric_fields),
+        )
+        .await
     }
 
     pub async fn set(
@@ -202,7 +692,11 @@ impl Gauge<Distribution> {
         entity_labels: &FieldMap,
         metric_fields: &FieldMap,
     ) {
+        if !self.config.enabled() {
+            return;
+        }
         self.inner.set(entity_labels, value, metric_fields).await;
+        self.invalidate(entity_labels, metric_fields);
     }
 }
 
@@ -210,7 +704,12 @@ impl Gauge<Distribution> {
 mod tests {
     use super::*;
     use crate::tsz::{testing::test_entity_labels, testing::test_metric_fields};
+    use crate::utils::clock::{Clock, test::MockClock};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_new() {
         let config = MetricConfig::default();
@@ -228,6 +727,14 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_gauge_config() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
+        assert_eq!(*gauge.config(), MetricConfig::gauge());
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_custom_config() {
         let config = MetricConfig::default()
@@ -240,6 +747,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_bool() {
         let gauge = Gauge::<bool>::new("/foo/bar/gauge/bool", MetricConfig::default());
@@ -255,6 +763,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_int() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge/int", MetricConfig::default());
@@ -270,6 +779,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_float() {
         let gauge = Gauge::<f64>::new("/foo/bar/gauge/float", MetricConfig::default());
@@ -285,6 +795,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_string() {
         let gauge = Gauge::<String>::new("/foo/bar/gauge/string", MetricConfig::default());
@@ -305,6 +816,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_distribution() {
         let gauge =
@@ -332,6 +844,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_twice() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -348,6 +861,21 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_exists() {
+        let gauge =
+            Gauge::<Distribution>::new("/foo/bar/gauge/distribution", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(!gauge.exists(&entity_labels, &metric_fields).await);
+        gauge
+            .set(Distribution::default(), &entity_labels, &metric_fields)
+            .await;
+        assert!(gauge.exists(&entity_labels, &metric_fields).await);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -363,6 +891,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -379,6 +908,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_after_deletion() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -396,6 +926,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_missing_entity() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -419,6 +950,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_entity() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -444,6 +976,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_delete_another_entity() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -469,6 +1002,7 @@ mod tests {
         );
     }
 
+    #[cfg(not(feature = "no-global-exporter"))]
     #[tokio::test]
     async fn test_set_after_entity_deletion() {
         let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default());
@@ -494,4 +1028,443 @@ mod tests {
                 .is_none()
         );
     }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_clear_all() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge/clear_all", MetricConfig::default());
+        let entity_labels1 = test_entity_labels();
+        let entity_labels2 = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge.set(12, &entity_labels1, &metric_fields).await;
+        gauge.set(34, &entity_labels2, &metric_fields).await;
+        assert_eq!(gauge.clear_all().await, 2);
+        assert!(gauge.get(&entity_labels1, &metric_fields).await.is_none());
+        assert!(gauge.get(&entity_labels2, &metric_fields).await.is_none());
+        assert!(
+            EXPORTER
+                .get_int(&entity_labels2, "/foo/bar/gauge/clear_all", &metric_fields)
+                .await
+                .is_none()
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_set_many() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge", MetricConfig::default())
+            .with_read_cache(Duration::from_secs(10));
+        let individual = Gauge::<i64>::new("/foo/bar/gauge/individual", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        let metric_fields3 = test_metric_fields();
+        gauge.set(78, &entity_labels, &metric_fields1).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields1).await, Some(78));
+        gauge
+            .set_many(
+                &entity_labels,
+                [
+                    (metric_fields1.clone(), 12),
+                    (metric_fields2.clone(), 34),
+                    (metric_fields3.clone(), 56),
+                ],
+            )
+            .await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields1).await, Some(12));
+        assert_eq!(gauge.get(&entity_labels, &metric_fields2).await, Some(34));
+        assert_eq!(gauge.get(&entity_labels, &metric_fields3).await, Some(56));
+        individual.set(12, &entity_labels, &metric_fields1).await;
+        individual.set(34, &entity_labels, &metric_fields2).await;
+        individual.set(56, &entity_labels, &metric_fields3).await;
+        for metric_fields in [&metric_fields1, &metric_fields2, &metric_fields3] {
+            assert_eq!(
+                gauge.get(&entity_labels, metric_fields).await,
+                individual.get(&entity_labels, metric_fields).await
+            );
+        }
+        let snapshot = EXPORTER.snapshot().await;
+        let timestamps: Vec<_> = [&metric_fields1, &metric_fields2, &metric_fields3]
+            .into_iter()
+            .map(|metric_fields| {
+                snapshot
+                    .get_cell(&entity_labels, "/foo/bar/gauge", metric_fields)
+                    .unwrap()
+                    .update_timestamp()
+            })
+            .collect();
+        assert_eq!(timestamps[0], timestamps[1]);
+        assert_eq!(timestamps[1], timestamps[2]);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_update() {
+        let gauge: &'static Gauge<i64> = Box::leak(Box::new(Gauge::new(
+            "/foo/bar/gauge/update",
+            MetricConfig::default(),
+        )));
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge.set(0, &entity_labels, &metric_fields).await;
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let entity_labels = entity_labels.clone();
+                let metric_fields = metric_fields.clone();
+                tokio::spawn(async move {
+                    gauge
+                        .update(&entity_labels, &metric_fields, |value| value.unwrap() + 1)
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(2));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_get_or_insert_with() {
+        let gauge = Gauge::<String>::new("/foo/bar/gauge/seeded", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            gauge
+                .get_or_insert_with(&entity_labels, &metric_fields, || "lorem".into())
+                .await,
+            "lorem"
+        );
+        assert_eq!(
+            gauge
+                .get_or_insert_with(&entity_labels, &metric_fields, || "ipsum".into())
+                .await,
+            "lorem"
+        );
+        assert_eq!(
+            gauge.get(&entity_labels, &metric_fields).await,
+            Some("lorem".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_get_or_insert_with() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let gauge: &'static Gauge<i64> = Box::leak(Box::new(Gauge::new_with(
+            exporter,
+            "/foo/bar/gauge/seeded",
+            MetricConfig::default(),
+        )));
+        let calls: &'static AtomicUsize = Box::leak(Box::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let tasks: Vec<_> = (0..2)
+            .map(|i| {
+                let entity_labels = entity_labels.clone();
+                let metric_fields = metric_fields.clone();
+                tokio::spawn(async move {
+                    gauge
+                        .get_or_insert_with(&entity_labels, &metric_fields, || {
+                            calls.fetch_add(1, Ordering::Relaxed);
+                            i
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let mut values = vec![];
+        for task in tasks {
+            values.push(task.await.unwrap());
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(values[0], values[1]);
+        assert_eq!(
+            gauge.get(&entity_labels, &metric_fields).await,
+            Some(values[0])
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_get_or_insert_with_disabled() {
+        let gauge = Gauge::<i64>::new(
+            "/foo/bar/gauge/seeded/disabled",
+            MetricConfig::default().set_enabled(false),
+        );
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            gauge
+                .get_or_insert_with(&entity_labels, &metric_fields, || 42)
+                .await,
+            42
+        );
+        assert!(!gauge.exists(&entity_labels, &metric_fields).await);
+    }
+
+    async fn get_update_timestamp(
+        exporter: &Exporter<'_>,
+        entity_labels: &FieldMap,
+        metric_fields: &FieldMap,
+    ) -> SystemTime {
+        exporter
+            .snapshot()
+            .await
+            .get_cell(entity_labels, "/foo/bar/gauge", metric_fields)
+            .unwrap()
+            .update_timestamp()
+    }
+
+    #[tokio::test]
+    async fn test_set_at() {
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+        ));
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let gauge = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        let past = clock.now() - Duration::from_secs(3600);
+        gauge.set_at(42, past, &entity_labels, &metric_fields).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(42));
+        assert_eq!(
+            get_update_timestamp(exporter, &entity_labels, &metric_fields).await,
+            past
+        );
+        let future = clock.now() + Duration::from_secs(3600);
+        gauge
+            .set_at(43, future, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(42));
+        assert_eq!(
+            get_update_timestamp(exporter, &entity_labels, &metric_fields).await,
+            past
+        );
+        assert_eq!(exporter.total_dropped_samples(), 1);
+        exporter.set_max_timestamp_skew(Duration::from_secs(7200));
+        gauge
+            .set_at(43, future, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(43));
+        assert_eq!(
+            get_update_timestamp(exporter, &entity_labels, &metric_fields).await,
+            future
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_cache() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let gauge = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default())
+            .with_read_cache(Duration::from_secs(10));
+        let other = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge.set(42, &entity_labels, &metric_fields).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(42));
+        other.set(43, &entity_labels, &metric_fields).await;
+        clock.advance(Duration::from_secs(9)).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(42));
+        clock.advance(Duration::from_secs(1)).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(43));
+        gauge.set(44, &entity_labels, &metric_fields).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(44));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_cache_pruning() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let gauge = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default())
+            .with_read_cache(Duration::from_secs(10));
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        gauge.set(1, &entity_labels, &metric_fields1).await;
+        gauge.set(2, &entity_labels, &metric_fields2).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields1).await, Some(1));
+        assert_eq!(gauge.read_cache.lock().unwrap().len(), 1);
+        clock.advance(Duration::from_secs(10)).await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields2).await, Some(2));
+        assert_eq!(gauge.read_cache.lock().unwrap().len(), 2);
+        let period = Gauge::<i64>::READ_CACHE_PRUNE_PERIOD as usize;
+        for _ in 2..period {
+            gauge.get(&entity_labels, &test_metric_fields()).await;
+        }
+        let read_cache = gauge.read_cache.lock().unwrap();
+        assert_eq!(read_cache.len(), period - 1);
+        assert!(!read_cache.contains_key(&(entity_labels.clone(), metric_fields1.clone())));
+        assert!(read_cache.contains_key(&(entity_labels.clone(), metric_fields2.clone())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_cache_write_during_read() {
+        let clock = Arc::new(MockClock::default());
+        let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+        let gauge = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default())
+            .with_read_cache(Duration::from_secs(10));
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge.set(1, &entity_labels, &metric_fields).await;
+        let value = gauge
+            .cached_get(&entity_labels, &metric_fields, async {
+                let value = gauge.inner.get(&entity_labels, &metric_fields).await;
+                gauge.set(2, &entity_labels, &metric_fields).await;
+                value
+            })
+            .await;
+        assert_eq!(value, Some(1));
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(2));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_compare_and_set() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge/leader", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(
+            gauge
+                .compare_and_set(None, 1, &entity_labels, &metric_fields)
+                .await
+        );
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(1));
+        assert!(
+            gauge
+                .compare_and_set(Some(1), 2, &entity_labels, &metric_fields)
+                .await
+        );
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(2));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_compare_and_set_mismatch() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge/leader", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert!(
+            !gauge
+                .compare_and_set(Some(1), 2, &entity_labels, &metric_fields)
+                .await
+        );
+        assert!(!gauge.exists(&entity_labels, &metric_fields).await);
+        gauge.set(3, &entity_labels, &metric_fields).await;
+        assert!(
+            !gauge
+                .compare_and_set(Some(1), 2, &entity_labels, &metric_fields)
+                .await
+        );
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(3));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_compare_and_set_expected_missing() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge/leader", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge.set(3, &entity_labels, &metric_fields).await;
+        assert!(
+            !gauge
+                .compare_and_set(None, 2, &entity_labels, &metric_fields)
+                .await
+        );
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(3));
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_current_distribution() {
+        let gauge = Gauge::<i64>::new("/foo/bar/gauge/depth", MetricConfig::default());
+        let metric_fields = test_metric_fields();
+        for value in [1, 5, 9] {
+            gauge
+                .set(value, &test_entity_labels(), &metric_fields)
+                .await;
+        }
+        let bucketer = Bucketer::fixed_width(2.0, 5);
+        let distribution = gauge.current_distribution(bucketer).await;
+        assert_eq!(distribution.count(), 3);
+        assert_eq!(distribution.sum(), 15.0);
+        assert_eq!(distribution.bucket(0), 1);
+        assert_eq!(distribution.bucket(1), 0);
+        assert_eq!(distribution.bucket(2), 1);
+        assert_eq!(distribution.bucket(3), 0);
+        assert_eq!(distribution.bucket(4), 1);
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_update_float() {
+        let gauge = Gauge::<f64>::new("/foo/bar/gauge/float", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        gauge
+            .update(&entity_labels, &metric_fields, |value| {
+                assert_eq!(value, None);
+                8.0
+            })
+            .await;
+        gauge
+            .update(&entity_labels, &metric_fields, |value| value.unwrap() * 0.5)
+            .await;
+        assert_eq!(gauge.get(&entity_labels, &metric_fields).await, Some(4.0));
+    }
+
+    #[tokio::test]
+    async fn test_update_type_mismatch() {
+        let exporter: &'static Exporter = Box::leak(Box::default());
+        let gauge = Gauge::<i64>::new_with(exporter, "/foo/bar/gauge", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        Pin::new(exporter)
+            .set_string(
+                &entity_labels,
+                "/foo/bar/gauge",
+                "lorem".into(),
+                &metric_fields,
+            )
+            .await
+            .unwrap();
+        gauge
+            .update(&entity_labels, &metric_fields, |value| {
+                value.unwrap_or(0) + 1
+            })
+            .await;
+        assert_eq!(
+            exporter
+                .get_string(&entity_labels, "/foo/bar/gauge", &metric_fields)
+                .await,
+            Some("lorem".into())
+        );
+    }
+
+    #[cfg(not(feature = "no-global-exporter"))]
+    #[tokio::test]
+    async fn test_set_many_strings() {
+        let gauge = Gauge::<String>::new("/foo/bar/gauge/string", MetricConfig::default());
+        let entity_labels = test_entity_labels();
+        let metric_fields1 = test_metric_fields();
+        let metric_fields2 = test_metric_fields();
+        gauge
+            .set_many(
+                &entity_labels,
+                [
+                    (metric_fields1.clone(), "lorem".to_string()),
+                    (metric_fields2.clone(), "ipsum".to_string()),
+                ],
+            )
+            .await;
+        assert_eq!(
+            gauge.get(&entity_labels, &metric_fields1).await,
+            Some("lorem".to_string())
+        );
+        assert_eq!(
+            gauge.get(&entity_labels, &metric_fields2).await,
+            Some("ipsum".to_string())
+        );
+    }
 }
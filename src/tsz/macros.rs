@@ -0,0 +1,136 @@
+/// Declares a static `tsz::counter::Counter` exported by the global exporter, e.g.:
+///
+///   define_counter! {
+///       /// Number of RPCs served, by status code.
+///       pub static RPC_COUNT = "/rpc/count", MetricConfig::default();
+///   }
+///
+///   RPC_COUNT.increment(&entity_labels, &metric_fields).await;
+///
+/// The static is a `LazyLock`, so the counter is created (and its config registered with the
+/// exporter) on first use. Doc comments and other attributes are forwarded to the static. The
+/// config may be omitted, in which case `MetricConfig::default()` is used. Not available with
+/// the `no-global-exporter` feature.
+#[macro_export]
+macro_rules! define_counter {
+    ($(#[$attr:meta])* $vis:vis static $ident:ident = $name:literal;) => {
+        $crate::define_counter! {
+            $(#[$attr])*
+            $vis static $ident = $name, $crate::tsz::config::MetricConfig::default();
+        }
+    };
+    ($(#[$attr:meta])* $vis:vis static $ident:ident = $name:literal, $config:expr $(,)?;) => {
+        $(#[$attr])*
+        $vis static $ident: ::std::sync::LazyLock<$crate::tsz::counter::Counter> =
+            ::std::sync::LazyLock::new(|| $crate::tsz::counter::Counter::new($name, $config));
+    };
+}
+
+/// Declares a static `tsz::gauge::Gauge<V>`, see `define_counter`. The value type goes after the
+/// name of the static, e.g. `pub static QUEUE_DEPTH: i64 = "/queue/depth";`.
+#[macro_export]
+macro_rules! define_gauge {
+    ($(#[$attr:meta])* $vis:vis static $ident:ident: $value:ty = $name:literal;) => {
+        $crate::define_gauge! {
+            $(#[$attr])*
+            $vis static $ident: $value = $name, $crate::tsz::config::MetricConfig::default();
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis static $ident:ident: $value:ty = $name:literal, $config:expr $(,)?;
+    ) => {
+        $(#[$attr])*
+        $vis static $ident: ::std::sync::LazyLock<$crate::tsz::gauge::Gauge<$value>> =
+            ::std::sync::LazyLock::new(|| {
+                $crate::tsz::gauge::Gauge::<$value>::new($name, $config)
+            });
+    };
+}
+
+/// Declares a static `tsz::event_metric::EventMetric`, see `define_counter`.
+#[macro_export]
+macro_rules! define_event_metric {
+    ($(#[$attr:meta])* $vis:vis static $ident:ident = $name:literal;) => {
+        $crate::define_event_metric! {
+            $(#[$attr])*
+            $vis static $ident = $name, $crate::tsz::config::MetricConfig::default();
+        }
+    };
+    ($(#[$attr:meta])* $vis:vis static $ident:ident = $name:literal, $config:expr $(,)?;) => {
+        $(#[$attr])*
+        $vis static $ident: ::std::sync::LazyLock<$crate::tsz::event_metric::EventMetric> =
+            ::std::sync::LazyLock::new(|| {
+                $crate::tsz::event_metric::EventMetric::new($name, $config)
+            });
+    };
+}
+
+#[cfg(all(test, not(feature = "no-global-exporter")))]
+mod tests {
+    use crate::tsz::{
+        bucketer::Bucketer,
+        config::MetricConfig,
+        distribution::Distribution,
+        testing::{test_entity_labels, test_metric_fields},
+    };
+
+    crate::define_counter! {
+        /// A counter with the default config.
+        static TEST_COUNTER = "/foo/bar/macro/counter";
+    }
+
+    crate::define_gauge! {
+        static TEST_GAUGE: i64 = "/foo/bar/macro/gauge", MetricConfig::default();
+    }
+
+    crate::define_event_metric! {
+        static TEST_EVENT_METRIC = "/foo/bar/macro/event_metric",
+            MetricConfig::default().set_bucketer(Bucketer::fixed_width(1.0, 10));
+    }
+
+    #[tokio::test]
+    async fn test_define_counter() {
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(TEST_COUNTER.name(), "/foo/bar/macro/counter");
+        TEST_COUNTER.increment(&entity_labels, &metric_fields).await;
+        TEST_COUNTER
+            .increment_by(2, &entity_labels, &metric_fields)
+            .await;
+        assert_eq!(
+            TEST_COUNTER.get(&entity_labels, &metric_fields).await,
+            Some(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_define_gauge() {
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        TEST_GAUGE.set(42, &entity_labels, &metric_fields).await;
+        assert_eq!(
+            TEST_GAUGE.get(&entity_labels, &metric_fields).await,
+            Some(42)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_define_event_metric() {
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        assert_eq!(
+            TEST_EVENT_METRIC.bucketer(),
+            Bucketer::fixed_width(1.0, 10).into()
+        );
+        TEST_EVENT_METRIC
+            .record(2.5, &entity_labels, &metric_fields)
+            .await;
+        let mut expected = Distribution::new(Bucketer::fixed_width(1.0, 10).into());
+        expected.record(2.5);
+        assert_eq!(
+            TEST_EVENT_METRIC.get(&entity_labels, &metric_fields).await,
+            Some(expected)
+        );
+    }
+}
@@ -1,14 +1,20 @@
+use std::collections::BTreeSet;
 use std::ops::Index;
-
-mod exporter;
+use std::sync::{Arc, LazyLock, Mutex};
 
 pub mod bucketer;
+#[cfg(not(feature = "no-global-exporter"))]
 pub mod buffered;
 pub mod config;
 pub mod counter;
 pub mod distribution;
 pub mod event_metric;
+pub mod exporter;
 pub mod gauge;
+mod macros;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod push;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum FieldValue {
@@ -17,59 +23,205 @@ pub enum FieldValue {
     Str(String),
 }
 
+impl FieldValue {
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            FieldValue::Bool(_) => FieldType::Bool,
+            FieldValue::Int(_) => FieldType::Int,
+            FieldValue::Str(_) => FieldType::Str,
+        }
+    }
+}
+
+/// The type of a `FieldValue`, used to declare field schemas (see `MetricConfig::set_fields`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FieldType {
+    Bool,
+    Int,
+    Str,
+}
+
+/// Pool of the keys of interned `FieldMap`s, see `FieldMap::interned`.
+static KEY_POOL: LazyLock<Mutex<BTreeSet<Arc<str>>>> = LazyLock::new(Mutex::default);
+
+fn intern_key(key: &str) -> Arc<str> {
+    let mut pool = KEY_POOL.lock().unwrap();
+    if let Some(key) = pool.get(key) {
+        key.clone()
+    } else {
+        let key: Arc<str> = key.into();
+        pool.insert(key.clone());
+        key
+    }
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FieldMap {
-    data: Vec<(String, FieldValue)>,
+    data: Vec<(Arc<str>, FieldValue)>,
 }
 
 impl FieldMap {
+    /// Builds a map from the given entries. The entries are sorted by key, so the result doesn't
+    /// depend on their order, except in case of duplicate keys where the first occurrence wins.
     pub fn from<const N: usize>(entries: [(&str, FieldValue); N]) -> Self {
-        let mut data = vec![];
-        for (key, value) in entries {
-            data.push((key.into(), value));
-        }
-        data.sort_unstable_by(
-            |(lhs, _): &(String, FieldValue), (rhs, _): &(String, FieldValue)| lhs.cmp(rhs),
-        );
-        let mut i = 1;
-        while i < data.len() {
-            let (key1, _) = &data[i - 1];
-            let (key2, _) = &data[i];
-            if key1 == key2 {
-                data.remove(i);
-            } else {
-                i += 1;
-            }
-        }
+        Self::from_entries(entries.into_iter().map(|(key, value)| (key.into(), value)))
+    }
+
+    /// Like `from`, but the keys are taken from a process-wide pool so that all interned maps
+    /// share a single allocation per distinct key. This saves memory when many cells or entities
+    /// repeat the same keys (e.g. `"tenant"`).
+    ///
+    /// Interned and non-interned maps are interchangeable: they compare and look up identically.
+    /// The pool never shrinks, so only intern keys drawn from a bounded set.
+    pub fn interned<const N: usize>(entries: [(&str, FieldValue); N]) -> Self {
+        Self::from_entries(
+            entries
+                .into_iter()
+                .map(|(key, value)| (intern_key(key), value)),
+        )
+    }
+
+    fn from_entries(entries: impl Iterator<Item = (Arc<str>, FieldValue)>) -> Self {
+        let mut data: Vec<(Arc<str>, FieldValue)> = entries.collect();
+        data.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+        data.dedup_by(|(key2, _), (key1, _)| key1 == key2);
         Self { data }
     }
-}
 
-impl Index<&str> for FieldMap {
-    type Output = FieldValue;
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
 
-    fn index(&self, index: &str) -> &Self::Output {
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Iterates over the fields in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &FieldValue)> {
+        self.data.iter().map(|(key, value)| (key.as_ref(), value))
+    }
+
+    /// Returns the value associated to `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&FieldValue> {
         let mut i = 0;
         let mut j = self.data.len();
         while i < j {
             let k = i + ((j - i) >> 1);
-            let (key, value) = &self.data[k];
-            if index < key.as_str() {
+            let (current, value) = &self.data[k];
+            if key < current.as_ref() {
                 j = k;
-            } else if index > key.as_str() {
+            } else if key > current.as_ref() {
                 i = k + 1;
             } else {
-                return value;
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// Returns a map with only the fields named in `keys`. Keys that aren't in this map are omitted.
+    /// Useful to roll up over a subset of dimensions.
+    pub fn project(&self, keys: &[&str]) -> FieldMap {
+        Self {
+            data: self
+                .data
+                .iter()
+                .filter(|(key, _)| keys.contains(&key.as_ref()))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Compares two maps disregarding the fields named in `ignore`, e.g. to deduplicate cells that
+    /// differ only by a high-cardinality key such as a request ID.
+    pub fn eq_ignoring(&self, other: &FieldMap, ignore: &[&str]) -> bool {
+        let retained = |(key, _): &&(Arc<str>, FieldValue)| !ignore.contains(&key.as_ref());
+        self.data
+            .iter()
+            .filter(retained)
+            .eq(other.data.iter().filter(retained))
+    }
+
+    /// Returns a hash of the map that is stable across runs, builds, and platforms, unlike the ones
+    /// computed with `DefaultHasher`. Suitable for routing entities to shards consistently.
+    ///
+    /// The hash is 64-bit FNV-1a over the fields in key order. Keys and strings are prefixed with
+    /// their length and values with their type, so that different maps don't trivially collide
+    /// (e.g. `{"ab": "c"}` and `{"a": "bc"}`). The encoding must never change, or existing shard
+    /// assignments would be reshuffled.
+    pub fn stable_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut feed = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+        feed(&(self.data.len() as u64).to_le_bytes());
+        for (key, value) in &self.data {
+            feed(&(key.len() as u64).to_le_bytes());
+            feed(key.as_bytes());
+            match value {
+                FieldValue::Bool(value) => feed(&[0, *value as u8]),
+                FieldValue::Int(value) => {
+                    feed(&[1]);
+                    feed(&value.to_le_bytes());
+                }
+                FieldValue::Str(value) => {
+                    feed(&[2]);
+                    feed(&(value.len() as u64).to_le_bytes());
+                    feed(value.as_bytes());
+                }
             }
         }
-        panic!()
+        hash
+    }
+
+    /// Checks that this map has exactly the fields declared in `schema`, with the declared types.
+    pub fn matches_schema(&self, schema: &[(&str, FieldType)]) -> bool {
+        self.data.len() == schema.len()
+            && schema.iter().all(|(key, field_type)| {
+                self.get(key)
+                    .is_some_and(|value| value.field_type() == *field_type)
+            })
     }
 }
 
+impl Index<&str> for FieldMap {
+    type Output = FieldValue;
+
+    fn index(&self, index: &str) -> &Self::Output {
+        self.get(index).unwrap()
+    }
+}
+
+/// Initializes tsz, starting the background task that periodically flushes the buffered metrics
+/// into the global exporter. Must be called from within a tokio runtime. Calling it more than once
+/// is harmless: there's only ever one flush loop.
 pub async fn init() {
+    #[cfg(not(feature = "no-global-exporter"))]
     crate::tsz::buffered::init().await;
 }
 
+/// Opts into metrics about tsz itself, all exported by the global exporter under `/tsz/`:
+/// `entity_count` and `cell_count` (gauges), `dropped_samples` (a counter of the writes dropped
+/// across all metrics, see `Exporter::dropped_samples`), and `flush_latency_ms` (a distribution of
+/// the durations of the flush cycles). They're refreshed after every flush cycle, so they're only
+/// useful once `init` has been called. Idempotent.
+pub async fn install_self_metrics() {
+    #[cfg(not(feature = "no-global-exporter"))]
+    crate::tsz::buffered::install_self_metrics().await;
+}
+
+/// Shuts tsz down, stopping the flush loop started by `init` and draining all buffered metrics into
+/// the global exporter so that no data is lost. Idempotent, and `init` may be called again after.
+pub async fn shutdown() {
+    #[cfg(not(feature = "no-global-exporter"))]
+    crate::tsz::buffered::shutdown().await;
+}
+
 #[cfg(test)]
 pub mod testing {
     use crate::tsz::{FieldMap, FieldValue};
@@ -158,6 +310,104 @@ mod tests {
         assert_ne!(map2, map3);
     }
 
+    #[test]
+    fn test_get() {
+        let map = FieldMap::from([
+            ("lorem", FieldValue::Bool(true)),
+            ("ipsum", FieldValue::Int(42)),
+        ]);
+        assert_eq!(map.len(), 2);
+        assert!(!map.is_empty());
+        assert_eq!(map.get("lorem"), Some(&FieldValue::Bool(true)));
+        assert_eq!(map.get("ipsum"), Some(&FieldValue::Int(42)));
+        assert_eq!(map.get("dolor"), None);
+        assert!(FieldMap::from([]).is_empty());
+    }
+
+    #[test]
+    fn test_eq_ignoring() {
+        let map1 = FieldMap::from([
+            ("a", FieldValue::Int(1)),
+            ("id", FieldValue::Str("x".into())),
+        ]);
+        let map2 = FieldMap::from([
+            ("a", FieldValue::Int(1)),
+            ("id", FieldValue::Str("y".into())),
+        ]);
+        assert_ne!(map1, map2);
+        assert!(map1.eq_ignoring(&map2, &["id"]));
+        assert!(!map1.eq_ignoring(&map2, &[]));
+        assert!(!map1.eq_ignoring(&map2, &["a"]));
+        assert!(map1.eq_ignoring(&FieldMap::from([("a", FieldValue::Int(1))]), &["id"]));
+        assert!(!map1.eq_ignoring(&FieldMap::from([("a", FieldValue::Int(2))]), &["id"]));
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        let map1 = FieldMap::from([
+            ("lorem", FieldValue::Str("ipsum".into())),
+            ("dolor", FieldValue::Int(42)),
+        ]);
+        let map2 = FieldMap::interned([
+            ("dolor", FieldValue::Int(42)),
+            ("lorem", FieldValue::Str("ipsum".into())),
+        ]);
+        assert_eq!(map1.stable_hash(), map2.stable_hash());
+        assert_ne!(
+            map1.stable_hash(),
+            FieldMap::from([("lorem", FieldValue::Str("ipsum".into()))]).stable_hash()
+        );
+        assert_ne!(
+            FieldMap::from([("ab", FieldValue::Str("c".into()))]).stable_hash(),
+            FieldMap::from([("a", FieldValue::Str("bc".into()))]).stable_hash()
+        );
+        assert_ne!(
+            FieldMap::from([("a", FieldValue::Int(1))]).stable_hash(),
+            FieldMap::from([("a", FieldValue::Bool(true))]).stable_hash()
+        );
+        assert_eq!(FieldMap::default().stable_hash(), 0xa8c7f832281a39c5);
+    }
+
+    #[test]
+    fn test_project() {
+        let map = FieldMap::from([
+            ("a", FieldValue::Bool(true)),
+            ("b", FieldValue::Int(42)),
+            ("c", FieldValue::Str("amet".into())),
+        ]);
+        assert_eq!(
+            map.project(&["c", "a"]),
+            FieldMap::from([
+                ("a", FieldValue::Bool(true)),
+                ("c", FieldValue::Str("amet".into())),
+            ])
+        );
+        assert_eq!(
+            map.project(&["b", "d"]),
+            FieldMap::from([("b", FieldValue::Int(42))])
+        );
+        assert!(map.project(&["d"]).is_empty());
+        assert!(map.project(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_matches_schema() {
+        let map = FieldMap::from([
+            ("code", FieldValue::Int(200)),
+            ("method", FieldValue::Str("GET".into())),
+        ]);
+        assert!(map.matches_schema(&[("code", FieldType::Int), ("method", FieldType::Str)]));
+        assert!(map.matches_schema(&[("method", FieldType::Str), ("code", FieldType::Int)]));
+        assert!(!map.matches_schema(&[("coed", FieldType::Int), ("method", FieldType::Str)]));
+        assert!(!map.matches_schema(&[("code", FieldType::Str), ("method", FieldType::Str)]));
+        assert!(!map.matches_schema(&[("code", FieldType::Int)]));
+        assert!(!map.matches_schema(&[
+            ("code", FieldType::Int),
+            ("method", FieldType::Str),
+            ("path", FieldType::Str),
+        ]));
+    }
+
     #[test]
     fn test_duplicates() {
         let map = FieldMap::from([
@@ -166,8 +416,38 @@ mod tests {
             ("lorem", FieldValue::Int(123)),
             ("dolor", FieldValue::Str("amet".into())),
         ]);
-        assert!(map["lorem"] == FieldValue::Bool(true) || map["lorem"] == FieldValue::Int(123));
+        assert_eq!(map["lorem"], FieldValue::Bool(true));
         assert_eq!(map["ipsum"], FieldValue::Int(42));
         assert_eq!(map["dolor"], FieldValue::Str("amet".into()));
     }
+
+    #[test]
+    fn test_interned() {
+        let map1 = FieldMap::interned([
+            ("lorem", FieldValue::Bool(true)),
+            ("ipsum", FieldValue::Int(42)),
+            ("lorem", FieldValue::Int(123)),
+        ]);
+        let map2 = FieldMap::from([
+            ("ipsum", FieldValue::Int(42)),
+            ("lorem", FieldValue::Bool(true)),
+        ]);
+        assert_eq!(map1, map2);
+        assert_eq!(map1.cmp(&map2), std::cmp::Ordering::Equal);
+        assert_eq!(map1.len(), 2);
+        assert_eq!(map1["lorem"], FieldValue::Bool(true));
+        assert_eq!(map1.get("ipsum"), map2.get("ipsum"));
+        assert_eq!(map1.get("dolor"), None);
+        assert_eq!(
+            map1.iter().collect::<Vec<_>>(),
+            map2.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_interned_keys_are_shared() {
+        let map1 = FieldMap::interned([("tenant", FieldValue::Int(1))]);
+        let map2 = FieldMap::interned([("tenant", FieldValue::Int(2))]);
+        assert!(Arc::ptr_eq(&map1.data[0].0, &map2.data[0].0));
+    }
 }
@@ -0,0 +1,374 @@
+use crate::tsz::{
+    FieldMap, FieldValue,
+    distribution::Distribution,
+    exporter::{Cell, Exporter, Value},
+};
+use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue, any_value};
+use opentelemetry_proto::tonic::metrics::v1::{
+    AggregationTemporality, Gauge, Histogram, HistogramDataPoint, Metric, MetricsData,
+    NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum, metric, number_data_point,
+};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn attribute(key: &str, value: &FieldValue) -> KeyValue {
+    let value = match value {
+        FieldValue::Bool(value) => any_value::Value::BoolValue(*value),
+        FieldValue::Int(value) => any_value::Value::IntValue(*value),
+        FieldValue::Str(value) => any_value::Value::StringValue(value.clone()),
+    };
+    KeyValue {
+        key: key.into(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+/// Prepended to the keys of the metric fields that have the same name as an entity label.
+const COLLIDING_FIELD_PREFIX: &str = "field.";
+
+/// OTLP has no notion of entities, so the entity labels and the metric fields are flattened into a
+/// single list of attributes, entity labels first. OTLP requires attribute keys to be unique, so
+/// metric fields named like an entity label get `COLLIDING_FIELD_PREFIX` prepended to their key.
+fn attributes(entity_labels: &FieldMap, metric_fields: &FieldMap) -> Vec<KeyValue> {
+    let fields = metric_fields.iter().map(|(key, value)| {
+        if entity_labels.get(key).is_some() {
+            attribute(&format!("{}{}", COLLIDING_FIELD_PREFIX, key), value)
+        } else {
+            attribute(key, value)
+        }
+    });
+    entity_labels
+        .iter()
+        .map(|(key, value)| attribute(key, value))
+        .chain(fields)
+        .collect()
+}
+
+/// Returns None for strings, which OTLP number points can't represent. Booleans become 0 or 1.
+fn number_data_point(
+    entity_labels: &FieldMap,
+    metric_fields: &FieldMap,
+    cell: &Cell,
+) -> Option<NumberDataPoint> {
+    let value = match cell.value() {
+        Value::Bool(value) => number_data_point::Value::AsInt(*value as i64),
+        Value::Int(value) => number_data_point::Value::AsInt(*value),
+        Value::Float(value) => number_data_point::Value::AsDouble(value.value),
+        _ => return None,
+    };
+    Some(NumberDataPoint {
+        attributes: attributes(entity_labels, metric_fields),
+        start_time_unix_nano: unix_nanos(cell.start_timestamp()),
+        time_unix_nano: unix_nanos(cell.update_timestamp()),
+        value: Some(value),
+        ..Default::default()
+    })
+}
+
+/// Our underflow bucket, finite buckets, and overflow bucket map exactly onto the OTLP buckets
+/// delimited by the `num_finite_buckets + 1` boundaries of the bucketer.
+fn histogram_data_point(
+    entity_labels: &FieldMap,
+    metric_fields: &FieldMap,
+    cell: &Cell,
+    distribution: &Distribution,
+) -> HistogramDataPoint {
+    let bucketer = distribution.bucketer();
+    let num_finite_buckets = distribution.num_finite_buckets();
+    let explicit_bounds = (0..=num_finite_buckets)
+        .map(|i| bucketer.lower_bound(i as isize))
+        .collect();
+    let bucket_counts = std::iter::once(distribution.underflow())
+        .chain((0..num_finite_buckets).map(|i| distribution.bucket(i)))
+        .chain(std::iter::once(distribution.overflow()))
+        .map(|count| count as u64)
+        .collect();
+    HistogramDataPoint {
+        attributes: attributes(entity_labels, metric_fields),
+        start_time_unix_nano: unix_nanos(cell.start_timestamp()),
+        time_unix_nano: unix_nanos(cell.update_timestamp()),
+        count: distribution.count() as u64,
+        sum: Some(distribution.sum()),
+        bucket_counts,
+        explicit_bounds,
+        ..Default::default()
+    }
+}
+
+/// Converts the current content of the exporter to OTLP metrics. Cumulative int and float metrics
+/// (i.e. counters) become Sums, which are monotonic only if the metric rejects negative deltas,
+/// other scalar metrics become Gauges, and distributions become Histograms with explicit bounds
+/// taken from their bucketer. String cells are skipped because OTLP has no string-valued points.
+///
+/// The content is read with `Exporter::export_snapshot`, so the int and float cells of metrics with
+/// `delta_mode` hold the change since the previous export and are reported as Sums with delta
+/// temporality.
+pub async fn to_otlp(exporter: &Exporter<'_>) -> MetricsData {
+    let snapshot = exporter.export_snapshot().await;
+    let mut cells_by_metric: BTreeMap<&str, Vec<(&FieldMap, &FieldMap, &Cell)>> = BTreeMap::new();
+    for (entity_labels, metric_name, metric_fields, cell) in snapshot.iter() {
+        cells_by_metric
+            .entry(metric_name)
+            .or_default()
+            .push((entity_labels, metric_fields, cell));
+    }
+    let metrics = cells_by_metric
+        .into_iter()
+        .filter_map(|(metric_name, cells)| {
+            let config = exporter
+                .get_metric_config(metric_name)
+                .copied()
+                .unwrap_or_default();
+            let data = if let Value::Dist(_) = cells[0].2.value() {
+                metric::Data::Histogram(Histogram {
+                    data_points: cells
+                        .iter()
+                        .filter_map(|(entity_labels, metric_fields, cell)| match cell.value() {
+                            Value::Dist(distribution) => Some(histogram_data_point(
+                                entity_labels,
+                                metric_fields,
+                                cell,
+                                distribution,
+                            )),
+                            _ => None,
+                        })
+                        .collect(),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                })
+            } else {
+                let data_points: Vec<NumberDataPoint> = cells
+                    .iter()
+                    .filter_map(|(entity_labels, metric_fields, cell)| {
+                        number_data_point(entity_labels, metric_fields, cell)
+                    })
+                    .collect();
+                if data_points.is_empty() {
+                    return None;
+                }
+                if config.delta_mode {
+                    metric::Data::Sum(Sum {
+                        data_points,
+                        aggregation_temporality: AggregationTemporality::Delta as i32,
+                        is_monotonic: config.cumulative && config.reject_negative_deltas,
+                    })
+                } else if config.cumulative {
+                    metric::Data::Sum(Sum {
+                        data_points,
+                        aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                        is_monotonic: config.reject_negative_deltas,
+                    })
+                } else {
+                    metric::Data::Gauge(Gauge { data_points })
+                }
+            };
+            Some(Metric {
+                name: metric_name.into(),
+                data: Some(data),
+                ..Default::default()
+            })
+        })
+        .collect();
+    MetricsData {
+        resource_metrics: vec![ResourceMetrics {
+            scope_metrics: vec![ScopeMetrics {
+                metrics,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsz::{
+        bucketer::Bucketer,
+        config::MetricConfig,
+        testing::{test_entity_labels, test_metric_fields},
+    };
+    use std::pin::Pin;
+
+    #[tokio::test]
+    async fn test_counter_to_otlp() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .add_to_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        let data = to_otlp(&exporter).await;
+        let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name, "/foo/bar");
+        match &metrics[0].data {
+            Some(metric::Data::Sum(sum)) => {
+                assert!(!sum.is_monotonic);
+                assert_eq!(
+                    sum.aggregation_temporality,
+                    AggregationTemporality::Cumulative as i32
+                );
+                assert_eq!(sum.data_points.len(), 1);
+                let point = &sum.data_points[0];
+                assert_eq!(point.value, Some(number_data_point::Value::AsInt(42)));
+                assert_eq!(
+                    point.attributes.len(),
+                    entity_labels.len() + metric_fields.len()
+                );
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_monotonic_counter_to_otlp() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric(
+                "/foo/bar",
+                MetricConfig::counter().set_reject_negative_deltas(true),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        exporter
+            .add_to_int(&test_entity_labels(), "/foo/bar", 42, &test_metric_fields())
+            .await
+            .unwrap();
+        let data = to_otlp(&exporter).await;
+        let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+        match &metrics[0].data {
+            Some(metric::Data::Sum(sum)) => assert!(sum.is_monotonic),
+            _ => panic!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_delta_mode_to_otlp() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric("/foo/bar", MetricConfig::counter().set_delta_mode(true))
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for (delta, expected) in [(42, 42), (8, 8)] {
+            exporter
+                .add_to_int(&entity_labels, "/foo/bar", delta, &metric_fields)
+                .await
+                .unwrap();
+            let data = to_otlp(&exporter).await;
+            let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+            match &metrics[0].data {
+                Some(metric::Data::Sum(sum)) => {
+                    assert_eq!(
+                        sum.aggregation_temporality,
+                        AggregationTemporality::Delta as i32
+                    );
+                    assert_eq!(
+                        sum.data_points[0].value,
+                        Some(number_data_point::Value::AsInt(expected))
+                    );
+                }
+                _ => panic!(),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn test_colliding_attributes() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = FieldMap::from([("job", FieldValue::Str("lorem".into()))]);
+        let metric_fields = FieldMap::from([
+            ("job", FieldValue::Str("ipsum".into())),
+            ("code", FieldValue::Int(200)),
+        ]);
+        exporter
+            .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+            .await
+            .unwrap();
+        let data = to_otlp(&exporter).await;
+        let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+        match &metrics[0].data {
+            Some(metric::Data::Gauge(gauge)) => {
+                let keys: Vec<&str> = gauge.data_points[0]
+                    .attributes
+                    .iter()
+                    .map(|attribute| attribute.key.as_str())
+                    .collect();
+                assert_eq!(keys, vec!["job", "code", "field.job"]);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_distribution_to_otlp() {
+        let exporter = Exporter::default();
+        exporter
+            .define_metric(
+                "/foo/bar",
+                MetricConfig::distribution(Bucketer::fixed_width(1.0, 3)),
+            )
+            .unwrap();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        for sample in [-1.0, 0.5, 1.5, 1.5, 10.0] {
+            exporter
+                .add_to_distribution(&entity_labels, "/foo/bar", sample, &metric_fields)
+                .await
+                .unwrap();
+        }
+        let data = to_otlp(&exporter).await;
+        let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 1);
+        match &metrics[0].data {
+            Some(metric::Data::Histogram(histogram)) => {
+                assert_eq!(histogram.data_points.len(), 1);
+                let point = &histogram.data_points[0];
+                assert_eq!(point.count, 5);
+                assert_eq!(point.sum, Some(12.5));
+                assert_eq!(point.explicit_bounds, vec![0.0, 1.0, 2.0, 3.0]);
+                assert_eq!(point.bucket_counts, vec![1, 1, 2, 0, 1]);
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_gauge_to_otlp() {
+        let exporter = Exporter::default();
+        let exporter = Pin::new(&exporter);
+        let entity_labels = test_entity_labels();
+        let metric_fields = test_metric_fields();
+        exporter
+            .set_float(&entity_labels, "/foo/bar", 3.5, &metric_fields)
+            .await
+            .unwrap();
+        exporter
+            .set_string(&entity_labels, "/foo/baz", "lorem".into(), &metric_fields)
+            .await
+            .unwrap();
+        let data = to_otlp(&exporter).await;
+        let metrics = &data.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 1);
+        match &metrics[0].data {
+            Some(metric::Data::Gauge(gauge)) => {
+                assert_eq!(
+                    gauge.data_points[0].value,
+                    Some(number_data_point::Value::AsDouble(3.5))
+                );
+            }
+            _ => panic!(),
+        };
+    }
+}
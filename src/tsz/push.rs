@@ -0,0 +1,350 @@
+use crate::tsz::exporter::SnapshotView;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Mutex, atomic::AtomicU64, atomic::Ordering};
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// Why pushing a snapshot to a collector failed. The variants let callers tell apart failures that
+/// may go away on their own (transport errors and some server statuses) from the ones that won't,
+/// and decide whether to retry, keep the data for the next cycle, or drop it.
+#[derive(Debug)]
+pub enum PushError {
+    /// The snapshot couldn't be converted into a request. Retrying won't help.
+    Serialization(String),
+    /// The request didn't make it to the server, or its response didn't make it back.
+    Transport(String),
+    /// The server rejected the request.
+    Status(Status),
+}
+
+impl PushError {
+    /// Indicates whether the same push may succeed if attempted again. Transport errors are always
+    /// retryable, while server statuses are only if they denote a transient condition.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            PushError::Serialization(_) => false,
+            PushError::Transport(_) => true,
+            PushError::Status(status) => matches!(
+                status.code(),
+                Code::Unavailable
+                    | Code::DeadlineExceeded
+                    | Code::ResourceExhausted
+                    | Code::Aborted
+            ),
+        }
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Serialization(message) => write!(f, "serialization error: {}", message),
+            PushError::Transport(message) => write!(f, "transport error: {}", message),
+            PushError::Status(status) => write!(f, "push rejected: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+impl From<Status> for PushError {
+    fn from(status: Status) -> Self {
+        PushError::Status(status)
+    }
+}
+
+/// How many times and how often a failed push is retried. The delay before the n-th retry is
+/// `backoff * 2^(n - 1)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    pub fn set_max_retries(mut self, value: usize) -> Self {
+        self.max_retries = value;
+        self
+    }
+
+    pub fn set_backoff(mut self, value: Duration) -> Self {
+        self.backoff = value;
+        self
+    }
+
+    /// Runs `attempt` until it succeeds, fails with a non-retryable error, or `max_retries` retries
+    /// have failed. Returns the error of the last attempt in the latter two cases, so that the
+    /// caller can still decide to keep the data for the next cycle.
+    pub async fn run<T, F>(&self, mut attempt: impl FnMut() -> F) -> Result<T, PushError>
+    where
+        F: Future<Output = Result<T, PushError>>,
+    {
+        let mut delay = self.backoff;
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Ok(result) => return Ok(result),
+                Err(error) if error.is_retryable() && retries < self.max_retries => {
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    retries += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Pushes the snapshots left in `pending` by previous cycles, oldest first, and then `snapshot`,
+    /// running each push through `run`. If a push still fails with a retryable error after all
+    /// retries, that snapshot and the ones after it are kept in `pending` for the next cycle and the
+    /// error is returned. Snapshots failing with a non-retryable error are dropped, and the first
+    /// such error is returned once the other snapshots have been pushed.
+    ///
+    /// `pending` must have a single consumer: the queue is only locked while popping a snapshot and
+    /// while putting a failed one back, so concurrent calls on the same `pending` could push the
+    /// snapshots out of order.
+    pub async fn push_or_rebuffer<F>(
+        &self,
+        pending: &PendingSnapshots,
+        snapshot: SnapshotView,
+        mut push: impl FnMut(&SnapshotView) -> F,
+    ) -> Result<(), PushError>
+    where
+        F: Future<Output = Result<(), PushError>>,
+    {
+        pending.push_back(snapshot);
+        let mut result = Ok(());
+        while let Some(snapshot) = pending.pop_front() {
+            match self.run(|| push(&snapshot)).await {
+                Ok(()) => {}
+                Err(error) if error.is_retryable() => {
+                    pending.push_front(snapshot);
+                    return Err(error);
+                }
+                Err(error) => {
+                    pending.dropped.fetch_add(1, Ordering::Relaxed);
+                    if result.is_ok() {
+                        result = Err(error);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Snapshots that couldn't be pushed yet, kept to be pushed again in the next cycle (see
+/// `RetryPolicy::push_or_rebuffer`). The queue is bounded: when it's full the oldest snapshot is
+/// dropped to make room for the new one. It's meant to be drained by a single push loop.
+#[derive(Debug)]
+pub struct PendingSnapshots {
+    capacity: usize,
+    queue: Mutex<VecDeque<SnapshotView>>,
+    dropped: AtomicU64,
+}
+
+impl PendingSnapshots {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queue: Mutex::default(),
+            dropped: AtomicU64::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+
+    /// Returns the number of snapshots dropped so far, either because the queue was full or because
+    /// their push failed with a non-retryable error.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn push_back(&self, snapshot: SnapshotView) {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.is_empty() && queue.len() >= self.capacity {
+            queue.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.capacity > 0 {
+            queue.push_back(snapshot);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn push_front(&self, snapshot: SnapshotView) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < self.capacity {
+            queue.push_front(snapshot);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn pop_front(&self) -> Option<SnapshotView> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tsz::exporter::Exporter;
+    use crate::utils::clock::test::MockClock;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_retryable() {
+        assert!(!PushError::Serialization("lorem".into()).is_retryable());
+        assert!(PushError::Transport("lorem".into()).is_retryable());
+        assert!(PushError::from(Status::unavailable("lorem")).is_retryable());
+        assert!(!PushError::from(Status::invalid_argument("lorem")).is_retryable());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_transient_error() {
+        let attempts = AtomicUsize::default();
+        let start = tokio::time::Instant::now();
+        let result = RetryPolicy::default()
+            .run(|| async {
+                if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                    Err(PushError::Transport("connection reset".into()))
+                } else {
+                    Ok(42)
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retry_on_permanent_error() {
+        let attempts = AtomicUsize::default();
+        let result: Result<(), PushError> = RetryPolicy::default()
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(Status::invalid_argument("bad metric").into())
+            })
+            .await;
+        match result {
+            Err(PushError::Status(status)) => assert_eq!(status.code(), Code::InvalidArgument),
+            _ => panic!(),
+        }
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_give_up_after_max_retries() {
+        let attempts = AtomicUsize::default();
+        let result: Result<(), PushError> = RetryPolicy::default()
+            .set_max_retries(2)
+            .run(|| async {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Err(PushError::Transport("connection refused".into()))
+            })
+            .await;
+        assert!(matches!(result, Err(PushError::Transport(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rebuffer_after_transient_errors() {
+        let clock = Arc::new(MockClock::default());
+        let exporter = Exporter::with_clock(clock.clone());
+        let pending = PendingSnapshots::new(10);
+        let policy = RetryPolicy::default().set_max_retries(1);
+        let snapshot1 = exporter.snapshot().await;
+        clock.advance(Duration::from_secs(10)).await;
+        let snapshot2 = exporter.snapshot().await;
+        let result = policy
+            .push_or_rebuffer(&pending, snapshot1.clone(), |_| async {
+                Err(PushError::Transport("connection refused".into()))
+            })
+            .await;
+        assert!(matches!(result, Err(PushError::Transport(_))));
+        assert_eq!(pending.len(), 1);
+        let pushed: Mutex<Vec<SystemTime>> = Mutex::default();
+        policy
+            .push_or_rebuffer(&pending, snapshot2.clone(), |snapshot| {
+                pushed.lock().unwrap().push(snapshot.timestamp());
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert!(pending.is_empty());
+        assert_eq!(pending.dropped(), 0);
+        assert_eq!(
+            *pushed.lock().unwrap(),
+            vec![snapshot1.timestamp(), snapshot2.timestamp()]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_rebuffer_on_permanent_error() {
+        let exporter = Exporter::with_clock(Arc::new(MockClock::default()));
+        let pending = PendingSnapshots::new(10);
+        let attempts = AtomicUsize::default();
+        let result = RetryPolicy::default()
+            .push_or_rebuffer(&pending, exporter.snapshot().await, |_| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err(Status::invalid_argument("bad metric").into()) }
+            })
+            .await;
+        assert!(matches!(result, Err(PushError::Status(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        assert!(pending.is_empty());
+        assert_eq!(pending.dropped(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pending_snapshots_bounded() {
+        let clock = Arc::new(MockClock::default());
+        let exporter = Exporter::with_clock(clock.clone());
+        let pending = PendingSnapshots::new(2);
+        let mut timestamps = vec![];
+        for _ in 0..3 {
+            let snapshot = exporter.snapshot().await;
+            timestamps.push(snapshot.timestamp());
+            RetryPolicy::none()
+                .push_or_rebuffer(&pending, snapshot, |_| async {
+                    Err(PushError::Transport("connection refused".into()))
+                })
+                .await
+                .unwrap_err();
+            clock.advance(Duration::from_secs(10)).await;
+        }
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending.dropped(), 1);
+        assert_eq!(pending.pop_front().unwrap().timestamp(), timestamps[1]);
+        assert_eq!(pending.pop_front().unwrap().timestamp(), timestamps[2]);
+    }
+}
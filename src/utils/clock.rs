@@ -1,8 +1,13 @@
 use std::fmt::Debug;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 pub trait Clock: Debug + Send + Sync {
+    /// Returns the wall-clock time, used for timestamps. It may jump backward (e.g. on NTP
+    /// adjustments) so it's not suitable for measuring durations.
     fn now(&self) -> SystemTime;
+
+    /// Returns a monotonic time point, used for measuring durations.
+    fn monotonic_now(&self) -> Instant;
 }
 
 #[derive(Default, Debug)]
@@ -12,38 +17,74 @@ impl Clock for RealClock {
     fn now(&self) -> SystemTime {
         SystemTime::now()
     }
+
+    fn monotonic_now(&self) -> Instant {
+        Instant::now()
+    }
 }
 
-#[cfg(test)]
+/// Measures the time elapsed since its creation using the monotonic source of a `Clock`, so that
+/// wall-clock adjustments don't affect the result. Suitable for `EventMetric::record_duration`.
+#[derive(Debug)]
+pub struct Stopwatch<'a> {
+    clock: &'a dyn Clock,
+    start: Instant,
+}
+
+impl<'a> Stopwatch<'a> {
+    pub fn start(clock: &'a dyn Clock) -> Self {
+        Self {
+            clock,
+            start: clock.monotonic_now(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock
+            .monotonic_now()
+            .saturating_duration_since(self.start)
+    }
+}
+
+/// Clocks for tests. Besides unit tests, they're available to external test harnesses with the
+/// `test-util` feature, e.g. to drive an exporter deterministically with `Exporter::with_clock`.
+#[cfg(any(test, feature = "test-util"))]
 pub mod test {
     use super::*;
     use std::sync::Mutex;
-    use std::time::Duration;
 
+    /// A clock that only moves when told to. `advance` also advances the tokio clock, so it must
+    /// be called from a runtime whose time is paused (e.g. `#[tokio::test(start_paused = true)]`).
     #[derive(Debug)]
     pub struct MockClock {
         time: Mutex<SystemTime>,
+        instant: Mutex<Instant>,
     }
 
     impl MockClock {
         pub fn new(start_time: SystemTime) -> Self {
             Self {
                 time: Mutex::new(start_time),
+                instant: Mutex::new(Instant::now()),
             }
         }
 
         pub async fn advance(&self, delta: Duration) {
-            let mut lock = self.time.lock().unwrap();
-            *lock += delta;
+            *self.time.lock().unwrap() += delta;
+            *self.instant.lock().unwrap() += delta;
             tokio::time::advance(delta).await;
         }
+
+        /// Changes the wall-clock time without affecting the monotonic time, as an NTP adjustment
+        /// would.
+        pub fn set_time(&self, time: SystemTime) {
+            *self.time.lock().unwrap() = time;
+        }
     }
 
     impl Default for MockClock {
         fn default() -> Self {
-            Self {
-                time: Mutex::new(SystemTime::UNIX_EPOCH),
-            }
+            Self::new(SystemTime::UNIX_EPOCH)
         }
     }
 
@@ -51,15 +92,17 @@ pub mod test {
         fn now(&self) -> SystemTime {
             *self.time.lock().unwrap()
         }
+
+        fn monotonic_now(&self) -> Instant {
+            *self.instant.lock().unwrap()
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
     use test::MockClock;
-    use tokio::time::Instant;
 
     #[test]
     fn test_default_mock_clock() {
@@ -79,12 +122,38 @@ mod tests {
     #[tokio::test(start_paused = true)]
     async fn test_advance_mock_clock() {
         let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(456));
-        let start_instant = Instant::now();
+        let start_instant = tokio::time::Instant::now();
+        let start_monotonic = clock.monotonic_now();
         clock.advance(Duration::from_secs(789)).await;
         assert_eq!(
             clock.now(),
             SystemTime::UNIX_EPOCH + Duration::from_secs(1245)
         );
-        assert_eq!(Instant::now(), start_instant + Duration::from_secs(789));
+        assert_eq!(
+            tokio::time::Instant::now(),
+            start_instant + Duration::from_secs(789)
+        );
+        assert_eq!(
+            clock.monotonic_now(),
+            start_monotonic + Duration::from_secs(789)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stopwatch() {
+        let clock = MockClock::default();
+        let stopwatch = Stopwatch::start(&clock);
+        assert_eq!(stopwatch.elapsed(), Duration::ZERO);
+        clock.advance(Duration::from_secs(12)).await;
+        assert_eq!(stopwatch.elapsed(), Duration::from_secs(12));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stopwatch_ignores_backward_jump() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        let stopwatch = Stopwatch::start(&clock);
+        clock.advance(Duration::from_secs(5)).await;
+        clock.set_time(SystemTime::UNIX_EPOCH);
+        assert_eq!(stopwatch.elapsed(), Duration::from_secs(5));
     }
 }
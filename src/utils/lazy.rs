@@ -1,20 +1,17 @@
-use std::cell::UnsafeCell;
 use std::fmt::Debug;
 use std::ops::Deref;
-use std::sync::{Mutex, atomic::AtomicBool, atomic::Ordering};
+use std::sync::{Mutex, OnceLock};
 
 pub struct Lazy<V: Sync> {
-    initialized: AtomicBool,
     factory: Mutex<Option<Box<dyn (FnOnce() -> V) + Send>>>,
-    value: UnsafeCell<Option<V>>,
+    value: OnceLock<V>,
 }
 
 impl<V: Sync> Lazy<V> {
     pub fn new<F: (FnOnce() -> V) + Send + 'static>(factory: F) -> Self {
         Self {
-            initialized: AtomicBool::default(),
             factory: Mutex::new(Some(Box::new(factory))),
-            value: UnsafeCell::new(None),
+            value: OnceLock::new(),
         }
     }
 }
@@ -23,43 +20,67 @@ impl<V: Sync> Deref for Lazy<V> {
     type Target = V;
 
     fn deref(&self) -> &Self::Target {
-        if self.initialized.load(Ordering::Acquire) {
-            return unsafe { &*self.value.get() }.as_ref().unwrap();
-        }
-        {
-            let mut factory = self.factory.lock().unwrap();
-            if self.initialized.load(Ordering::Relaxed) {
-                return unsafe { &*self.value.get() }.as_ref().unwrap();
-            }
-            let value = factory.take().unwrap()();
-            unsafe {
-                *self.value.get() = Some(value);
-            }
-            self.initialized.store(true, Ordering::Release);
-        }
-        unsafe { &*self.value.get() }.as_ref().unwrap()
+        // `OnceLock` runs the initializer at most once and blocks concurrent callers until it
+        // completes, so the factory is always available here.
+        self.value
+            .get_or_init(|| self.factory.lock().unwrap().take().unwrap()())
     }
 }
 
 impl<V: Sync> Debug for Lazy<V> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Lazy")
-            .field("initialized", &self.initialized)
-            .field("value", &self.value)
+            .field("initialized", &self.value.get().is_some())
             .finish()
     }
 }
 
-unsafe impl<V: Send + Sync> Send for Lazy<V> {}
-unsafe impl<V: Sync> Sync for Lazy<V> {}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Barrier, atomic::AtomicUsize, atomic::Ordering};
 
     #[test]
     fn test_lazy() {
         let lazy = Lazy::new(|| 42);
         assert_eq!(*lazy, 42);
     }
+
+    #[test]
+    fn test_debug() {
+        let lazy = Lazy::new(|| 42);
+        assert_eq!(format!("{:?}", lazy), "Lazy { initialized: false }");
+        assert_eq!(*lazy, 42);
+        assert_eq!(format!("{:?}", lazy), "Lazy { initialized: true }");
+    }
+
+    #[test]
+    fn test_concurrent_deref() {
+        const NUM_THREADS: usize = 16;
+        let calls = Arc::new(AtomicUsize::default());
+        let lazy = {
+            let calls = calls.clone();
+            Arc::new(Lazy::new(move || {
+                calls.fetch_add(1, Ordering::Relaxed);
+                42
+            }))
+        };
+        let barrier = Arc::new(Barrier::new(NUM_THREADS));
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let lazy = lazy.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..1000 {
+                        assert_eq!(**lazy, 42);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
 }
@@ -0,0 +1,69 @@
+//! Drives an exporter with a mock clock through the public API, as an external test harness would.
+//! Requires the `test-util` feature.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tsdb2::tsz::{
+    FieldMap, FieldValue, config::MetricConfig, counter::Counter, exporter::Exporter,
+};
+use tsdb2::utils::clock::test::MockClock;
+
+#[tokio::test(start_paused = true)]
+async fn test_exporter_with_mock_clock() {
+    let clock = Arc::new(MockClock::default());
+    let exporter = Exporter::with_clock(clock.clone());
+    let exporter = Pin::new(&exporter);
+    let entity_labels = FieldMap::from([("job", FieldValue::Str("lorem".into()))]);
+    let metric_fields = FieldMap::from([("code", FieldValue::Int(200))]);
+    exporter
+        .set_int(&entity_labels, "/foo/bar", 42, &metric_fields)
+        .await
+        .unwrap();
+    clock.advance(Duration::from_secs(10)).await;
+    exporter
+        .set_int(&entity_labels, "/foo/bar", 43, &metric_fields)
+        .await
+        .unwrap();
+    let snapshot = exporter.snapshot().await;
+    assert_eq!(
+        snapshot.timestamp(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+    );
+    let cell = snapshot
+        .get_cell(&entity_labels, "/foo/bar", &metric_fields)
+        .unwrap();
+    assert_eq!(cell.start_timestamp(), SystemTime::UNIX_EPOCH);
+    assert_eq!(
+        cell.update_timestamp(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_counter_with_mock_clock() {
+    let clock = Arc::new(MockClock::default());
+    let exporter: &'static Exporter = Box::leak(Box::new(Exporter::with_clock(clock.clone())));
+    let counter = Counter::new_with(exporter, "/foo/bar", MetricConfig::default());
+    let entity_labels = FieldMap::from([("job", FieldValue::Str("lorem".into()))]);
+    let metric_fields = FieldMap::default();
+    counter
+        .increment_by(5, &entity_labels, &metric_fields)
+        .await;
+    clock.advance(Duration::from_secs(30)).await;
+    counter
+        .increment_by(3, &entity_labels, &metric_fields)
+        .await;
+    assert_eq!(counter.get(&entity_labels, &metric_fields).await, Some(8));
+    let cell = exporter
+        .snapshot()
+        .await
+        .get_cell(&entity_labels, "/foo/bar", &metric_fields)
+        .cloned()
+        .unwrap();
+    assert_eq!(cell.start_timestamp(), SystemTime::UNIX_EPOCH);
+    assert_eq!(
+        cell.update_timestamp(),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(30)
+    );
+}